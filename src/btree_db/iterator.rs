@@ -0,0 +1,117 @@
+use std::ops::Bound;
+
+use crate::error::DbResult;
+use crate::kvdb::{KeyRange, ScanDirection};
+
+use super::free_page_in_tree;
+use super::page::{Page, PageId};
+use super::pager::Pager;
+
+// Walks the leaves in the range one at a time. Leaves don't link to each other, so the scan
+// descends from the root again for every leaf, aiming just past the separator that bounded the
+// previous one.
+pub struct BTreeScan<'a> {
+    pager: &'a mut Pager,
+    range: KeyRange,
+    direction: ScanDirection,
+    // where the next leaf to read starts, `None` once the range is exhausted
+    next_target: Option<Bound<String>>,
+    entries: std::vec::IntoIter<(String, String)>,
+}
+
+impl<'a> BTreeScan<'a> {
+    pub fn new(pager: &'a mut Pager, range: KeyRange, direction: ScanDirection) -> Self {
+        let next_target = Some(match direction {
+            ScanDirection::Forward => range.start.clone(),
+            ScanDirection::Reverse => range.end.clone(),
+        });
+        BTreeScan {
+            pager,
+            range,
+            direction,
+            next_target,
+            entries: vec![].into_iter(),
+        }
+    }
+    fn try_next(&mut self) -> DbResult<Option<(String, String)>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Ok(Some(entry));
+            }
+            let Some(target) = self.next_target.take() else {
+                return Ok(None);
+            };
+            let (leaf_id, lower_fence, upper_fence) = self.find_leaf(&target)?;
+            let Page::Leaf { keys, values } = self.pager.get(leaf_id)? else {
+                unreachable!()
+            };
+            let mut entries: Vec<(String, String)> = keys
+                .iter()
+                .zip(values)
+                .filter(|(key, _)| self.range.contains(key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            self.next_target = match self.direction {
+                ScanDirection::Forward => upper_fence
+                    .filter(|fence| !self.range.is_after_end(fence))
+                    .map(Bound::Included),
+                ScanDirection::Reverse => {
+                    entries.reverse();
+                    lower_fence
+                        .filter(|fence| !self.range.is_before_start(fence))
+                        .map(Bound::Excluded)
+                }
+            };
+            self.entries = entries.into_iter();
+        }
+    }
+    // the leaf holding the first keys past `target` in the scan's direction, along with the
+    // separators bounding it from below and above
+    fn find_leaf(
+        &mut self,
+        target: &Bound<String>,
+    ) -> DbResult<(PageId, Option<String>, Option<String>)> {
+        let mut id = self.pager.root();
+        let (mut lower_fence, mut upper_fence) = (None, None);
+        loop {
+            match self.pager.get(id)? {
+                Page::Leaf { .. } => return Ok((id, lower_fence, upper_fence)),
+                Page::Internal { keys, children } => {
+                    let idx = match (target, self.direction) {
+                        (Bound::Unbounded, ScanDirection::Forward) => 0,
+                        (Bound::Unbounded, ScanDirection::Reverse) => keys.len(),
+                        (Bound::Excluded(key), ScanDirection::Reverse) => {
+                            keys.partition_point(|separator| separator < key)
+                        }
+                        (Bound::Included(key) | Bound::Excluded(key), _) => {
+                            keys.partition_point(|separator| separator <= key)
+                        }
+                    };
+                    if idx > 0 {
+                        lower_fence = Some(keys[idx - 1].clone());
+                    }
+                    if idx < keys.len() {
+                        upper_fence = Some(keys[idx].clone());
+                    }
+                    id = children[idx];
+                }
+                Page::Free { .. } => return Err(free_page_in_tree(id)),
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for BTreeScan<'a> {
+    type Item = DbResult<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                self.next_target = None;
+                self.entries = vec![].into_iter();
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::iter::empty;
+
+use self::iterator::BTreeScan;
+use self::page::{internal_entry_size, leaf_entry_size, Page, PageId, PAGE_HEADER_SIZE};
+use self::pager::{PageChange, Pager};
+use crate::error::{DbResult, Error};
+use crate::kv_file::RecoveryPolicy;
+use crate::kvdb::{
+    write_batch::WriteBatch, KVDb, KeyRange, KeyStatus, ScanDirection, ScanIterator,
+};
+
+mod iterator;
+mod page;
+mod pager;
+mod wal;
+
+// pages smaller than this can't hold enough entries to be worth splitting
+const MIN_PAGE_SIZE: usize = 128;
+
+// A B+tree kept in a single file of fixed-size pages. Values live in the leaves, internal pages
+// only hold separator keys. Updates are made in place, each operation is logged to a WAL when it's
+// done and the pages it changed are written back later.
+pub struct BTreeDb {
+    description: String,
+    pager: Pager,
+    page_size: usize,
+    max_entry_size: usize,
+}
+
+impl KVDb for BTreeDb {
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+    fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
+        self.check_entry_size(key, value)?;
+        let result = self.put_in_tree(key, value);
+        self.commit_or_roll_back(result)
+    }
+    fn delete(&mut self, key: &str) -> DbResult<()> {
+        let result = self.delete_from_tree(key);
+        self.commit_or_roll_back(result)
+    }
+    // every change of the batch is logged before the one commit record, and recovery only
+    // replays changes that made it to a commit
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        for (key, status) in batch.entries() {
+            if let KeyStatus::Present(value) = status {
+                self.check_entry_size(key, value)?;
+            }
+        }
+        let result = batch
+            .entries()
+            .iter()
+            .try_for_each(|(key, status)| match status {
+                KeyStatus::Present(value) => self.put_in_tree(key, value),
+                KeyStatus::Deleted => self.delete_from_tree(key),
+            });
+        self.commit_or_roll_back(result)
+    }
+    // a write's commit record is the last it logs, so its LSN serves as the sequence number
+    fn last_seq(&self) -> u64 {
+        self.pager.last_lsn()
+    }
+    fn get(&mut self, key: &str) -> DbResult<Option<String>> {
+        let mut id = self.pager.root();
+        loop {
+            match self.pager.get(id)? {
+                Page::Internal { keys, children } => id = children[child_index(keys, key)],
+                Page::Leaf { keys, values } => {
+                    return Ok(keys
+                        .binary_search_by(|other| other.as_str().cmp(key))
+                        .ok()
+                        .map(|idx| values[idx].clone()))
+                }
+                Page::Free { .. } => return Err(free_page_in_tree(id)),
+            }
+        }
+    }
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>> {
+        if range.is_empty() {
+            return Ok(Box::new(empty()));
+        }
+        Ok(Box::new(BTreeScan::new(&mut self.pager, range, direction)))
+    }
+}
+
+impl BTreeDb {
+    pub fn new(
+        dir_path: &str,
+        file_name: &str,
+        page_size: usize,
+        cache_size: usize,
+        wal_size_threshold: u64,
+        recovery_policy: RecoveryPolicy,
+    ) -> DbResult<BTreeDb> {
+        if page_size < MIN_PAGE_SIZE {
+            return Err(Error::InvalidInput(format!(
+                "page size of {} bytes is below the minimum of {}",
+                page_size, MIN_PAGE_SIZE
+            )));
+        }
+        let description = format!(
+            "B+tree DB with page size of {} bytes, page cache of {} pages and WAL size threshold of {} bytes",
+            page_size, cache_size, wal_size_threshold
+        );
+        Ok(BTreeDb {
+            description,
+            pager: Pager::open(
+                dir_path,
+                file_name,
+                page_size,
+                cache_size,
+                wal_size_threshold,
+                recovery_policy,
+            )?,
+            page_size,
+            // small enough that a split always leaves both halves within a page
+            max_entry_size: (page_size - PAGE_HEADER_SIZE - 8) / 4,
+        })
+    }
+    // commits the changes made to the tree since the last commit, or rolls all of them back if one
+    // of them failed, so that a failed operation leaves neither records in the WAL nor pages in
+    // the cache behind for the next commit to pick up
+    fn commit_or_roll_back(&mut self, result: DbResult<()>) -> DbResult<()> {
+        if let Err(e) = result {
+            self.pager.roll_back()?;
+            return Err(e);
+        }
+        self.pager.commit()
+    }
+    fn check_entry_size(&self, key: &str, value: &str) -> DbResult<()> {
+        if leaf_entry_size(key, value).max(internal_entry_size(key)) > self.max_entry_size {
+            return Err(Error::InvalidInput(format!(
+                "entry for key {} doesn't fit in a page, at most {} bytes of key and value fit",
+                key,
+                self.max_entry_size - leaf_entry_size("", "")
+            )));
+        }
+        Ok(())
+    }
+    // changes the tree without committing, so that several changes can be committed together
+    fn put_in_tree(&mut self, key: &str, value: &str) -> DbResult<()> {
+        let root = self.pager.root();
+        if let Some((separator, right)) = self.insert(root, key, value)? {
+            let new_root = self.pager.allocate(Page::Internal {
+                keys: vec![separator],
+                children: vec![root, right],
+            })?;
+            self.pager.set_root(new_root);
+        }
+        Ok(())
+    }
+    fn delete_from_tree(&mut self, key: &str) -> DbResult<()> {
+        let root = self.pager.root();
+        if self.remove(root, key)? {
+            // the root lost its last separator, so the tree gets one level shorter
+            if let Page::Internal { keys, children } = self.pager.get(root)? {
+                if keys.is_empty() {
+                    let new_root = children[0];
+                    self.pager.free(root)?;
+                    self.pager.set_root(new_root);
+                }
+            }
+        }
+        Ok(())
+    }
+    // inserts into the subtree under `id`, returning the separator and the new page to its
+    // right if the page had to be split
+    fn insert(&mut self, id: PageId, key: &str, value: &str) -> DbResult<Option<(String, PageId)>> {
+        let page = match self.pager.get(id)? {
+            Page::Leaf { .. } => {
+                let mut page = self.pager.get(id)?.clone();
+                page.leaf_put(key, value);
+                if page.encoded_size() <= self.page_size {
+                    let change = PageChange::Put(key.to_owned(), value.to_owned());
+                    self.pager.update(id, page, Some(change))?;
+                    return Ok(None);
+                }
+                page
+            }
+            Page::Internal { keys, children } => {
+                let idx = child_index(keys, key);
+                let child = children[idx];
+                let Some((separator, right)) = self.insert(child, key, value)? else {
+                    return Ok(None);
+                };
+                let Page::Internal { keys, children } = self.pager.get(id)? else {
+                    unreachable!()
+                };
+                let mut keys = keys.clone();
+                let mut children = children.clone();
+                keys.insert(idx, separator);
+                children.insert(idx + 1, right);
+                Page::Internal { keys, children }
+            }
+            Page::Free { .. } => return Err(free_page_in_tree(id)),
+        };
+
+        if page.encoded_size() <= self.page_size {
+            self.pager.update(id, page, None)?;
+            return Ok(None);
+        }
+        let (left, separator, right) = split(page);
+        self.pager.update(id, left, None)?;
+        let right_id = self.pager.allocate(right)?;
+        Ok(Some((separator, right_id)))
+    }
+    // removes the key from the subtree under `id`, returning whether it was there
+    fn remove(&mut self, id: PageId, key: &str) -> DbResult<bool> {
+        let (idx, child) = match self.pager.get(id)? {
+            Page::Leaf { .. } => {
+                let mut page = self.pager.get(id)?.clone();
+                if !page.leaf_delete(key) {
+                    return Ok(false);
+                }
+                let change = PageChange::Delete(key.to_owned());
+                self.pager.update(id, page, Some(change))?;
+                return Ok(true);
+            }
+            Page::Internal { keys, children } => {
+                let idx = child_index(keys, key);
+                (idx, children[idx])
+            }
+            Page::Free { .. } => return Err(free_page_in_tree(id)),
+        };
+
+        if !self.remove(child, key)? {
+            return Ok(false);
+        }
+        if self.pager.get(child)?.encoded_size() < self.page_size / 4 {
+            self.rebalance(id, idx)?;
+        }
+        Ok(true)
+    }
+    // merges the parent's child at `idx` with a sibling, or evens the two out if they don't fit
+    // in one page together
+    fn rebalance(&mut self, parent_id: PageId, idx: usize) -> DbResult<()> {
+        let Page::Internal { keys, children } = self.pager.get(parent_id)? else {
+            unreachable!()
+        };
+        if children.len() < 2 {
+            return Ok(());
+        }
+        let mut keys = keys.clone();
+        let mut children = children.clone();
+        let left_idx = idx.saturating_sub(1);
+        let (left_id, right_id) = (children[left_idx], children[left_idx + 1]);
+
+        let left = self.pager.get(left_id)?.clone();
+        let right = self.pager.get(right_id)?.clone();
+        let combined = concat(left, &keys[left_idx], right)
+            .ok_or_else(|| Error::InvalidData(format!("page {} has mixed children", parent_id)))?;
+        if combined.encoded_size() <= self.page_size {
+            self.pager.update(left_id, combined, None)?;
+            self.pager.free(right_id)?;
+            keys.remove(left_idx);
+            children.remove(left_idx + 1);
+        } else {
+            let (left, separator, right) = split(combined);
+            self.pager.update(left_id, left, None)?;
+            self.pager.update(right_id, right, None)?;
+            keys[left_idx] = separator;
+        }
+        self.pager
+            .update(parent_id, Page::Internal { keys, children }, None)
+    }
+}
+
+// the child of an internal page whose keys include `key`
+fn child_index(keys: &[String], key: &str) -> usize {
+    keys.partition_point(|separator| separator.as_str() <= key)
+}
+
+// splits an overflowing page into two at around half of its bytes, returning the separator that
+// goes up to the parent
+fn split(page: Page) -> (Page, String, Page) {
+    match page {
+        Page::Leaf {
+            mut keys,
+            mut values,
+        } => {
+            let sizes: Vec<usize> = keys
+                .iter()
+                .zip(&values)
+                .map(|(key, value)| leaf_entry_size(key, value))
+                .collect();
+            let mid = halfway(&sizes).clamp(1, keys.len() - 1);
+            let right_keys = keys.split_off(mid);
+            let right_values = values.split_off(mid);
+            let separator = right_keys[0].clone();
+            (
+                Page::Leaf { keys, values },
+                separator,
+                Page::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                },
+            )
+        }
+        Page::Internal {
+            mut keys,
+            mut children,
+        } => {
+            let sizes: Vec<usize> = keys.iter().map(|key| internal_entry_size(key)).collect();
+            // the separator at `mid` moves up, so each side keeps at least one
+            let mid = halfway(&sizes).clamp(1, keys.len() - 2);
+            let right_keys = keys.split_off(mid + 1);
+            let right_children = children.split_off(mid + 1);
+            let separator = keys.pop().unwrap();
+            (
+                Page::Internal { keys, children },
+                separator,
+                Page::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            )
+        }
+        Page::Free { .. } => unreachable!(),
+    }
+}
+
+// joins two neighbouring pages, pulling down the separator between them if they are internal
+fn concat(left: Page, separator: &str, right: Page) -> Option<Page> {
+    match (left, right) {
+        (
+            Page::Leaf {
+                mut keys,
+                mut values,
+            },
+            Page::Leaf {
+                keys: right_keys,
+                values: right_values,
+            },
+        ) => {
+            keys.extend(right_keys);
+            values.extend(right_values);
+            Some(Page::Leaf { keys, values })
+        }
+        (
+            Page::Internal {
+                mut keys,
+                mut children,
+            },
+            Page::Internal {
+                keys: right_keys,
+                children: right_children,
+            },
+        ) => {
+            keys.push(separator.to_owned());
+            keys.extend(right_keys);
+            children.extend(right_children);
+            Some(Page::Internal { keys, children })
+        }
+        _ => None,
+    }
+}
+
+// the first index at which the sizes before it add up to at least half of the total
+fn halfway(sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    let mut sum = 0;
+    for (idx, size) in sizes.iter().enumerate() {
+        if 2 * sum >= total {
+            return idx;
+        }
+        sum += size;
+    }
+    sizes.len()
+}
+
+impl Drop for BTreeDb {
+    fn drop(&mut self) {
+        // leaves nothing for the next open to redo
+        let _ = self.pager.checkpoint();
+    }
+}
+
+fn free_page_in_tree(id: PageId) -> Error {
+    Error::InvalidData(format!(
+        "page {} is in the free list but still in the tree",
+        id
+    ))
+}
@@ -0,0 +1,248 @@
+use crate::crc::crc32;
+use crate::encoding::{put_str, put_u32, put_u64, Decoder};
+
+pub type PageId = u64;
+
+// every page starts with a checksum of the rest of the page (4 bytes), the LSN of the last WAL
+// record applied to it (8 bytes), the page type (1 byte) and the number of entries (4 bytes), the
+// unused tail of the page is zeroed and covered by the checksum as well
+// leaf: key and value of each entry, in key order
+// internal: first child, then a separator key and the child to its right for each entry, the
+// keys in a child are >= the separator on its left and < the separator on its right
+// free: the next page in the free list (8 bytes), 0 if it's the last one
+// meta (always page 0): magic number, format version, page size, root, number of pages and the
+// head of the free list
+pub const PAGE_HEADER_SIZE: usize = 17;
+// checksum, page header, magic number, format version and page size
+pub const META_PREFIX_SIZE: usize = PAGE_HEADER_SIZE + 16;
+const FORMAT_VERSION: u32 = 2;
+const MAGIC: u64 = 0x6274_7265_6564_6221;
+const META_PAGE_TYPE: u8 = 0;
+const LEAF_PAGE_TYPE: u8 = 1;
+const INTERNAL_PAGE_TYPE: u8 = 2;
+const FREE_PAGE_TYPE: u8 = 3;
+
+#[derive(Clone, Debug)]
+pub enum Page {
+    Leaf {
+        keys: Vec<String>,
+        values: Vec<String>,
+    },
+    Internal {
+        keys: Vec<String>,
+        children: Vec<PageId>,
+    },
+    Free {
+        next: PageId,
+    },
+}
+
+impl Page {
+    pub fn empty_leaf() -> Self {
+        Page::Leaf {
+            keys: vec![],
+            values: vec![],
+        }
+    }
+    // the number of bytes the page takes up once encoded, without the zeroed tail
+    pub fn encoded_size(&self) -> usize {
+        PAGE_HEADER_SIZE
+            + match self {
+                Page::Leaf { keys, values } => keys
+                    .iter()
+                    .zip(values)
+                    .map(|(key, value)| leaf_entry_size(key, value))
+                    .sum(),
+                Page::Internal { keys, .. } => {
+                    8 + keys
+                        .iter()
+                        .map(|key| internal_entry_size(key))
+                        .sum::<usize>()
+                }
+                Page::Free { .. } => 8,
+            }
+    }
+    // inserts or updates a leaf entry
+    pub fn leaf_put(&mut self, key: &str, value: &str) {
+        let Page::Leaf { keys, values } = self else {
+            return;
+        };
+        match keys.binary_search_by(|other| other.as_str().cmp(key)) {
+            Ok(idx) => values[idx] = value.to_owned(),
+            Err(idx) => {
+                keys.insert(idx, key.to_owned());
+                values.insert(idx, value.to_owned());
+            }
+        }
+    }
+    // removes a leaf entry, returning whether it was there
+    pub fn leaf_delete(&mut self, key: &str) -> bool {
+        let Page::Leaf { keys, values } = self else {
+            return false;
+        };
+        let Ok(idx) = keys.binary_search_by(|other| other.as_str().cmp(key)) else {
+            return false;
+        };
+        keys.remove(idx);
+        values.remove(idx);
+        true
+    }
+    pub fn encode(&self, page_size: usize, lsn: u64) -> Vec<u8> {
+        let mut body = vec![];
+        put_u64(&mut body, lsn);
+        self.encode_entries(&mut body);
+        seal_page(body, page_size)
+    }
+    // the page and its LSN
+    pub fn decode(bytes: &[u8]) -> Result<(Self, u64), String> {
+        let mut decoder = open_page(bytes)?;
+        let lsn = decoder.u64()?;
+        Ok((Self::decode_entries(&mut decoder)?, lsn))
+    }
+    // the page without its checksum and LSN, which is how full page images go into the WAL
+    pub fn encode_entries(&self, buf: &mut Vec<u8>) {
+        match self {
+            Page::Leaf { keys, values } => {
+                buf.push(LEAF_PAGE_TYPE);
+                put_u32(buf, keys.len() as u32);
+                for (key, value) in keys.iter().zip(values) {
+                    put_str(buf, key);
+                    put_str(buf, value);
+                }
+            }
+            Page::Internal { keys, children } => {
+                buf.push(INTERNAL_PAGE_TYPE);
+                put_u32(buf, keys.len() as u32);
+                put_u64(buf, children[0]);
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    put_str(buf, key);
+                    put_u64(buf, *child);
+                }
+            }
+            Page::Free { next } => {
+                buf.push(FREE_PAGE_TYPE);
+                put_u32(buf, 0);
+                put_u64(buf, *next);
+            }
+        }
+    }
+    pub fn decode_entries(decoder: &mut Decoder) -> Result<Self, String> {
+        let page_type = decoder.u8()?;
+        let count = decoder.u32()? as usize;
+        match page_type {
+            LEAF_PAGE_TYPE => {
+                let mut keys = Vec::with_capacity(count);
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    keys.push(decoder.str()?);
+                    values.push(decoder.str()?);
+                }
+                Ok(Page::Leaf { keys, values })
+            }
+            INTERNAL_PAGE_TYPE => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = Vec::with_capacity(count + 1);
+                children.push(decoder.u64()?);
+                for _ in 0..count {
+                    keys.push(decoder.str()?);
+                    children.push(decoder.u64()?);
+                }
+                Ok(Page::Internal { keys, children })
+            }
+            FREE_PAGE_TYPE => Ok(Page::Free {
+                next: decoder.u64()?,
+            }),
+            _ => Err(format!("unexpected page type {}", page_type)),
+        }
+    }
+}
+
+pub fn leaf_entry_size(key: &str, value: &str) -> usize {
+    8 + key.len() + value.len()
+}
+
+pub fn internal_entry_size(key: &str) -> usize {
+    4 + key.len() + 8
+}
+
+#[derive(Clone, Debug)]
+pub struct Meta {
+    pub page_size: u32,
+    pub root: PageId,
+    pub num_pages: u64,
+    pub free_list_head: PageId,
+}
+
+impl Meta {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        put_u64(&mut body, 0);
+        body.push(META_PAGE_TYPE);
+        put_u32(&mut body, 0);
+        put_u64(&mut body, MAGIC);
+        put_u32(&mut body, FORMAT_VERSION);
+        self.encode_fields(&mut body);
+        seal_page(body, self.page_size as usize)
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut decoder = open_page(bytes)?;
+        Self::decode_prefix(&mut decoder)?;
+        Self::decode_fields(&mut decoder)
+    }
+    // the page size the file was created with, which has to be known before the meta page's
+    // checksum can be checked
+    pub fn decode_page_size(bytes: &[u8]) -> Result<u32, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.u32()?;
+        Self::decode_prefix(&mut decoder)?;
+        decoder.u32()
+    }
+    pub fn encode_fields(&self, buf: &mut Vec<u8>) {
+        put_u32(buf, self.page_size);
+        put_u64(buf, self.root);
+        put_u64(buf, self.num_pages);
+        put_u64(buf, self.free_list_head);
+    }
+    pub fn decode_fields(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(Meta {
+            page_size: decoder.u32()?,
+            root: decoder.u64()?,
+            num_pages: decoder.u64()?,
+            free_list_head: decoder.u64()?,
+        })
+    }
+    fn decode_prefix(decoder: &mut Decoder) -> Result<(), String> {
+        decoder.u64()?;
+        if decoder.u8()? != META_PAGE_TYPE {
+            return Err("first page is not a meta page".to_string());
+        }
+        decoder.u32()?;
+        if decoder.u64()? != MAGIC {
+            return Err("not a B+tree file".to_string());
+        }
+        let version = decoder.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported B+tree format version {}", version));
+        }
+        Ok(())
+    }
+}
+
+fn seal_page(body: Vec<u8>, page_size: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(page_size);
+    put_u32(&mut buf, 0);
+    buf.extend_from_slice(&body);
+    buf.resize(page_size, 0);
+    let checksum = crc32(&buf[4..]);
+    buf[..4].copy_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+// checks the page's checksum and returns a decoder positioned right after it
+fn open_page(bytes: &[u8]) -> Result<Decoder<'_>, String> {
+    let mut decoder = Decoder::new(bytes);
+    if decoder.u32()? != crc32(&bytes[4..]) {
+        return Err("page checksum mismatch".to_string());
+    }
+    Ok(decoder)
+}
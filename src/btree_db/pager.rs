@@ -0,0 +1,403 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::error::{DbResult, Error};
+use crate::kv_file::RecoveryPolicy;
+
+use super::page::{Meta, Page, PageId, META_PREFIX_SIZE};
+use super::wal::{Wal, WalRecord};
+
+const WAL_FILE_EXTENSION: &str = "wal";
+
+// how a leaf changed, for the WAL to log instead of the whole page
+pub enum PageChange {
+    Put(String, String),
+    Delete(String),
+}
+
+struct CachedPage {
+    page: Page,
+    lsn: u64,
+    dirty: bool,
+    last_used: u64,
+}
+
+// a page as it was before the operation in progress first changed it
+struct ChangedPage {
+    // the cached page, its LSN and whether it was dirty, `None` if the data file had it
+    cached: Option<(Page, u64, bool)>,
+    was_imaged: bool,
+}
+
+// Reads and writes fixed-size pages, keeping the most recently used ones in memory. Every change
+// goes through the WAL first, pages are only written back to the data file when they're evicted
+// or at a checkpoint, which happens once the WAL grows past its threshold. Page 0 holds the meta
+// page.
+pub struct Pager {
+    file: File,
+    file_path: String,
+    page_size: usize,
+    meta: Meta,
+    // the meta page changed since the last checkpoint
+    meta_dirty: bool,
+    // the meta page changed in the operation in progress
+    meta_changed: bool,
+    // the meta page as of the last commit
+    committed_meta: Meta,
+    wal: Wal,
+    wal_size_threshold: u64,
+    // LSN of the last commit, pages changed after it can't be written back yet
+    committed_lsn: u64,
+    // pages logged with a full image since the last checkpoint
+    imaged_pages: HashSet<PageId>,
+    // pages the operation in progress changed, to put back if it fails partway through
+    changed_pages: HashMap<PageId, ChangedPage>,
+    cache_capacity: usize,
+    cache: HashMap<PageId, CachedPage>,
+    // page ids by the time they were last used, to find the least recently used one
+    lru: BTreeMap<u64, PageId>,
+    clock: u64,
+}
+
+impl Pager {
+    pub fn open(
+        dir_path: &str,
+        file_name: &str,
+        page_size: usize,
+        cache_capacity: usize,
+        wal_size_threshold: u64,
+        recovery_policy: RecoveryPolicy,
+    ) -> DbResult<Pager> {
+        fs::create_dir_all(dir_path)?;
+        let file_path = dir_path.to_owned() + file_name;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&file_path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let wal_file_name = Path::new(file_name)
+            .with_extension(WAL_FILE_EXTENSION)
+            .to_string_lossy()
+            .into_owned();
+        let meta = Meta {
+            page_size: page_size as u32,
+            root: 1,
+            num_pages: 2,
+            free_list_head: 0,
+        };
+        let mut pager = Pager {
+            file,
+            file_path,
+            page_size,
+            committed_meta: meta.clone(),
+            meta,
+            meta_dirty: true,
+            meta_changed: false,
+            wal: Wal::open(dir_path, &wal_file_name)?,
+            wal_size_threshold,
+            committed_lsn: 0,
+            imaged_pages: HashSet::new(),
+            changed_pages: HashMap::new(),
+            cache_capacity: cache_capacity.max(1),
+            cache: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        };
+
+        let records = pager.wal.recover(recovery_policy)?;
+        if is_new {
+            pager.cache_page(1, Page::empty_leaf(), 0, true)?;
+        } else {
+            // a meta page torn by a crash is restored from the WAL
+            let meta_result = pager.read_meta();
+            let meta_is_torn = matches!(meta_result, Err(Error::Corrupted(..)));
+            match meta_is_torn {
+                // the page count isn't known until the meta record is replayed
+                true => pager.meta.num_pages = u64::MAX,
+                false => pager.meta = meta_result?,
+            }
+            pager.meta_dirty = false;
+            pager.redo(records)?;
+            if meta_is_torn && !pager.meta_dirty {
+                return Err(pager.corrupted(0, "meta page checksum mismatch"));
+            }
+        }
+        pager.checkpoint()?;
+        pager.committed_meta = pager.meta.clone();
+        Ok(pager)
+    }
+    // LSN of the last record logged, LSNs keep counting up across checkpoints and restarts
+    pub fn last_lsn(&self) -> u64 {
+        self.wal.last_lsn()
+    }
+    pub fn root(&self) -> PageId {
+        self.meta.root
+    }
+    pub fn set_root(&mut self, root: PageId) {
+        self.meta.root = root;
+        self.meta_changed = true;
+    }
+    pub fn get(&mut self, id: PageId) -> DbResult<&Page> {
+        self.load(id)?;
+        Ok(&self.cache[&id].page)
+    }
+    // replaces a page, logging either the change or, for structural changes and the first change
+    // after a checkpoint, the whole page
+    pub fn update(&mut self, id: PageId, page: Page, change: Option<PageChange>) -> DbResult<()> {
+        self.changed_pages.entry(id).or_insert_with(|| ChangedPage {
+            cached: self
+                .cache
+                .get(&id)
+                .map(|cached| (cached.page.clone(), cached.lsn, cached.dirty)),
+            was_imaged: self.imaged_pages.contains(&id),
+        });
+        let record = match change {
+            Some(PageChange::Put(key, value)) if self.imaged_pages.contains(&id) => {
+                WalRecord::LeafPut(id, key, value)
+            }
+            Some(PageChange::Delete(key)) if self.imaged_pages.contains(&id) => {
+                WalRecord::LeafDelete(id, key)
+            }
+            _ => {
+                self.imaged_pages.insert(id);
+                WalRecord::PageImage(id, page.clone())
+            }
+        };
+        let lsn = self.wal.log(&record);
+        self.put(id, page, lsn)
+    }
+    pub fn allocate(&mut self, page: Page) -> DbResult<PageId> {
+        let id = match self.meta.free_list_head {
+            0 => {
+                self.meta.num_pages += 1;
+                self.meta.num_pages - 1
+            }
+            id => {
+                self.meta.free_list_head = match self.get(id)? {
+                    Page::Free { next } => *next,
+                    _ => return Err(self.corrupted(id, "free list points to a page in use")),
+                };
+                id
+            }
+        };
+        self.meta_changed = true;
+        self.update(id, page, None)?;
+        Ok(id)
+    }
+    pub fn free(&mut self, id: PageId) -> DbResult<()> {
+        let next = self.meta.free_list_head;
+        self.update(id, Page::Free { next }, None)?;
+        self.meta.free_list_head = id;
+        self.meta_changed = true;
+        Ok(())
+    }
+    // ends the operation in progress, its changes survive a crash from here on
+    pub fn commit(&mut self) -> DbResult<()> {
+        if self.meta_changed {
+            self.wal.log(&WalRecord::Meta(self.meta.clone()));
+            self.meta_changed = false;
+            self.meta_dirty = true;
+        }
+        self.committed_lsn = match self.wal.commit() {
+            Ok(lsn) => lsn,
+            Err(e) => {
+                self.roll_back()?;
+                return Err(e);
+            }
+        };
+        self.committed_meta = self.meta.clone();
+        self.changed_pages.clear();
+        if self.wal.size() > self.wal_size_threshold {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+    // drops the operation in progress after it failed partway through, nothing it logged is
+    // written and the pages it changed are put back the way they were, the ones that weren't cached
+    // are read from the data file again, which pages that can't be evicted yet never reach
+    pub fn roll_back(&mut self) -> DbResult<()> {
+        self.wal.discard()?;
+        self.meta = self.committed_meta.clone();
+        self.meta_changed = false;
+        for (id, changed) in self.changed_pages.drain() {
+            if !changed.was_imaged {
+                self.imaged_pages.remove(&id);
+            }
+            let Some(cached) = self.cache.get_mut(&id) else {
+                continue;
+            };
+            match changed.cached {
+                Some((page, lsn, dirty)) => {
+                    cached.page = page;
+                    cached.lsn = lsn;
+                    cached.dirty = dirty;
+                }
+                None => {
+                    self.lru.remove(&cached.last_used);
+                    self.cache.remove(&id);
+                }
+            }
+        }
+        Ok(())
+    }
+    // writes every changed page back to the data file, after which the WAL can be emptied
+    pub fn checkpoint(&mut self) -> DbResult<()> {
+        self.wal.sync()?;
+        let mut dirty_ids: Vec<PageId> = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(id, _)| *id)
+            .collect();
+        dirty_ids.sort();
+        for id in dirty_ids {
+            let cached = &self.cache[&id];
+            let bytes = cached.page.encode(self.page_size, cached.lsn);
+            self.write_page(id, &bytes)?;
+            self.cache.get_mut(&id).unwrap().dirty = false;
+        }
+        if self.meta_dirty {
+            let bytes = self.meta.encode();
+            self.write_page(0, &bytes)?;
+            self.meta_dirty = false;
+        }
+        self.file.sync_all()?;
+        self.wal.reset()?;
+        self.imaged_pages.clear();
+        Ok(())
+    }
+    // replays the committed records the data file might be missing
+    fn redo(&mut self, records: Vec<(u64, WalRecord)>) -> DbResult<()> {
+        self.committed_lsn = records.last().map_or(0, |(lsn, _)| *lsn);
+        for (lsn, record) in records {
+            match record {
+                WalRecord::PageImage(id, page) => self.put(id, page, lsn)?,
+                WalRecord::LeafPut(id, key, value) => {
+                    if let Some(mut page) = self.page_to_redo(id, lsn)? {
+                        page.leaf_put(&key, &value);
+                        self.put(id, page, lsn)?;
+                    }
+                }
+                WalRecord::LeafDelete(id, key) => {
+                    if let Some(mut page) = self.page_to_redo(id, lsn)? {
+                        page.leaf_delete(&key);
+                        self.put(id, page, lsn)?;
+                    }
+                }
+                WalRecord::Meta(meta) => {
+                    self.meta = meta;
+                    self.meta_dirty = true;
+                }
+                WalRecord::Commit => {}
+            }
+        }
+        Ok(())
+    }
+    // the page to apply a record to, `None` if the page already has it
+    fn page_to_redo(&mut self, id: PageId, lsn: u64) -> DbResult<Option<Page>> {
+        self.load(id)?;
+        let cached = &self.cache[&id];
+        Ok((cached.lsn < lsn).then(|| cached.page.clone()))
+    }
+    fn load(&mut self, id: PageId) -> DbResult<()> {
+        if !self.cache.contains_key(&id) {
+            let (page, lsn) = self.read_page(id)?;
+            self.cache_page(id, page, lsn, false)?;
+        }
+        self.touch(id);
+        Ok(())
+    }
+    fn put(&mut self, id: PageId, page: Page, lsn: u64) -> DbResult<()> {
+        if let Some(cached) = self.cache.get_mut(&id) {
+            cached.page = page;
+            cached.lsn = lsn;
+            cached.dirty = true;
+            self.touch(id);
+            return Ok(());
+        }
+        self.cache_page(id, page, lsn, true)
+    }
+    fn cache_page(&mut self, id: PageId, page: Page, lsn: u64, dirty: bool) -> DbResult<()> {
+        if self.cache.len() >= self.cache_capacity {
+            self.evict()?;
+        }
+        self.cache.insert(
+            id,
+            CachedPage {
+                page,
+                lsn,
+                dirty,
+                last_used: 0,
+            },
+        );
+        self.touch(id);
+        Ok(())
+    }
+    fn touch(&mut self, id: PageId) {
+        let cached = self.cache.get_mut(&id).unwrap();
+        self.lru.remove(&cached.last_used);
+        self.clock += 1;
+        cached.last_used = self.clock;
+        self.lru.insert(self.clock, id);
+    }
+    // drops the least recently used page that can be written back, pages changed by the operation
+    // in progress stay until it commits, so the cache can briefly grow past its capacity
+    fn evict(&mut self) -> DbResult<()> {
+        let evictable = self.lru.iter().find(|(_, id)| {
+            let cached = &self.cache[*id];
+            !cached.dirty || cached.lsn <= self.committed_lsn
+        });
+        let Some((&last_used, &id)) = evictable else {
+            return Ok(());
+        };
+        self.lru.remove(&last_used);
+        let cached = self.cache.remove(&id).unwrap();
+        if cached.dirty {
+            // the page can't reach the data file before the records it reflects reach the WAL
+            self.wal.sync()?;
+            self.write_page(id, &cached.page.encode(self.page_size, cached.lsn))?;
+        }
+        Ok(())
+    }
+    fn read_meta(&mut self) -> DbResult<Meta> {
+        let mut bytes = vec![0; self.page_size.max(META_PREFIX_SIZE)];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut bytes[..META_PREFIX_SIZE])?;
+        let page_size =
+            Meta::decode_page_size(&bytes).map_err(|msg| self.corrupted(0, &msg))? as usize;
+        if page_size != self.page_size {
+            return Err(Error::InvalidInput(format!(
+                "{} has pages of {} bytes, not {}",
+                self.file_path, page_size, self.page_size
+            )));
+        }
+        self.file.read_exact(&mut bytes[META_PREFIX_SIZE..])?;
+        Meta::decode(&bytes).map_err(|msg| self.corrupted(0, &msg))
+    }
+    fn read_page(&mut self, id: PageId) -> DbResult<(Page, u64)> {
+        if id == 0 || id >= self.meta.num_pages {
+            return Err(self.corrupted(id, "page id out of bounds"));
+        }
+        let mut bytes = vec![0; self.page_size];
+        self.file.seek(SeekFrom::Start(self.offset(id)))?;
+        self.file.read_exact(&mut bytes)?;
+        Page::decode(&bytes).map_err(|msg| self.corrupted(id, &msg))
+    }
+    fn write_page(&mut self, id: PageId, bytes: &[u8]) -> DbResult<()> {
+        self.file.seek(SeekFrom::Start(self.offset(id)))?;
+        self.file.write_all(bytes)?;
+        Ok(())
+    }
+    fn offset(&self, id: PageId) -> u64 {
+        id * self.page_size as u64
+    }
+    fn corrupted(&self, id: PageId, msg: &str) -> Error {
+        Error::Corrupted(self.file_path.clone(), self.offset(id), msg.to_string())
+    }
+}
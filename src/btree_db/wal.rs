@@ -0,0 +1,259 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::crc::crc32;
+use crate::encoding::{
+    decode_record_header, put_record_header, put_str, put_u64, Decoder, RECORD_HEADER_SIZE,
+};
+use crate::error::{DbResult, Error};
+use crate::kv_file::RecoveryPolicy;
+
+use super::page::{Meta, Page, PageId};
+
+// WAL layout: the LSN the log starts at (8 bytes), then records, each one being a checksum of the
+// payload (4 bytes), the payload length (4 bytes), a checksum of the two fields before it (4 bytes)
+// and the payload
+// payload layout: LSN (8 bytes), record type (1 byte), fields of the record
+// The records of one operation are written together and end with a commit record, so an operation
+// is either entirely in the log or not at all.
+const WAL_HEADER_SIZE: u64 = 8;
+const PAGE_IMAGE_RECORD_TYPE: u8 = 1;
+const LEAF_PUT_RECORD_TYPE: u8 = 2;
+const LEAF_DELETE_RECORD_TYPE: u8 = 3;
+const META_RECORD_TYPE: u8 = 4;
+const COMMIT_RECORD_TYPE: u8 = 5;
+
+// Changes are logged physiologically: a record applies to a single page, either replacing it with
+// a full image or describing an entry change within it. A page's first change after a checkpoint
+// is always logged as a full image, so redo never depends on a page that might have been torn.
+#[derive(Debug)]
+pub enum WalRecord {
+    PageImage(PageId, Page),
+    LeafPut(PageId, String, String),
+    LeafDelete(PageId, String),
+    Meta(Meta),
+    Commit,
+}
+
+impl WalRecord {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            WalRecord::PageImage(id, page) => {
+                buf.push(PAGE_IMAGE_RECORD_TYPE);
+                put_u64(buf, *id);
+                page.encode_entries(buf);
+            }
+            WalRecord::LeafPut(id, key, value) => {
+                buf.push(LEAF_PUT_RECORD_TYPE);
+                put_u64(buf, *id);
+                put_str(buf, key);
+                put_str(buf, value);
+            }
+            WalRecord::LeafDelete(id, key) => {
+                buf.push(LEAF_DELETE_RECORD_TYPE);
+                put_u64(buf, *id);
+                put_str(buf, key);
+            }
+            WalRecord::Meta(meta) => {
+                buf.push(META_RECORD_TYPE);
+                meta.encode_fields(buf);
+            }
+            WalRecord::Commit => buf.push(COMMIT_RECORD_TYPE),
+        }
+    }
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        match decoder.u8()? {
+            PAGE_IMAGE_RECORD_TYPE => Ok(WalRecord::PageImage(
+                decoder.u64()?,
+                Page::decode_entries(decoder)?,
+            )),
+            LEAF_PUT_RECORD_TYPE => Ok(WalRecord::LeafPut(
+                decoder.u64()?,
+                decoder.str()?,
+                decoder.str()?,
+            )),
+            LEAF_DELETE_RECORD_TYPE => Ok(WalRecord::LeafDelete(decoder.u64()?, decoder.str()?)),
+            META_RECORD_TYPE => Ok(WalRecord::Meta(Meta::decode_fields(decoder)?)),
+            COMMIT_RECORD_TYPE => Ok(WalRecord::Commit),
+            record_type => Err(format!("unknown WAL record type {}", record_type)),
+        }
+    }
+}
+
+pub struct Wal {
+    file: File,
+    file_path: String,
+    // records of the operation in progress
+    pending: Vec<u8>,
+    // LSN of the first record in `pending`, or of the next one logged if there's none
+    pending_first_lsn: u64,
+    next_lsn: u64,
+    size: u64,
+    synced: bool,
+}
+
+impl Wal {
+    pub fn open(dir_path: &str, file_name: &str) -> DbResult<Wal> {
+        fs::create_dir_all(dir_path)?;
+        let file_path = dir_path.to_owned() + file_name;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&file_path)?;
+        let size = file.metadata()?.len();
+        Ok(Wal {
+            file,
+            file_path,
+            pending: vec![],
+            pending_first_lsn: 1,
+            next_lsn: 1,
+            size,
+            synced: true,
+        })
+    }
+    // the records of every committed operation in the log, in order, dropping the tail an
+    // interrupted write left behind
+    pub fn recover(&mut self, policy: RecoveryPolicy) -> DbResult<Vec<(u64, WalRecord)>> {
+        if self.size < WAL_HEADER_SIZE {
+            // created but never reset, nothing was logged yet
+            self.reset()?;
+            return Ok(vec![]);
+        }
+        let mut bytes = vec![];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+        self.next_lsn = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+
+        let mut records = vec![];
+        let mut batch = vec![];
+        let mut committed_len = WAL_HEADER_SIZE as usize;
+        let mut pos = committed_len;
+        while let Some((lsn, record, len)) = self.read_record(&bytes, pos)? {
+            pos += len;
+            self.next_lsn = lsn + 1;
+            let is_commit = matches!(record, WalRecord::Commit);
+            batch.push((lsn, record));
+            if is_commit {
+                records.append(&mut batch);
+                committed_len = pos;
+            }
+        }
+        self.pending_first_lsn = self.next_lsn;
+
+        if committed_len < bytes.len() {
+            match policy {
+                RecoveryPolicy::TruncateTornTail => {
+                    self.file.set_len(committed_len as u64)?;
+                    self.file.sync_all()?;
+                    self.size = committed_len as u64;
+                }
+                RecoveryPolicy::Refuse => {
+                    return Err(Error::TornTail(
+                        self.file_path.clone(),
+                        committed_len as u64,
+                    ))
+                }
+            }
+        }
+        Ok(records)
+    }
+    pub fn log(&mut self, record: &WalRecord) -> u64 {
+        let lsn = self.next_lsn;
+        self.next_lsn += 1;
+
+        let mut payload = vec![];
+        put_u64(&mut payload, lsn);
+        record.encode(&mut payload);
+        put_record_header(&mut self.pending, &payload);
+        self.pending.extend_from_slice(&payload);
+        lsn
+    }
+    // writes out the operation's records, followed by a commit record
+    pub fn commit(&mut self) -> DbResult<u64> {
+        let lsn = self.log(&WalRecord::Commit);
+        self.file.seek(SeekFrom::Start(self.size))?;
+        self.file.write_all(&self.pending)?;
+        self.size += self.pending.len() as u64;
+        self.pending.clear();
+        self.pending_first_lsn = self.next_lsn;
+        self.synced = false;
+        Ok(lsn)
+    }
+    // drops the records of an operation that failed partway through, their LSNs are handed out
+    // again, and cuts off whatever part of them a failed commit got to write
+    pub fn discard(&mut self) -> DbResult<()> {
+        self.next_lsn = self.pending_first_lsn;
+        self.pending.clear();
+        self.file.set_len(self.size)?;
+        Ok(())
+    }
+    pub fn sync(&mut self) -> DbResult<()> {
+        if !self.synced {
+            self.file.sync_data()?;
+            self.synced = true;
+        }
+        Ok(())
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+    // empties the log once everything in it is in the data file, LSNs keep counting up from where
+    // they were: the header is written over before the records are cut off, so a crash in between
+    // leaves records that are already in the data file after it rather than an empty log
+    pub fn reset(&mut self) -> DbResult<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.next_lsn.to_le_bytes())?;
+        self.file.set_len(WAL_HEADER_SIZE)?;
+        self.file.sync_all()?;
+        self.size = WAL_HEADER_SIZE;
+        self.synced = true;
+        Ok(())
+    }
+    // the record at `pos` along with its LSN and encoded length, `None` at the end of the log or
+    // at a record that was only partly written
+    fn read_record(&self, bytes: &[u8], pos: usize) -> DbResult<Option<(u64, WalRecord, usize)>> {
+        let rest = &bytes[pos..];
+        if rest.len() < RECORD_HEADER_SIZE {
+            return Ok(None);
+        }
+        // a header that doesn't match its checksum is only a torn record if nothing follows it,
+        // otherwise its length can't be trusted to tell where the record ends
+        let (crc, payload_len) = match decode_record_header(&rest[..RECORD_HEADER_SIZE]) {
+            Ok(fields) => fields,
+            Err(_) if rest.len() == RECORD_HEADER_SIZE => return Ok(None),
+            Err(msg) => return Err(self.corrupted(pos, &msg)),
+        };
+        // the header's checksum matched, so the record was cut off by the end of the log
+        if rest.len() - RECORD_HEADER_SIZE < payload_len {
+            return Ok(None);
+        }
+        let payload = &rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload_len];
+        let len = RECORD_HEADER_SIZE + payload_len;
+        if crc32(payload) != crc {
+            if len == rest.len() {
+                return Ok(None);
+            }
+            return Err(self.corrupted(pos, "checksum mismatch"));
+        }
+
+        let mut decoder = Decoder::new(payload);
+        let decoded = decoder
+            .u64()
+            .and_then(|lsn| Ok((lsn, WalRecord::decode(&mut decoder)?)));
+        match decoded {
+            Ok((lsn, record)) if decoder.is_empty() => Ok(Some((lsn, record, len))),
+            Ok(_) => Err(self.corrupted(pos, "trailing bytes after the record")),
+            Err(msg) => Err(self.corrupted(pos, &msg)),
+        }
+    }
+    fn corrupted(&self, pos: usize, msg: &str) -> Error {
+        Error::Corrupted(self.file_path.clone(), pos as u64, msg.to_string())
+    }
+}
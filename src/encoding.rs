@@ -0,0 +1,81 @@
+use crate::crc::crc32;
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_str(buf: &mut Vec<u8>, value: &str) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+// the header of a record in an append-only file: checksum of the payload (4 bytes), payload length
+// (4 bytes) and a checksum of those 8 bytes (4 bytes), so that a corrupted length is never taken
+// for a record running past the end of the file
+pub const RECORD_HEADER_SIZE: usize = 12;
+
+pub fn put_record_header(buf: &mut Vec<u8>, payload: &[u8]) {
+    let header_start = buf.len();
+    put_u32(buf, crc32(payload));
+    put_u32(buf, payload.len() as u32);
+    let header_crc = crc32(&buf[header_start..]);
+    put_u32(buf, header_crc);
+}
+
+// the checksum of the payload and the payload length
+pub fn decode_record_header(header: &[u8]) -> Result<(u32, usize), String> {
+    let header_crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if crc32(&header[0..8]) != header_crc {
+        return Err("header checksum mismatch".to_string());
+    }
+    let crc = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let payload_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    Ok((crc, payload_len))
+}
+
+// Reads little-endian values from a byte slice. Errors are plain messages so callers can attach the
+// file and offset they were decoding from.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes, pos: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    pub fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| "string is not valid UTF-8".to_string())
+    }
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < len {
+            return Err(format!(
+                "expected {} more bytes at position {}, found {}",
+                len,
+                self.pos,
+                self.bytes.len() - self.pos
+            ));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
@@ -11,6 +11,14 @@ pub enum Error {
     LockPoisoned,
     InvalidInput(String),
     InvalidData(String),
+    TornTail(String, u64),
+    Corrupted(String, u64, String),
+    // a transaction couldn't commit because of a concurrent write
+    Conflict(String),
+    // a transaction was picked to break a deadlock and has to be rolled back
+    Deadlock(String),
+    // a transaction waited for a lock for longer than the timeout
+    LockTimeout(String),
     Wrapped(String, Box<Self>),
 }
 
@@ -21,6 +29,19 @@ impl fmt::Display for Error {
             Error::LockPoisoned => write!(f, "lock for resource poisoned"),
             Error::InvalidInput(ref msg) => write!(f, "invalid input error: {}", msg),
             Error::InvalidData(ref msg) => write!(f, "invalid data error: {}", msg),
+            Error::TornTail(ref file_path, offset) => write!(
+                f,
+                "torn record at the tail of {} at offset {}",
+                file_path, offset
+            ),
+            Error::Corrupted(ref file_path, offset, ref msg) => write!(
+                f,
+                "corrupted record in {} at offset {}: {}",
+                file_path, offset, msg
+            ),
+            Error::Conflict(ref msg) => write!(f, "transaction conflict: {}", msg),
+            Error::Deadlock(ref msg) => write!(f, "deadlock: {}", msg),
+            Error::LockTimeout(ref msg) => write!(f, "lock wait timeout: {}", msg),
             Error::Wrapped(ref msg, ref err) => write!(f, "{}: {}", msg, err),
         }
     }
@@ -33,11 +54,37 @@ impl error::Error for Error {
             Error::LockPoisoned => None,
             Error::InvalidInput(_) => None,
             Error::InvalidData(_) => None,
+            Error::TornTail(..) => None,
+            Error::Corrupted(..) => None,
+            Error::Conflict(_) => None,
+            Error::Deadlock(_) => None,
+            Error::LockTimeout(_) => None,
             Error::Wrapped(_, ref err) => Some(err),
         }
     }
 }
 
+// an error from a background thread is returned to every write that comes after it, an I/O error
+// can't be cloned as is so its copy only keeps its kind and message
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        match *self {
+            Error::Io(ref err) => Error::Io(io::Error::new(err.kind(), err.to_string())),
+            Error::LockPoisoned => Error::LockPoisoned,
+            Error::InvalidInput(ref msg) => Error::InvalidInput(msg.clone()),
+            Error::InvalidData(ref msg) => Error::InvalidData(msg.clone()),
+            Error::TornTail(ref file_path, offset) => Error::TornTail(file_path.clone(), offset),
+            Error::Corrupted(ref file_path, offset, ref msg) => {
+                Error::Corrupted(file_path.clone(), offset, msg.clone())
+            }
+            Error::Conflict(ref msg) => Error::Conflict(msg.clone()),
+            Error::Deadlock(ref msg) => Error::Deadlock(msg.clone()),
+            Error::LockTimeout(ref msg) => Error::LockTimeout(msg.clone()),
+            Error::Wrapped(ref msg, ref err) => Error::Wrapped(msg.clone(), err.clone()),
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::Io(e)
@@ -1,42 +1,123 @@
 use crate::error::DbResult;
-use crate::kvdb::KVDb;
-use std::collections::HashMap;
+use crate::kvdb::{
+    write_batch::WriteBatch, KVDb, KeyRange, KeyStatus, ScanDirection, ScanIterator,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::iter::empty;
+
+enum Map<T> {
+    Hashed(HashMap<String, T>),
+    Sorted(BTreeMap<String, T>),
+}
 
 pub struct InMemoryDb<T: Clone> {
-    map: HashMap<String, T>,
+    map: Map<T>,
+    // counts the writes made through `KVDb`, nothing is kept across runs so it starts over
+    last_seq: u64,
 }
 
 impl KVDb for InMemoryDb<String> {
     fn description(&self) -> String {
-        "In-Memory DB".to_string()
+        match self.map {
+            Map::Hashed(_) => "In-Memory DB".to_string(),
+            Map::Sorted(_) => "In-Memory DB with sorted map".to_string(),
+        }
     }
     fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
+        self.last_seq += 1;
         Ok(Self::set(self, key, &value.to_string()))
     }
     fn delete(&mut self, key: &str) -> DbResult<()> {
+        self.last_seq += 1;
         Ok(Self::delete(self, key))
     }
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        for (key, status) in batch.entries() {
+            match status {
+                KeyStatus::Present(value) => Self::set(self, key, value),
+                KeyStatus::Deleted => Self::delete(self, key),
+            }
+        }
+        self.last_seq += batch.len() as u64;
+        Ok(())
+    }
+    fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
     fn get(&mut self, key: &str) -> DbResult<Option<String>> {
         Ok(Self::get(&self, key))
     }
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>> {
+        Ok(Box::new(
+            self.range(&range, direction)
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ))
+    }
 }
 
 impl<T: Clone> InMemoryDb<T> {
     pub fn set(&mut self, key: &str, value: &T) -> () {
-        self.map.insert(String::from(key), value.clone());
+        match self.map {
+            Map::Hashed(ref mut map) => map.insert(String::from(key), value.clone()),
+            Map::Sorted(ref mut map) => map.insert(String::from(key), value.clone()),
+        };
     }
     pub fn delete(&mut self, key: &str) -> () {
-        self.map.remove(key);
+        match self.map {
+            Map::Hashed(ref mut map) => map.remove(key),
+            Map::Sorted(ref mut map) => map.remove(key),
+        };
     }
     pub fn get(&self, key: &str) -> Option<T> {
-        self.map.get(key).map(|value| value.clone())
+        match self.map {
+            Map::Hashed(ref map) => map.get(key).cloned(),
+            Map::Sorted(ref map) => map.get(key).cloned(),
+        }
     }
     pub fn keys(&self) -> Vec<&String> {
-        Vec::from_iter(self.map.keys())
+        match self.map {
+            Map::Hashed(ref map) => Vec::from_iter(map.keys()),
+            Map::Sorted(ref map) => Vec::from_iter(map.keys()),
+        }
+    }
+    // entries in the range, ordered by key in the given direction; the sorted map seeks straight
+    // to the start of the range, the hashed one has to go through all of its keys
+    pub fn range(
+        &self,
+        range: &KeyRange,
+        direction: ScanDirection,
+    ) -> Box<dyn Iterator<Item = (&String, &T)> + '_> {
+        if range.is_empty() {
+            return Box::new(empty());
+        }
+        match (&self.map, direction) {
+            (Map::Sorted(map), ScanDirection::Forward) => {
+                Box::new(map.range::<str, _>(range.as_bounds()))
+            }
+            (Map::Sorted(map), ScanDirection::Reverse) => {
+                Box::new(map.range::<str, _>(range.as_bounds()).rev())
+            }
+            (Map::Hashed(map), _) => {
+                let mut entries: Vec<(&String, &T)> =
+                    map.iter().filter(|(key, _)| range.contains(key)).collect();
+                entries.sort_by_key(|&(key, _)| key);
+                if direction == ScanDirection::Reverse {
+                    entries.reverse();
+                }
+                Box::new(entries.into_iter())
+            }
+        }
     }
     pub fn new() -> InMemoryDb<T> {
         InMemoryDb {
-            map: HashMap::new(),
+            map: Map::Hashed(HashMap::new()),
+            last_seq: 0,
+        }
+    }
+    pub fn new_sorted() -> InMemoryDb<T> {
+        InMemoryDb {
+            map: Map::Sorted(BTreeMap::new()),
+            last_seq: 0,
         }
     }
 }
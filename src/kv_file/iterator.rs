@@ -1,50 +1,82 @@
-use std::{
-    fs::File,
-    io::{BufReader, Seek, SeekFrom},
-};
+use std::collections::VecDeque;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 
-use crate::error::DbResult;
+use crate::error::{DbResult, Error};
 
-use super::{utils::read_line, KVLine};
+use super::{
+    utils::{read_record, RecordRead},
+    KVLine,
+};
 
 #[derive(Debug)]
-pub enum KVFileIterator<'a> {
+pub enum KVFileIterator<R: Read + Seek> {
     Stopped,
-    Running(BufReader<&'a mut File>),
+    // the base offset is added to positions in the reader, for readers over a single block; the
+    // lines are the entries of the last batch read that are yet to be returned
+    Running(BufReader<R>, String, u64, VecDeque<KVLine>),
 }
 
-impl<'a> Iterator for KVFileIterator<'a> {
+impl<R: Read + Seek> Iterator for KVFileIterator<R> {
     type Item = DbResult<KVLine>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let Self::Running(reader) = self else {
+        let Self::Running(reader, file_path, base_offset, batch_lines) = self else {
             return None;
         };
-        let Ok(offset) = reader.stream_position() else {
+        if let Some(line) = batch_lines.pop_front() {
+            return Some(Ok(line));
+        }
+        let Ok(position) = reader.stream_position() else {
             return None;
         };
-        match read_line(reader) {
-            Ok(None) => {
-                *self = Self::Stopped;
-                None
+        let offset = *base_offset + position;
+        let result = match read_record(reader) {
+            Ok(RecordRead::Record(key, status, seq)) => {
+                return Some(Ok(KVLine {
+                    key,
+                    status,
+                    seq,
+                    offset,
+                }))
             }
-            Ok(Some((key, status))) => Some(Ok(KVLine {
-                key,
-                status,
-                offset,
-            })),
-            Err(e) => {
-                *self = Self::Stopped;
-                Some(Err(e))
+            Ok(RecordRead::Batch(entries, first_seq)) => {
+                batch_lines.extend(entries.into_iter().zip(first_seq..).map(
+                    |((key, status), seq)| KVLine {
+                        key,
+                        status,
+                        seq,
+                        offset,
+                    },
+                ));
+                // an empty batch has nothing to yield, move on to the next record
+                return self.next();
             }
-        }
+            Ok(RecordRead::Eof) => None,
+            Ok(RecordRead::TornTail) => Some(Err(Error::TornTail(file_path.clone(), offset))),
+            Ok(RecordRead::Corrupted(msg)) => {
+                Some(Err(Error::Corrupted(file_path.clone(), offset, msg)))
+            }
+            Err(e) => Some(Err(e)),
+        };
+        *self = Self::Stopped;
+        result
     }
 }
 
-impl<'a> KVFileIterator<'a> {
-    pub fn new(file: &'a mut File, offset: u64) -> DbResult<KVFileIterator<'a>> {
-        file.seek(SeekFrom::Start(offset))?;
-        Ok(Self::Running(BufReader::new(file)))
+impl<R: Read + Seek> KVFileIterator<R> {
+    pub fn new(
+        mut reader: R,
+        file_path: String,
+        base_offset: u64,
+        offset: u64,
+    ) -> DbResult<KVFileIterator<R>> {
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(Self::Running(
+            BufReader::new(reader),
+            file_path,
+            base_offset,
+            VecDeque::new(),
+        ))
     }
     pub fn try_next(&mut self) -> DbResult<Option<KVLine>> {
         match self.next() {
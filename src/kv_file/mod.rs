@@ -1,30 +1,79 @@
 use std::fs::{self, File, OpenOptions};
-use std::io::{ErrorKind, Seek, SeekFrom, Write};
-use std::os::unix::fs::MetadataExt;
+use std::io::{Cursor, ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
 
+use crate::encoding::{decode_record_header, RECORD_HEADER_SIZE};
 use crate::error::{DbResult, Error};
-use crate::kvdb::KeyStatus;
+use crate::kvdb::{write_batch::WriteBatch, KeyStatus};
 
-use self::iterator::KVFileIterator;
-use self::utils::write_line;
+use self::utils::{write_batch_record, write_record};
+
+pub use self::iterator::KVFileIterator;
+pub use self::reader::FileReader;
 
 mod iterator;
+mod reader;
 mod utils;
 
-const DELIMITER: &str = ",";
-const TOMBSTONE: &str = "🪦";
+// record layout: checksum of the payload (4 bytes), payload length (4 bytes), checksum of the two
+// fields before it (4 bytes), payload
+// payload layout: type (1 byte), sequence number (8 bytes), key length (4 bytes), key, value
+// batch payload layout: type (1 byte), sequence number of the first entry (8 bytes), number of
+// entries (4 bytes), entries, which are numbered one after the other
+// batch entry layout: type (1 byte), key length (4 bytes), key, value length (4 bytes), value
+const PAYLOAD_HEADER_SIZE: usize = 13;
+const BATCH_ENTRY_HEADER_SIZE: usize = 9;
+const MAX_PAYLOAD_SIZE: usize = u32::MAX as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum RecordType {
+    Put = 1,
+    Tombstone = 2,
+    Batch = 3,
+}
+
+impl TryFrom<u8> for RecordType {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, String> {
+        match value {
+            1 => Ok(RecordType::Put),
+            2 => Ok(RecordType::Tombstone),
+            3 => Ok(RecordType::Batch),
+            _ => Err(format!("unknown record type {}", value)),
+        }
+    }
+}
+
+// What to do when a file ends with a partially written record, which is what a crash in the middle
+// of an append leaves behind. Corruption anywhere before the tail is always reported.
+#[derive(Clone, Copy, Debug)]
+pub enum RecoveryPolicy {
+    TruncateTornTail,
+    Refuse,
+}
 
 #[derive(Debug)]
 pub struct KVLine {
     pub key: String,
     pub status: KeyStatus<String>,
+    // the sequence number the write was given, a record of a later write has a higher one
+    pub seq: u64,
     pub offset: u64,
 }
 
+pub type BlockIterator = KVFileIterator<Cursor<Arc<[u8]>>>;
+
+// A file of records. The file is opened on first use and its handle is kept for as long as the
+// KVFile lives, and shared with the KVFiles made from it, reads are positional so they don't need
+// the handle to themselves and any number of them can run at once.
 pub struct KVFile {
     pub dir_path: String,
     pub file_name: String,
-    file: Option<File>,
+    file: OnceLock<Arc<File>>,
 }
 
 impl KVFile {
@@ -32,35 +81,127 @@ impl KVFile {
         Ok(KVFile {
             dir_path: dir_path.to_string(),
             file_name: file_name.to_string(),
-            file: None,
+            file: OnceLock::new(),
         })
     }
-    pub fn copy(file: &Self) -> DbResult<KVFile> {
-        Self::new(&file.dir_path, &file.file_name)
+    // a KVFile sharing the file's handle, it keeps reading the same file after the original is
+    // renamed or deleted
+    pub fn share(file: &Self) -> DbResult<KVFile> {
+        let shared = Self::new(&file.dir_path, &file.file_name)?;
+        let _ = shared.file.set(Arc::clone(file.open_file()?));
+        Ok(shared)
     }
-    pub fn iter(&mut self) -> DbResult<KVFileIterator> {
+    pub fn iter(&self) -> DbResult<KVFileIterator<FileReader>> {
         self.create_iterator(0)
     }
-    pub fn iter_from_offset(&mut self, offset: u64) -> DbResult<KVFileIterator> {
+    pub fn iter_from_offset(&self, offset: u64) -> DbResult<KVFileIterator<FileReader>> {
         self.create_iterator(offset)
     }
-    pub fn size(&mut self) -> DbResult<u64> {
-        self.open_file()?;
-        let metadata = self.file.as_mut().unwrap().metadata()?;
-        Ok(metadata.size())
+    pub fn size(&self) -> DbResult<u64> {
+        Ok(self.open_file()?.metadata()?.size())
+    }
+    pub fn last_modified(&self) -> DbResult<SystemTime> {
+        Ok(self.open_file()?.metadata()?.modified()?)
+    }
+    pub fn append_line(
+        &mut self,
+        key: &str,
+        status: &KeyStatus<String>,
+        seq: u64,
+    ) -> DbResult<u64> {
+        let mut file: &File = self.open_file()?;
+        let pos = file.seek(SeekFrom::End(0))?;
+        write_record(&mut file, key, status, seq).and(Ok(pos))
     }
-    pub fn append_line(&mut self, key: &str, status: &KeyStatus<String>) -> DbResult<u64> {
-        self.open_file()?;
-        let file = self.file.as_mut().unwrap();
+    // appends the whole batch as one record, its entries all have the record's offset and are
+    // numbered from `first_seq` on
+    pub fn append_batch(&mut self, batch: &WriteBatch, first_seq: u64) -> DbResult<u64> {
+        let mut file: &File = self.open_file()?;
         let pos = file.seek(SeekFrom::End(0))?;
-        write_line(file, key, status).and(Ok(pos))
+        write_batch_record(&mut file, batch, first_seq).and(Ok(pos))
+    }
+    pub fn append_bytes(&mut self, bytes: &[u8]) -> DbResult<u64> {
+        let mut file: &File = self.open_file()?;
+        let pos = file.seek(SeekFrom::End(0))?;
+        file.write_all(bytes)?;
+        Ok(pos)
+    }
+    pub fn read_bytes(&self, offset: u64, len: u64) -> DbResult<Vec<u8>> {
+        let mut buf = vec![0u8; len as usize];
+        self.open_file()?.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+    // reads `len` bytes of whole records in one go and iterates over them in memory
+    pub fn read_block(&self, offset: u64, len: u64) -> DbResult<BlockIterator> {
+        let block = self.read_bytes(offset, len)?;
+        self.iter_block(offset, block.into())
     }
-    pub fn read_at_offset(&mut self, offset: u64) -> DbResult<Option<String>> {
+    // iterates over the whole records of a block that was read from the offset earlier
+    pub fn iter_block(&self, offset: u64, block: Arc<[u8]>) -> DbResult<BlockIterator> {
+        KVFileIterator::new(Cursor::new(block), self.get_file_path(), offset, 0)
+    }
+    // reads the bytes of the whole record at the offset, header included
+    pub fn read_record_bytes(&self, offset: u64) -> DbResult<Vec<u8>> {
+        let header = self.read_bytes(offset, RECORD_HEADER_SIZE as u64)?;
+        let (_, payload_len) = decode_record_header(&header)
+            .map_err(|msg| Error::Corrupted(self.get_file_path(), offset, msg))?;
+        let len = (RECORD_HEADER_SIZE + payload_len) as u64;
+        if offset + len > self.size()? {
+            return Err(Error::Corrupted(
+                self.get_file_path(),
+                offset,
+                "record runs past the end of the file".to_string(),
+            ));
+        }
+        self.read_bytes(offset, len)
+    }
+    pub fn sync(&mut self) -> DbResult<()> {
+        if let Some(file) = self.file.get() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+    // the line the record at the offset holds for the key, a batch record can hold several keys
+    // and the last entry for a key is the one that counts
+    pub fn read_at_offset(&self, offset: u64, key: &str) -> DbResult<Option<KVLine>> {
+        let mut found = None;
         for line_result in self.iter_from_offset(offset)? {
             let line = line_result?;
-            return Ok(line.status.into());
+            if line.offset != offset {
+                break;
+            }
+            if line.key == key {
+                found = Some(line);
+            }
         }
-        Ok(None)
+        Ok(found)
+    }
+    pub fn recover(
+        &mut self,
+        policy: RecoveryPolicy,
+        process_line: &mut dyn FnMut(KVLine) -> DbResult<()>,
+    ) -> DbResult<()> {
+        let mut torn_tail_offset = None;
+        for line_result in self.iter()? {
+            match line_result {
+                Ok(line) => process_line(line)?,
+                Err(Error::TornTail(_, offset)) => torn_tail_offset = Some(offset),
+                Err(e) => return Err(e),
+            }
+        }
+        let Some(offset) = torn_tail_offset else {
+            return Ok(());
+        };
+        match policy {
+            RecoveryPolicy::TruncateTornTail => self.truncate(offset),
+            RecoveryPolicy::Refuse => Err(Error::TornTail(self.get_file_path(), offset)),
+        }
+    }
+    pub fn truncate(&mut self, len: u64) -> DbResult<()> {
+        let file = self.open_file()?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        Ok(())
     }
     pub fn delete(&mut self) -> DbResult<()> {
         self.close_file()?;
@@ -89,39 +230,46 @@ impl KVFile {
 
         Ok(())
     }
-    fn get_file_path(&self) -> String {
+    pub fn get_file_path(&self) -> String {
         get_file_path(&self.dir_path, &self.file_name)
     }
-    fn open_file(&mut self) -> DbResult<()> {
-        if self.file.is_some() {
-            return Ok(());
+    pub fn open_file(&self) -> DbResult<&Arc<File>> {
+        if let Some(file) = self.file.get() {
+            return Ok(file);
         }
         fs::create_dir_all(&self.dir_path)?;
         let file_path = get_file_path(&self.dir_path, &self.file_name);
-        self.file = Some(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(file_path)
-                .map_err(Error::from)?,
-        );
-        Ok(())
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(file_path)
+            .map_err(Error::from)?;
+        // another reader may have opened it in the meantime, both handles are to the same file
+        Ok(self.file.get_or_init(|| Arc::new(file)))
     }
     fn close_file(&mut self) -> DbResult<()> {
-        if let Some(mut file) = self.file.take() {
-            file.flush()?;
+        if let Some(file) = self.file.take() {
+            (&*file).flush()?;
         }
         Ok(())
     }
-    fn create_iterator(&mut self, offset: u64) -> DbResult<KVFileIterator> {
-        self.open_file()?;
-        let file = self.file.as_mut().unwrap();
-        KVFileIterator::new(file, offset)
+    fn create_iterator(&self, offset: u64) -> DbResult<KVFileIterator<FileReader>> {
+        let reader = FileReader::new(Arc::clone(self.open_file()?));
+        KVFileIterator::new(reader, self.get_file_path(), 0, offset)
     }
 }
 
+// encodes a record the same way `append_line` writes it, for callers that batch records up
+pub fn encode_record(
+    buf: &mut Vec<u8>,
+    key: &str,
+    status: &KeyStatus<String>,
+    seq: u64,
+) -> DbResult<()> {
+    write_record(buf, key, status, seq)
+}
+
 fn get_file_path(dir_path: &str, file_name: &str) -> String {
     dir_path.to_owned() + file_name
 }
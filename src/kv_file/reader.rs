@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+// Reads a file handle shared with other readers with positional reads, keeping its own position,
+// so that no reader moves the others' and none of them needs the handle to itself.
+pub struct FileReader {
+    file: Arc<File>,
+    position: u64,
+}
+
+impl FileReader {
+    pub fn new(file: Arc<File>) -> Self {
+        FileReader { file, position: 0 }
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num_read = self.file.read_at(buf, self.position)?;
+        self.position += num_read as u64;
+        Ok(num_read)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.file.metadata()?.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            ));
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
@@ -1,56 +1,224 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader, Read, Write},
-};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
 
-use crate::{error::Error, kvdb::KeyStatus};
+use super::{RecordType, BATCH_ENTRY_HEADER_SIZE, MAX_PAYLOAD_SIZE, PAYLOAD_HEADER_SIZE};
+use crate::crc::crc32;
+use crate::encoding::{decode_record_header, put_record_header, RECORD_HEADER_SIZE};
 use crate::error::DbResult;
-use super::{DELIMITER, TOMBSTONE};
+use crate::kvdb::write_batch::WriteBatch;
+use crate::{error::Error, kvdb::KeyStatus};
 
-pub fn read_line<T: Read>(
-    reader: &mut BufReader<T>,
-) -> DbResult<Option<(String, KeyStatus<String>)>> {
-    let mut buf = String::new();
-    let bytes_read = reader.read_line(&mut buf)?;
-    if bytes_read == 0 {
-        return Ok(None);
-    }
-    // remove \n
-    let _ = buf.split_off(buf.len() - 1);
-    match buf.split_once(DELIMITER) {
-        Some((key, read_value)) => {
-            let mut status = KeyStatus::Deleted;
-            if read_value != TOMBSTONE {
-                status = KeyStatus::Present(read_value.to_string())
-            }
-            Ok(Some((key.to_string(), status)))
-        }
-        None => Err(Error::InvalidData(format!(
-            "ill-formed line in file, expected the delimiter '{}' to be present",
-            DELIMITER
-        ))),
+pub enum RecordRead {
+    Record(String, KeyStatus<String>, u64),
+    // the entries along with the sequence number of the first, the others follow it in order
+    Batch(Vec<(String, KeyStatus<String>)>, u64),
+    Eof,
+    // the header or the payload runs past the end of the file, or it is the last record in the
+    // file and a checksum doesn't match; both are what an interrupted append leaves behind
+    TornTail,
+    Corrupted(String),
+}
+
+pub fn read_record<T: Read>(reader: &mut BufReader<T>) -> DbResult<RecordRead> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    match read_exact_or_eof(reader, &mut header)? {
+        Some(0) => return Ok(RecordRead::Eof),
+        Some(_) => return Ok(RecordRead::TornTail),
+        None => {}
+    }
+    let (crc, payload_len) = match decode_record_header(&header) {
+        Ok(fields) => fields,
+        Err(msg) => return at_tail_or_corrupted(reader, msg),
+    };
+
+    // read incrementally rather than trusting a possibly garbage length for the allocation
+    let mut payload = Vec::new();
+    reader
+        .by_ref()
+        .take(payload_len as u64)
+        .read_to_end(&mut payload)?;
+    // the header's checksum matched, so the length is the one that was written and the record was
+    // cut off by the end of the file
+    if payload.len() < payload_len {
+        return Ok(RecordRead::TornTail);
+    }
+    if crc32(&payload) != crc {
+        return at_tail_or_corrupted(reader, "checksum mismatch".to_string());
+    }
+
+    match decode_payload(payload) {
+        Ok(record) => Ok(record),
+        Err(msg) => Ok(RecordRead::Corrupted(msg)),
+    }
+}
+
+pub fn write_record<W: Write>(
+    writer: &mut W,
+    key: &str,
+    status: &KeyStatus<String>,
+    seq: u64,
+) -> DbResult<()> {
+    let (record_type, value) = split_status(status);
+    let payload_len = PAYLOAD_HEADER_SIZE + key.len() + value.len();
+    check_payload_len(payload_len)?;
+
+    let mut payload = Vec::with_capacity(payload_len);
+    payload.push(record_type as u8);
+    payload.extend_from_slice(&seq.to_le_bytes());
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(key.as_bytes());
+    payload.extend_from_slice(value.as_bytes());
+    write_payload(writer, &payload)
+}
+
+pub fn write_batch_record<W: Write>(
+    writer: &mut W,
+    batch: &WriteBatch,
+    first_seq: u64,
+) -> DbResult<()> {
+    let payload_len = PAYLOAD_HEADER_SIZE
+        + batch
+            .entries()
+            .iter()
+            .map(|(key, status)| BATCH_ENTRY_HEADER_SIZE + key.len() + split_status(status).1.len())
+            .sum::<usize>();
+    check_payload_len(payload_len)?;
+
+    let mut payload = Vec::with_capacity(payload_len);
+    payload.push(RecordType::Batch as u8);
+    payload.extend_from_slice(&first_seq.to_le_bytes());
+    payload.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+    for (key, status) in batch.entries() {
+        let (record_type, value) = split_status(status);
+        payload.push(record_type as u8);
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(key.as_bytes());
+        payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        payload.extend_from_slice(value.as_bytes());
     }
+    write_payload(writer, &payload)
 }
 
-pub fn write_line(file: &mut File, key: &str, value: &KeyStatus<String>) -> DbResult<()> {
-    if key.contains(DELIMITER) {
+fn split_status(status: &KeyStatus<String>) -> (RecordType, &str) {
+    match status {
+        KeyStatus::Present(ref value) => (RecordType::Put, value.as_str()),
+        KeyStatus::Deleted => (RecordType::Tombstone, ""),
+    }
+}
+
+fn check_payload_len(payload_len: usize) -> DbResult<()> {
+    if payload_len > MAX_PAYLOAD_SIZE {
         return Err(Error::InvalidInput(format!(
-            "key must not have '{}'",
-            DELIMITER
+            "record of {} bytes exceeds the maximum of {} bytes",
+            payload_len, MAX_PAYLOAD_SIZE
         )));
     }
-    let written_value = match value {
-        KeyStatus::Present(ref value) => {
-            if value == TOMBSTONE {
-                return Err(Error::InvalidInput(format!(
-                    "storing {} as a value is not supported",
-                    TOMBSTONE
-                )));
-            }
-            value
-        }
-        KeyStatus::Deleted => TOMBSTONE,
-    };
-    writeln!(file, "{}{}{}", key, DELIMITER, written_value)?;
     Ok(())
 }
+
+fn write_payload<W: Write>(writer: &mut W, payload: &[u8]) -> DbResult<()> {
+    // the whole record goes out in a single write
+    let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    put_record_header(&mut buf, payload);
+    buf.extend_from_slice(payload);
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+fn decode_payload(mut payload: Vec<u8>) -> Result<RecordRead, String> {
+    if payload.len() < PAYLOAD_HEADER_SIZE {
+        return Err(format!("payload of {} bytes is too short", payload.len()));
+    }
+    let record_type = RecordType::try_from(payload[0])?;
+    let seq = u64::from_le_bytes(payload[1..9].try_into().unwrap());
+    if record_type == RecordType::Batch {
+        return decode_batch_payload(&payload).map(|entries| RecordRead::Batch(entries, seq));
+    }
+    let key_len = u32::from_le_bytes(payload[9..13].try_into().unwrap()) as usize;
+    if PAYLOAD_HEADER_SIZE + key_len > payload.len() {
+        return Err(format!(
+            "key of {} bytes doesn't fit in a payload of {} bytes",
+            key_len,
+            payload.len()
+        ));
+    }
+
+    let value = payload.split_off(PAYLOAD_HEADER_SIZE + key_len);
+    let key = payload.split_off(PAYLOAD_HEADER_SIZE);
+    let key = decode_utf8(key, "key")?;
+    let status = match record_type {
+        RecordType::Put => KeyStatus::Present(decode_utf8(value, "value")?),
+        _ => KeyStatus::Deleted,
+    };
+    Ok(RecordRead::Record(key, status, seq))
+}
+
+fn decode_batch_payload(payload: &[u8]) -> Result<Vec<(String, KeyStatus<String>)>, String> {
+    let num_entries = u32::from_le_bytes(payload[9..13].try_into().unwrap()) as usize;
+    // not trusting the count for the allocation either, each entry takes at least its header
+    let mut entries = Vec::with_capacity(num_entries.min(payload.len() / BATCH_ENTRY_HEADER_SIZE));
+    let mut rest = &payload[PAYLOAD_HEADER_SIZE..];
+    for _ in 0..num_entries {
+        let (field, after) = take_bytes(rest, 1)?;
+        let record_type = RecordType::try_from(field[0])?;
+        let (key, after) = take_field(after)?;
+        let (value, after) = take_field(after)?;
+        rest = after;
+        let key = decode_utf8(key.to_vec(), "key")?;
+        let status = match record_type {
+            RecordType::Put => KeyStatus::Present(decode_utf8(value.to_vec(), "value")?),
+            RecordType::Tombstone => KeyStatus::Deleted,
+            RecordType::Batch => return Err("batch nested in a batch".to_string()),
+        };
+        entries.push((key, status));
+    }
+    if !rest.is_empty() {
+        return Err(format!("{} bytes left over after the batch", rest.len()));
+    }
+    Ok(entries)
+}
+
+// a field prefixed with its length (4 bytes), and what's left after it
+fn take_field(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let (len, rest) = take_bytes(bytes, 4)?;
+    take_bytes(rest, u32::from_le_bytes(len.try_into().unwrap()) as usize)
+}
+
+fn take_bytes(bytes: &[u8], len: usize) -> Result<(&[u8], &[u8]), String> {
+    if len > bytes.len() {
+        return Err(format!(
+            "batch entry of {} bytes doesn't fit in the {} bytes left",
+            len,
+            bytes.len()
+        ));
+    }
+    Ok(bytes.split_at(len))
+}
+
+fn decode_utf8(bytes: Vec<u8>, field: &str) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|_| format!("{} of record is not valid UTF-8", field))
+}
+
+// a record that fails a checksum is only torn if nothing follows it
+fn at_tail_or_corrupted<T: Read>(reader: &mut BufReader<T>, msg: String) -> DbResult<RecordRead> {
+    Ok(match reader.fill_buf()?.is_empty() {
+        true => RecordRead::TornTail,
+        false => RecordRead::Corrupted(msg),
+    })
+}
+
+// returns the number of bytes read if the reader hit EOF before filling the buffer
+fn read_exact_or_eof<T: Read>(
+    reader: &mut BufReader<T>,
+    buf: &mut [u8],
+) -> DbResult<Option<usize>> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(Some(filled)),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,177 @@
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{DbResult, Error};
+
+pub type TxnId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+// Shared and exclusive locks on keys, held by transactions until they release all of them at once.
+// A lock that can't be granted right away is waited for, up to the wait timeout. Before waiting,
+// the wait-for graph is checked for a cycle through the waiting transaction, and if there is one
+// the youngest transaction in it, the one with the highest id, is picked as the victim: its wait
+// fails with `Error::Deadlock`, and it has to release its locks for the others to go on. A lock is
+// granted as soon as it's compatible with the ones held, waiting requests don't hold back newer
+// ones.
+pub struct LockManager {
+    locked_table: Mutex<LockTable>,
+    // notified whenever locks are released or a victim is picked
+    lock_table_changed: Condvar,
+    wait_timeout: Duration,
+}
+
+#[derive(Default)]
+struct LockTable {
+    next_txn_id: TxnId,
+    // the holders of each locked key, with the mode they hold it in
+    locks: HashMap<String, HashMap<TxnId, LockMode>>,
+    // the keys each transaction holds a lock on
+    held_keys: HashMap<TxnId, HashSet<String>>,
+    // the lock each waiting transaction is waiting for
+    waits: HashMap<TxnId, (String, LockMode)>,
+    // waiting transactions picked to break a deadlock, each takes itself out when it wakes up
+    victims: HashSet<TxnId>,
+}
+
+impl LockManager {
+    pub fn new(wait_timeout: Duration) -> Self {
+        LockManager {
+            locked_table: Mutex::new(LockTable::default()),
+            lock_table_changed: Condvar::new(),
+            wait_timeout,
+        }
+    }
+    // ids only grow, so a younger transaction has a higher one
+    pub fn new_txn_id(&self) -> DbResult<TxnId> {
+        let mut table = self.locked_table.lock()?;
+        table.next_txn_id += 1;
+        Ok(table.next_txn_id)
+    }
+    // returns once the transaction holds the lock, a shared lock it already holds is upgraded,
+    // fails with `Error::Deadlock` if it's picked as a victim and with `Error::LockTimeout` if it
+    // waits for longer than the timeout
+    pub fn acquire(&self, txn_id: TxnId, key: &str, mode: LockMode) -> DbResult<()> {
+        let deadline = Instant::now() + self.wait_timeout;
+        let mut table = self.locked_table.lock()?;
+        loop {
+            if table.victims.remove(&txn_id) {
+                table.waits.remove(&txn_id);
+                return Err(Error::Deadlock(format!(
+                    "transaction {} was picked as the victim while waiting for {}",
+                    txn_id, key
+                )));
+            }
+            if table.blockers(txn_id, key, mode).is_empty() {
+                table.waits.remove(&txn_id);
+                table.grant(txn_id, key, mode);
+                return Ok(());
+            }
+            if let Entry::Vacant(wait) = table.waits.entry(txn_id) {
+                wait.insert((key.to_owned(), mode));
+                if let Some(victim) = table.find_deadlock_victim(txn_id) {
+                    table.victims.insert(victim);
+                    self.lock_table_changed.notify_all();
+                    continue;
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                table.waits.remove(&txn_id);
+                return Err(Error::LockTimeout(format!(
+                    "transaction {} waited for {} for longer than {:?}",
+                    txn_id, key, self.wait_timeout
+                )));
+            }
+            table = self
+                .lock_table_changed
+                .wait_timeout(table, deadline - now)?
+                .0;
+        }
+    }
+    pub fn release_all(&self, txn_id: TxnId) -> DbResult<()> {
+        let mut table = self.locked_table.lock()?;
+        for key in table.held_keys.remove(&txn_id).unwrap_or_default() {
+            if let Some(holders) = table.locks.get_mut(&key) {
+                holders.remove(&txn_id);
+                if holders.is_empty() {
+                    table.locks.remove(&key);
+                }
+            }
+        }
+        table.waits.remove(&txn_id);
+        table.victims.remove(&txn_id);
+        self.lock_table_changed.notify_all();
+        Ok(())
+    }
+}
+
+impl LockTable {
+    // the other transactions holding the key in a mode the lock can't be granted alongside
+    fn blockers(&self, txn_id: TxnId, key: &str, mode: LockMode) -> Vec<TxnId> {
+        let Some(holders) = self.locks.get(key) else {
+            return vec![];
+        };
+        holders
+            .iter()
+            .filter(|&(&holder, &held_mode)| {
+                holder != txn_id
+                    && (mode == LockMode::Exclusive || held_mode == LockMode::Exclusive)
+            })
+            .map(|(&holder, _)| holder)
+            .collect()
+    }
+    fn grant(&mut self, txn_id: TxnId, key: &str, mode: LockMode) {
+        let held_mode = self
+            .locks
+            .entry(key.to_owned())
+            .or_default()
+            .entry(txn_id)
+            .or_insert(mode);
+        if mode == LockMode::Exclusive {
+            *held_mode = LockMode::Exclusive;
+        }
+        self.held_keys
+            .entry(txn_id)
+            .or_default()
+            .insert(key.to_owned());
+    }
+    // the transactions the waiting transaction waits for, none if it isn't waiting
+    fn waits_for(&self, txn_id: TxnId) -> Vec<TxnId> {
+        match self.waits.get(&txn_id) {
+            Some((key, mode)) => self.blockers(txn_id, key, *mode),
+            None => vec![],
+        }
+    }
+    // the youngest transaction of a cycle in the wait-for graph through the given transaction,
+    // which just started waiting, so any new cycle goes through it; transactions already picked
+    // are left out, their waits are about to end
+    fn find_deadlock_victim(&self, txn_id: TxnId) -> Option<TxnId> {
+        let mut path = vec![txn_id];
+        let mut visited = HashSet::from([txn_id]);
+        let mut pending = vec![self.waits_for(txn_id)];
+        while let Some(next) = pending.last_mut() {
+            let Some(next_txn_id) = next.pop() else {
+                pending.pop();
+                path.pop();
+                continue;
+            };
+            if self.victims.contains(&next_txn_id) {
+                continue;
+            }
+            if next_txn_id == txn_id {
+                return path.into_iter().max();
+            }
+            if visited.insert(next_txn_id) {
+                path.push(next_txn_id);
+                pending.push(self.waits_for(next_txn_id));
+            }
+        }
+        None
+    }
+}
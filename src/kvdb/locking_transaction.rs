@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::DbResult;
+
+use super::{
+    lock_manager::{LockManager, LockMode, TxnId},
+    write_batch::WriteBatch,
+    KVDb, KeyStatus,
+};
+
+// A handle to a DB that hands out pessimistic transactions, which lock the keys they use through
+// a shared lock manager. Writes made to the DB without going through a transaction don't take any
+// locks.
+pub struct LockingDb<T: KVDb> {
+    locked_db: Arc<Mutex<T>>,
+    lock_manager: Arc<LockManager>,
+}
+
+impl<T: KVDb> Clone for LockingDb<T> {
+    fn clone(&self) -> Self {
+        LockingDb {
+            locked_db: Arc::clone(&self.locked_db),
+            lock_manager: Arc::clone(&self.lock_manager),
+        }
+    }
+}
+
+impl<T: KVDb> LockingDb<T> {
+    pub fn new(db: T, lock_wait_timeout: Duration) -> Self {
+        LockingDb {
+            locked_db: Arc::new(Mutex::new(db)),
+            lock_manager: Arc::new(LockManager::new(lock_wait_timeout)),
+        }
+    }
+    pub fn description(&self) -> DbResult<String> {
+        Ok(self.locked_db.lock()?.description())
+    }
+    // reads the last committed value, a transaction's writes only reach the DB together
+    pub fn get(&self, key: &str) -> DbResult<Option<String>> {
+        self.locked_db.lock()?.get(key)
+    }
+    pub fn begin(&self) -> DbResult<LockingTransaction<T>> {
+        Ok(LockingTransaction {
+            locked_db: Arc::clone(&self.locked_db),
+            lock_manager: Arc::clone(&self.lock_manager),
+            id: self.lock_manager.new_txn_id()?,
+            writes: BTreeMap::new(),
+            is_done: false,
+        })
+    }
+}
+
+// Two-phase locking: a read takes a shared lock on the key and a write an exclusive one, and every
+// lock is held until the transaction commits or rolls back, so transactions that use the same keys
+// run as if one after the other. Writes are buffered and applied as a single batch on commit. An
+// operation that fails with `Error::Deadlock` or `Error::LockTimeout` leaves the transaction with
+// the locks it had, it has to be rolled back, or dropped, which rolls it back too.
+pub struct LockingTransaction<T: KVDb> {
+    locked_db: Arc<Mutex<T>>,
+    lock_manager: Arc<LockManager>,
+    id: TxnId,
+    writes: BTreeMap<String, KeyStatus<String>>,
+    is_done: bool,
+}
+
+impl<T: KVDb> LockingTransaction<T> {
+    pub fn get(&mut self, key: &str) -> DbResult<Option<String>> {
+        self.read(key, LockMode::Shared)
+    }
+    // reads the key under an exclusive lock, for a key that's about to be written: two
+    // transactions that both read a key and then write it would each hold a shared lock the
+    // other one's upgrade waits for
+    pub fn get_for_update(&mut self, key: &str) -> DbResult<Option<String>> {
+        self.read(key, LockMode::Exclusive)
+    }
+    pub fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
+        self.write(key, KeyStatus::Present(value.to_owned()))
+    }
+    pub fn delete(&mut self, key: &str) -> DbResult<()> {
+        self.write(key, KeyStatus::Deleted)
+    }
+    pub fn commit(mut self) -> DbResult<()> {
+        self.is_done = true;
+        let result = self.apply_writes();
+        self.lock_manager.release_all(self.id)?;
+        result
+    }
+    pub fn rollback(mut self) -> DbResult<()> {
+        self.is_done = true;
+        self.lock_manager.release_all(self.id)
+    }
+    fn read(&mut self, key: &str, mode: LockMode) -> DbResult<Option<String>> {
+        self.lock_manager.acquire(self.id, key, mode)?;
+        match self.writes.get(key) {
+            Some(status) => Ok(status.clone().into()),
+            None => self.locked_db.lock()?.get(key),
+        }
+    }
+    fn write(&mut self, key: &str, status: KeyStatus<String>) -> DbResult<()> {
+        self.lock_manager
+            .acquire(self.id, key, LockMode::Exclusive)?;
+        self.writes.insert(key.to_owned(), status);
+        Ok(())
+    }
+    fn apply_writes(&self) -> DbResult<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for (key, status) in &self.writes {
+            batch.set_status(key, status.clone());
+        }
+        self.locked_db.lock()?.write(&batch)
+    }
+}
+
+impl<T: KVDb> Drop for LockingTransaction<T> {
+    fn drop(&mut self) {
+        if !self.is_done {
+            let _ = self.lock_manager.release_all(self.id);
+        }
+    }
+}
@@ -1,16 +1,66 @@
-use crate::error::DbResult;
+use std::ops::{Bound, RangeBounds};
 
+use crate::error::{DbResult, Error};
+
+use self::write_batch::WriteBatch;
+
+pub mod lock_manager;
+pub mod locking_transaction;
+pub mod shared_db;
+pub mod transaction;
+pub mod write_batch;
+
+pub type ScanIterator<'a> = Box<dyn Iterator<Item = DbResult<(String, String)>> + 'a>;
+// statuses of keys, each with the sequence number of the write that set it
+pub type StatusIterator<'a> =
+    Box<dyn Iterator<Item = DbResult<(String, KeyStatus<String>, u64)>> + 'a>;
+
+// keys and values are UTF-8 strings, any string can be stored but other bytes have to be encoded as
+// text first
 pub trait KVDb {
     fn description(&self) -> String;
     fn set(&mut self, key: &str, value: &str) -> DbResult<()>;
     fn delete(&mut self, key: &str) -> DbResult<()>;
     fn get(&mut self, key: &str) -> DbResult<Option<String>>;
+    // applies all of the batch's entries or none of them, a crash can't leave only some applied
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()>;
+    // the sequence number of the last write, every write gets a higher one than the writes before
+    // it
+    fn last_seq(&self) -> u64;
     fn set_status(&mut self, key: &str, status: &KeyStatus<String>) -> DbResult<()> {
         match status {
             KeyStatus::Deleted => self.delete(key),
             KeyStatus::Present(value) => self.set(key, &value),
         }
     }
+    // live key-value pairs in the range, ordered by key in the given direction
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>>;
+    // live key-value pairs whose key starts with `prefix`, in key order
+    fn scan_prefix(&mut self, prefix: &str) -> DbResult<ScanIterator<'_>> {
+        self.scan(KeyRange::prefix(prefix), ScanDirection::Forward)
+    }
+    // gives back the space held by overwritten and deleted entries, for DBs that don't already do
+    // it on their own
+    fn compact(&mut self) -> DbResult<()> {
+        Ok(())
+    }
+    // a view of the DB as of its last write, for DBs that keep the versions of a key it replaces
+    fn snapshot(&mut self) -> DbResult<Box<dyn Snapshot + Send>> {
+        Err(Error::InvalidInput(format!(
+            "{} doesn't support snapshots",
+            self.description()
+        )))
+    }
+}
+
+// A read-only view of a DB as of the sequence number it was taken at, writes made after it aren't
+// seen. The versions it reads are kept around for as long as it's alive, it's released when it's
+// dropped.
+pub trait Snapshot {
+    fn seq(&self) -> u64;
+    fn get(&self, key: &str) -> DbResult<Option<String>>;
+    // live key-value pairs in the range as of the snapshot, ordered by key in the given direction
+    fn scan(&self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'static>>;
 }
 
 #[derive(Clone, Debug)]
@@ -27,3 +77,97 @@ impl<T: Clone> Into<Option<T>> for KeyStatus<T> {
         }
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanDirection {
+    Forward,
+    Reverse,
+}
+
+impl ScanDirection {
+    // whether `key` comes before `other` when scanning in this direction
+    pub fn precedes(&self, key: &str, other: &str) -> bool {
+        match self {
+            ScanDirection::Forward => key < other,
+            ScanDirection::Reverse => key > other,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyRange {
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+}
+
+impl KeyRange {
+    // e.g. `KeyRange::new("a".."f")` or `KeyRange::new(..)`
+    pub fn new<'a, R: RangeBounds<&'a str>>(range: R) -> Self {
+        KeyRange {
+            start: range.start_bound().map(|key| key.to_string()),
+            end: range.end_bound().map(|key| key.to_string()),
+        }
+    }
+    // every key starting with `prefix`, i.e. from the prefix itself up to (excluding) the
+    // smallest string that sorts after all of them
+    pub fn prefix(prefix: &str) -> Self {
+        KeyRange {
+            start: Bound::Included(prefix.to_owned()),
+            end: prefix_successor(prefix).map_or(Bound::Unbounded, Bound::Excluded),
+        }
+    }
+    pub fn as_bounds(&self) -> (Bound<&str>, Bound<&str>) {
+        (
+            self.start.as_ref().map(|key| key.as_str()),
+            self.end.as_ref().map(|key| key.as_str()),
+        )
+    }
+    pub fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start >= end,
+            _ => false,
+        }
+    }
+    pub fn contains(&self, key: &str) -> bool {
+        !self.is_before_start(key) && !self.is_after_end(key)
+    }
+    pub fn is_before_start(&self, key: &str) -> bool {
+        match self.start {
+            Bound::Included(ref start) => key < start.as_str(),
+            Bound::Excluded(ref start) => key <= start.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+    pub fn is_after_end(&self, key: &str) -> bool {
+        match self.end {
+            Bound::Included(ref end) => key > end.as_str(),
+            Bound::Excluded(ref end) => key >= end.as_str(),
+            Bound::Unbounded => false,
+        }
+    }
+    // whether every key past `key` in the given direction is out of the range
+    pub fn is_exhausted_at(&self, key: &str, direction: ScanDirection) -> bool {
+        match direction {
+            ScanDirection::Forward => self.is_after_end(key),
+            ScanDirection::Reverse => self.is_before_start(key),
+        }
+    }
+}
+
+// bumps the last character that can be bumped and drops the ones after it, `None` if every
+// character is already `char::MAX`
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // `from_u32` rejects the surrogate code points, so this also skips over them
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
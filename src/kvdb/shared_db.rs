@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use crate::error::DbResult;
+
+use super::{write_batch::WriteBatch, KVDb, KeyRange, ScanDirection, ScanIterator};
+
+// A DB that synchronizes its own reads and writes, so they only need a shared reference to it.
+// Writes are serialized inside the DB, in a way that doesn't hold reads up while one of them is
+// stalled. Scans hold on to whatever they read from, so they don't borrow the DB either.
+pub trait ConcurrentKVDb: KVDb + Send + Sync {
+    fn set_shared(&self, key: &str, value: &str) -> DbResult<()>;
+    fn delete_shared(&self, key: &str) -> DbResult<()>;
+    // reads see either none of the batch or all of it
+    fn write_shared(&self, batch: &WriteBatch) -> DbResult<()>;
+    fn get_shared(&self, key: &str) -> DbResult<Option<String>>;
+    fn scan_shared(
+        &self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>>;
+}
+
+// A handle to a DB that can be cloned and sent to other threads. It doesn't lock anything itself,
+// the DB decides what its reads and writes wait on.
+pub struct SharedDb<T: ConcurrentKVDb> {
+    db: Arc<T>,
+}
+
+impl<T: ConcurrentKVDb> Clone for SharedDb<T> {
+    fn clone(&self) -> Self {
+        SharedDb {
+            db: Arc::clone(&self.db),
+        }
+    }
+}
+
+impl<T: ConcurrentKVDb> SharedDb<T> {
+    pub fn new(db: T) -> Self {
+        SharedDb { db: Arc::new(db) }
+    }
+    pub fn description(&self) -> String {
+        self.db.description()
+    }
+    pub fn set(&self, key: &str, value: &str) -> DbResult<()> {
+        self.db.set_shared(key, value)
+    }
+    pub fn delete(&self, key: &str) -> DbResult<()> {
+        self.db.delete_shared(key)
+    }
+    pub fn write(&self, batch: &WriteBatch) -> DbResult<()> {
+        self.db.write_shared(batch)
+    }
+    pub fn get(&self, key: &str) -> DbResult<Option<String>> {
+        self.db.get_shared(key)
+    }
+    pub fn scan(
+        &self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>> {
+        self.db.scan_shared(range, direction)
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::empty;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{DbResult, Error};
+
+use super::{write_batch::WriteBatch, KVDb, KeyRange, KeyStatus, ScanDirection, ScanIterator};
+
+// A handle to a DB that hands out optimistic transactions. Writes made through the handle, by a
+// transaction's commit or on their own, note the sequence number they got for each key, so that a
+// commit can tell whether anything it read was written to after the transaction began. Writes
+// made to the DB without going through the handle aren't seen.
+pub struct TransactionalDb<T: KVDb> {
+    locked_shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T: KVDb> {
+    db: T,
+    // the sequence number of the last write to each key, only kept for as long as some running
+    // transaction began before it
+    write_seqs: BTreeMap<String, u64>,
+    // the number of running transactions by the sequence number they began at
+    running: BTreeMap<u64, usize>,
+}
+
+impl<T: KVDb> Clone for TransactionalDb<T> {
+    fn clone(&self) -> Self {
+        TransactionalDb {
+            locked_shared: Arc::clone(&self.locked_shared),
+        }
+    }
+}
+
+impl<T: KVDb> TransactionalDb<T> {
+    pub fn new(db: T) -> Self {
+        TransactionalDb {
+            locked_shared: Arc::new(Mutex::new(Shared {
+                db,
+                write_seqs: BTreeMap::new(),
+                running: BTreeMap::new(),
+            })),
+        }
+    }
+    pub fn description(&self) -> DbResult<String> {
+        Ok(self.locked_shared.lock()?.db.description())
+    }
+    pub fn set(&self, key: &str, value: &str) -> DbResult<()> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.write(&batch)
+    }
+    pub fn delete(&self, key: &str) -> DbResult<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(&batch)
+    }
+    pub fn write(&self, batch: &WriteBatch) -> DbResult<()> {
+        self.locked_shared.lock()?.write(batch)
+    }
+    pub fn get(&self, key: &str) -> DbResult<Option<String>> {
+        self.locked_shared.lock()?.db.get(key)
+    }
+    // the pairs are read while the lock is held, so the scan is a consistent view
+    pub fn scan(
+        &self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>> {
+        let pairs = self
+            .locked_shared
+            .lock()?
+            .db
+            .scan(range, direction)?
+            .collect::<DbResult<Vec<(String, String)>>>()?;
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+    pub fn begin(&self) -> DbResult<Transaction<T>> {
+        let mut shared = self.locked_shared.lock()?;
+        let start_seq = shared.db.last_seq();
+        *shared.running.entry(start_seq).or_default() += 1;
+        Ok(Transaction {
+            locked_shared: Arc::clone(&self.locked_shared),
+            start_seq,
+            read_keys: BTreeSet::new(),
+            scanned_ranges: vec![],
+            writes: BTreeMap::new(),
+            is_done: false,
+        })
+    }
+}
+
+impl<T: KVDb> Shared<T> {
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        self.db.write(batch)?;
+        if !self.running.is_empty() {
+            let seq = self.db.last_seq();
+            for (key, _) in batch.entries() {
+                self.write_seqs.insert(key.clone(), seq);
+            }
+        }
+        Ok(())
+    }
+    // whether the key was written to after `seq`
+    fn is_written_after(&self, key: &str, seq: u64) -> bool {
+        self.write_seqs
+            .get(key)
+            .is_some_and(|&write_seq| write_seq > seq)
+    }
+    fn end_transaction(&mut self, start_seq: u64) {
+        if let Some(count) = self.running.get_mut(&start_seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.running.remove(&start_seq);
+            }
+        }
+        // writes made before every running transaction began can't conflict with any of them
+        match self.running.keys().next() {
+            Some(&oldest_start_seq) => self
+                .write_seqs
+                .retain(|_, &mut write_seq| write_seq > oldest_start_seq),
+            None => self.write_seqs.clear(),
+        }
+    }
+}
+
+// Reads go to the DB as they're made, and the keys and ranges read are kept track of. Writes are
+// buffered until the commit, which checks that none of what was read has been written to since the
+// transaction began, and then applies the writes as a single batch. A transaction sees its own
+// writes. Dropping it without committing rolls it back.
+pub struct Transaction<T: KVDb> {
+    locked_shared: Arc<Mutex<Shared<T>>>,
+    // the sequence number of the last write before the transaction began
+    start_seq: u64,
+    read_keys: BTreeSet<String>,
+    scanned_ranges: Vec<KeyRange>,
+    writes: BTreeMap<String, KeyStatus<String>>,
+    is_done: bool,
+}
+
+impl<T: KVDb> Transaction<T> {
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.writes
+            .insert(key.to_owned(), KeyStatus::Present(value.to_owned()));
+    }
+    pub fn delete(&mut self, key: &str) {
+        self.writes.insert(key.to_owned(), KeyStatus::Deleted);
+    }
+    pub fn get(&mut self, key: &str) -> DbResult<Option<String>> {
+        if let Some(status) = self.writes.get(key) {
+            return Ok(status.clone().into());
+        }
+        let value = self.locked_shared.lock()?.db.get(key)?;
+        self.read_keys.insert(key.to_owned());
+        Ok(value)
+    }
+    // the DB's pairs in the range with the transaction's own writes applied over them
+    pub fn scan(
+        &mut self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>> {
+        if range.is_empty() {
+            return Ok(Box::new(empty()));
+        }
+        let mut pairs = self
+            .locked_shared
+            .lock()?
+            .db
+            .scan(range.clone(), ScanDirection::Forward)?
+            .collect::<DbResult<BTreeMap<String, String>>>()?;
+        for (key, status) in self.writes.range::<str, _>(range.as_bounds()) {
+            match status {
+                KeyStatus::Present(value) => pairs.insert(key.clone(), value.clone()),
+                KeyStatus::Deleted => pairs.remove(key),
+            };
+        }
+        self.scanned_ranges.push(range);
+        let pairs: Vec<(String, String)> = match direction {
+            ScanDirection::Forward => pairs.into_iter().collect(),
+            ScanDirection::Reverse => pairs.into_iter().rev().collect(),
+        };
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+    // fails with `Error::Conflict` if a key the transaction read, or a key in a range it scanned,
+    // was written to after it began, in which case none of its writes are applied
+    pub fn commit(mut self) -> DbResult<()> {
+        self.is_done = true;
+        let mut shared = self.locked_shared.lock()?;
+        let result = self.validate_and_apply(&mut shared);
+        shared.end_transaction(self.start_seq);
+        result
+    }
+    pub fn rollback(mut self) -> DbResult<()> {
+        self.is_done = true;
+        self.locked_shared.lock()?.end_transaction(self.start_seq);
+        Ok(())
+    }
+    fn validate_and_apply(&self, shared: &mut Shared<T>) -> DbResult<()> {
+        if let Some(key) = self
+            .read_keys
+            .iter()
+            .find(|key| shared.is_written_after(key, self.start_seq))
+        {
+            return Err(Error::Conflict(format!(
+                "{} was written to after the transaction read it",
+                key
+            )));
+        }
+        for range in &self.scanned_ranges {
+            if let Some((key, _)) = shared
+                .write_seqs
+                .range::<str, _>(range.as_bounds())
+                .find(|(_, &write_seq)| write_seq > self.start_seq)
+            {
+                return Err(Error::Conflict(format!(
+                    "{} was written to after the transaction scanned a range holding it",
+                    key
+                )));
+            }
+        }
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for (key, status) in &self.writes {
+            batch.set_status(key, status.clone());
+        }
+        shared.write(&batch)
+    }
+}
+
+impl<T: KVDb> Drop for Transaction<T> {
+    fn drop(&mut self) {
+        if !self.is_done {
+            if let Ok(mut shared) = self.locked_shared.lock() {
+                shared.end_transaction(self.start_seq);
+            }
+        }
+    }
+}
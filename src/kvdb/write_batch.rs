@@ -0,0 +1,35 @@
+use super::KeyStatus;
+
+// Sets and deletes that are written as one unit: a DB logs the whole batch as a single record, so
+// after a crash either all of it is there or none of it is. Entries apply in the order they were
+// added, a later one for the same key wins.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    entries: Vec<(String, KeyStatus<String>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch {
+            entries: Vec::new(),
+        }
+    }
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.set_status(key, KeyStatus::Present(value.to_owned()));
+    }
+    pub fn delete(&mut self, key: &str) {
+        self.set_status(key, KeyStatus::Deleted);
+    }
+    pub fn set_status(&mut self, key: &str, status: KeyStatus<String>) {
+        self.entries.push((key.to_owned(), status));
+    }
+    pub fn entries(&self) -> &[(String, KeyStatus<String>)] {
+        &self.entries
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
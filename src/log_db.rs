@@ -1,9 +1,13 @@
 use crate::error::DbResult;
-use crate::kv_file::KVFile;
-use crate::kvdb::{KVDb, KeyStatus};
+use crate::in_memory_db::InMemoryDb;
+use crate::kv_file::{KVFile, RecoveryPolicy};
+use crate::kvdb::{
+    write_batch::WriteBatch, KVDb, KeyRange, KeyStatus, ScanDirection, ScanIterator,
+};
 
 pub struct LogDb {
     file: KVFile,
+    last_seq: u64,
 }
 
 impl KVDb for LogDb {
@@ -11,12 +15,27 @@ impl KVDb for LogDb {
         "Log DB".to_string()
     }
     fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
-        self.file
-            .append_line(key, &KeyStatus::Present(value.to_owned()))
-            .and(Ok(()))
+        self.file.append_line(
+            key,
+            &KeyStatus::Present(value.to_owned()),
+            self.last_seq + 1,
+        )?;
+        self.last_seq += 1;
+        Ok(())
     }
     fn delete(&mut self, key: &str) -> DbResult<()> {
-        self.file.append_line(key, &KeyStatus::Deleted).and(Ok(()))
+        self.file
+            .append_line(key, &KeyStatus::Deleted, self.last_seq + 1)?;
+        self.last_seq += 1;
+        Ok(())
+    }
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        self.file.append_batch(batch, self.last_seq + 1)?;
+        self.last_seq += batch.len() as u64;
+        Ok(())
+    }
+    fn last_seq(&self) -> u64 {
+        self.last_seq
     }
     fn get(&mut self, key: &str) -> DbResult<Option<String>> {
         let mut value = None;
@@ -28,12 +47,38 @@ impl KVDb for LogDb {
         }
         Ok(value)
     }
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>> {
+        // no index to go through, so the whole log is replayed
+        let mut latest = InMemoryDb::new_sorted();
+        for line_result in self.file.iter()? {
+            let line = line_result?;
+            if range.contains(&line.key) {
+                match line.status {
+                    KeyStatus::Present(value) => latest.set(&line.key, &value),
+                    KeyStatus::Deleted => latest.delete(&line.key),
+                }
+            }
+        }
+        let entries: Vec<(String, String)> = latest
+            .range(&range, direction)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
 }
 
 impl LogDb {
-    pub fn new(dir_path: &str, file_name: &str) -> DbResult<LogDb> {
-        Ok(LogDb {
-            file: KVFile::new(dir_path, file_name)?,
-        })
+    pub fn new(
+        dir_path: &str,
+        file_name: &str,
+        recovery_policy: RecoveryPolicy,
+    ) -> DbResult<LogDb> {
+        let mut file = KVFile::new(dir_path, file_name)?;
+        let mut last_seq = 0;
+        file.recover(recovery_policy, &mut |line| {
+            last_seq = last_seq.max(line.seq);
+            Ok(())
+        })?;
+        Ok(LogDb { file, last_seq })
     }
 }
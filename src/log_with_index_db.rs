@@ -1,50 +1,407 @@
+use std::{
+    panic::resume_unwind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    thread::{spawn, JoinHandle},
+};
+
 use crate::error::DbResult;
 use crate::in_memory_db::InMemoryDb;
-use crate::kv_file::{KVFile, KVLine};
-use crate::kvdb::{KVDb, KeyStatus};
+use crate::kv_file::{encode_record, KVFile, KVLine, RecoveryPolicy};
+use crate::kvdb::{
+    shared_db::ConcurrentKVDb, write_batch::WriteBatch, KVDb, KeyRange, KeyStatus, ScanDirection,
+    ScanIterator,
+};
+use crate::tmp_file_names::TMP_COMPACTION_FILE_NAME;
+use crate::utils::{is_thread_running, sync_dir};
+
+// a compaction only starts once the log has at least this many records, so that a small log
+// doesn't get rewritten over and over
+const MIN_RECORDS_TO_COMPACT: u64 = 1000;
+// compacted records are written out in chunks of about this many bytes
+const COMPACTION_WRITE_SIZE: usize = 1 << 20;
 
 pub struct LogWithIndexDb {
+    // compact once this fraction of the records in the log is garbage
+    garbage_ratio_threshold: f32,
+    // writes append to the log one at a time under this lock, and only take the log's lock to
+    // update the index, so reads never wait for an append or a compaction
+    locked_writer: Mutex<LogWriter>,
+    locked_log: RwLock<Log>,
+    last_seq: AtomicU64,
+}
+
+// The log as reads see it, its records are only reachable once they're in the index.
+struct Log {
     file: KVFile,
     index: InMemoryDb<u64>,
 }
 
+struct LogWriter {
+    // a handle of its own on the log, the appends go through it
+    file: KVFile,
+    num_records: u64,
+    // records that were overwritten or deleted, along with the tombstones themselves
+    num_garbage_records: u64,
+    // offset of the record of the last write
+    last_offset: u64,
+    compaction_thread_join_handle: Option<JoinHandle<DbResult<Compaction>>>,
+}
+
+// A compacted log written in the background, which is only missing the records written to the log
+// after the compaction started.
+struct Compaction {
+    file: KVFile,
+    index: InMemoryDb<u64>,
+    // the size of the log when the compaction started, the records after it are the ones missing
+    log_size: u64,
+    num_records: u64,
+    num_garbage_records: u64,
+    last_offset: u64,
+}
+
 impl KVDb for LogWithIndexDb {
     fn description(&self) -> String {
-        "Log with index DB".to_string()
+        format!(
+            "Log with index DB with garbage ratio threshold of {}",
+            self.garbage_ratio_threshold
+        )
     }
     fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
-        self.file
-            .append_line(key, &KeyStatus::Present(value.to_string()))
-            .and_then(|offset| Ok(self.index.set(key, &offset)))
+        self.set_shared(key, value)
     }
     fn delete(&mut self, key: &str) -> DbResult<()> {
-        self.file
-            .append_line(key, &KeyStatus::Deleted)
-            .and_then(|_| Ok(self.index.delete(key)))
+        self.delete_shared(key)
+    }
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        self.write_shared(batch)
+    }
+    fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Acquire)
     }
     fn get(&mut self, key: &str) -> DbResult<Option<String>> {
-        match self.index.get(key) {
-            Some(offset) => self.file.read_at_offset(offset),
+        self.get_shared(key)
+    }
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>> {
+        self.scan_shared(range, direction)
+    }
+    // compacts the log as it is now, waiting for a compaction that's already running to be done
+    // first since it started before the last writes
+    fn compact(&mut self) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        self.finish_compaction(&mut writer)?;
+        self.start_compaction(&mut writer)?;
+        self.finish_compaction(&mut writer)
+    }
+}
+
+impl Drop for LogWithIndexDb {
+    fn drop(&mut self) {
+        // the compacted log is left behind and deleted on the next open
+        if let Ok(writer) = self.locked_writer.get_mut() {
+            if let Some(handle) = writer.compaction_thread_join_handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl ConcurrentKVDb for LogWithIndexDb {
+    fn set_shared(&self, key: &str, value: &str) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        let seq = self.last_seq.load(Ordering::Acquire) + 1;
+        let offset = writer
+            .file
+            .append_line(key, &KeyStatus::Present(value.to_string()), seq)?;
+        {
+            let mut log = self.locked_log.write()?;
+            writer.count_record(&log.index, key, false);
+            log.index.set(key, &offset);
+        }
+        self.last_seq.store(seq, Ordering::Release);
+        writer.last_offset = offset;
+        self.maybe_compact(&mut writer)
+    }
+    fn delete_shared(&self, key: &str) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        let seq = self.last_seq.load(Ordering::Acquire) + 1;
+        let offset = writer.file.append_line(key, &KeyStatus::Deleted, seq)?;
+        {
+            let mut log = self.locked_log.write()?;
+            writer.count_record(&log.index, key, true);
+            log.index.delete(key);
+        }
+        self.last_seq.store(seq, Ordering::Release);
+        writer.last_offset = offset;
+        self.maybe_compact(&mut writer)
+    }
+    // the whole batch goes into the index under one lock, so reads see none of it or all of it
+    fn write_shared(&self, batch: &WriteBatch) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        let first_seq = self.last_seq.load(Ordering::Acquire) + 1;
+        let offset = writer.file.append_batch(batch, first_seq)?;
+        {
+            let mut log = self.locked_log.write()?;
+            for (key, status) in batch.entries() {
+                writer.count_record(&log.index, key, matches!(status, KeyStatus::Deleted));
+                match status {
+                    KeyStatus::Present(_) => log.index.set(key, &offset),
+                    KeyStatus::Deleted => log.index.delete(key),
+                }
+            }
+        }
+        self.last_seq
+            .store(first_seq + batch.len() as u64 - 1, Ordering::Release);
+        writer.last_offset = offset;
+        self.maybe_compact(&mut writer)
+    }
+    fn get_shared(&self, key: &str) -> DbResult<Option<String>> {
+        let log = self.locked_log.read()?;
+        match log.index.get(key) {
+            Some(offset) => Ok(log
+                .file
+                .read_at_offset(offset, key)?
+                .and_then(|line| line.status.into())),
             None => Ok(None),
         }
     }
+    fn scan_shared(
+        &self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>> {
+        let log = self.locked_log.read()?;
+        // the index is hashed, so the keys in the range are picked out of all of them and sorted
+        let entries: Vec<(String, u64)> = log
+            .index
+            .range(&range, direction)
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect();
+        // the scan shares the log's handle, so it keeps reading the same log after a compaction
+        // swaps in a new one
+        let file = KVFile::share(&log.file)?;
+        drop(log);
+        Ok(Box::new(entries.into_iter().filter_map(
+            move |(key, offset)| {
+                match file.read_at_offset(offset, &key) {
+                    Ok(line) => line
+                        .and_then(|line| line.status.into())
+                        .map(|value| Ok((key, value))),
+                    Err(e) => Some(Err(e)),
+                }
+            },
+        )))
+    }
 }
 
 impl LogWithIndexDb {
-    pub fn new(dir_path: &str, file_name: &str) -> DbResult<LogWithIndexDb> {
+    pub fn new(
+        dir_path: &str,
+        file_name: &str,
+        garbage_ratio_threshold: f32,
+        recovery_policy: RecoveryPolicy,
+    ) -> DbResult<LogWithIndexDb> {
+        // a compaction that didn't get to swap its file in is simply redone later
+        KVFile::new(dir_path, TMP_COMPACTION_FILE_NAME)?.delete()?;
         let mut index = InMemoryDb::new();
+        let (mut num_records, mut num_garbage_records) = (0, 0);
+        let (mut last_seq, mut last_offset) = (0, 0);
         let mut file = KVFile::new(dir_path, file_name)?;
-        for line_result in file.iter()? {
+        file.recover(recovery_policy, &mut |line| {
+            let KVLine {
+                key,
+                status,
+                seq,
+                offset,
+            } = line;
+            num_records += 1;
+            if seq > last_seq {
+                (last_seq, last_offset) = (seq, offset);
+            }
+            if index.get(&key).is_some() {
+                num_garbage_records += 1;
+            }
+            match status {
+                KeyStatus::Present(_) => index.set(&key, &offset),
+                KeyStatus::Deleted => {
+                    num_garbage_records += 1;
+                    index.delete(&key)
+                }
+            };
+            Ok(())
+        })?;
+        Ok(LogWithIndexDb {
+            garbage_ratio_threshold,
+            locked_writer: Mutex::new(LogWriter {
+                file: KVFile::share(&file)?,
+                num_records,
+                num_garbage_records,
+                last_offset,
+                compaction_thread_join_handle: None,
+            }),
+            locked_log: RwLock::new(Log { file, index }),
+            last_seq: AtomicU64::new(last_seq),
+        })
+    }
+    fn maybe_compact(&self, writer: &mut LogWriter) -> DbResult<()> {
+        if is_thread_running(&writer.compaction_thread_join_handle) {
+            return Ok(());
+        }
+        if writer.compaction_thread_join_handle.is_some() {
+            return self.finish_compaction(writer);
+        }
+        if writer.num_records >= MIN_RECORDS_TO_COMPACT
+            && writer.num_garbage_records as f32
+                >= self.garbage_ratio_threshold * writer.num_records as f32
+        {
+            self.start_compaction(writer)?;
+        }
+        Ok(())
+    }
+    // rewrites the live records as of now into a new log in the background, writes go on to the
+    // old log in the meantime
+    fn start_compaction(&self, writer: &mut LogWriter) -> DbResult<()> {
+        let file = KVFile::share(&writer.file)?;
+        let entries: Vec<(String, u64)> = self
+            .locked_log
+            .read()?
+            .index
+            .range(&KeyRange::new(..), ScanDirection::Forward)
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect();
+        let (log_size, last_seq, last_offset) = (
+            writer.file.size()?,
+            self.last_seq.load(Ordering::Acquire),
+            writer.last_offset,
+        );
+        writer.compaction_thread_join_handle = Some(spawn(move || {
+            Self::compact_log(file, entries, log_size, last_seq, last_offset)
+        }));
+        Ok(())
+    }
+    // waits for the compaction in the background if there is one, copies over the records written
+    // to the log since it started and swaps the compacted log in for the old one; the rename is
+    // atomic and synced to the directory, so a crash leaves either the old log or the new one
+    fn finish_compaction(&self, writer: &mut LogWriter) -> DbResult<()> {
+        let Some(handle) = writer.compaction_thread_join_handle.take() else {
+            return Ok(());
+        };
+        let mut compaction = match handle.join() {
+            Ok(result) => result?,
+            Err(e) => resume_unwind(e),
+        };
+        let log_size = writer.file.size()?;
+        let tail_offset = compaction.file.size()?;
+        let tail = writer
+            .file
+            .read_bytes(compaction.log_size, log_size - compaction.log_size)?;
+        compaction.file.append_bytes(&tail)?;
+        compaction.file.sync()?;
+        compaction.file.rename(&writer.file.file_name)?;
+        sync_dir(&writer.file.dir_path)?;
+
+        writer.file = KVFile::share(&compaction.file)?;
+        writer.num_records = compaction.num_records;
+        writer.num_garbage_records = compaction.num_garbage_records;
+        writer.last_offset = compaction.last_offset;
+        // the records copied over are applied again, at their offsets in the compacted log, before
+        // reads are switched over to it
+        let mut index = compaction.index;
+        for line_result in compaction.file.iter_from_offset(tail_offset)? {
             let KVLine {
                 key,
                 status,
                 offset,
+                ..
             } = line_result?;
+            writer.count_record(&index, &key, matches!(status, KeyStatus::Deleted));
             match status {
                 KeyStatus::Present(_) => index.set(&key, &offset),
                 KeyStatus::Deleted => index.delete(&key),
+            }
+            writer.last_offset = offset;
+        }
+        *self.locked_log.write()? = Log {
+            file: compaction.file,
+            index,
+        };
+        Ok(())
+    }
+    // writes the live records out to a new log, they keep their sequence numbers, and if the last
+    // write was a delete its tombstone is kept as well, so that its number isn't handed out again
+    // after a restart
+    fn compact_log(
+        file: KVFile,
+        entries: Vec<(String, u64)>,
+        log_size: u64,
+        last_seq: u64,
+        last_offset: u64,
+    ) -> DbResult<Compaction> {
+        let mut compact_kvfile = KVFile::new(&file.dir_path, TMP_COMPACTION_FILE_NAME)?;
+        // a previous compaction might have been interrupted
+        compact_kvfile.delete()?;
+        let mut compact_index = InMemoryDb::new();
+        let mut buf = vec![];
+        let mut buf_offset = 0;
+        let mut compact_last_seq = 0;
+        let mut compact_last_offset = 0;
+        for (key, offset) in entries {
+            let Some(line) = file.read_at_offset(offset, &key)? else {
+                continue;
             };
+            if line.seq > compact_last_seq {
+                compact_last_seq = line.seq;
+                compact_last_offset = buf_offset + buf.len() as u64;
+            }
+            compact_index.set(&key, &(buf_offset + buf.len() as u64));
+            encode_record(&mut buf, &key, &line.status, line.seq)?;
+            if buf.len() >= COMPACTION_WRITE_SIZE {
+                compact_kvfile.append_bytes(&buf)?;
+                buf_offset += buf.len() as u64;
+                buf.clear();
+            }
+        }
+        let mut num_garbage_records = 0;
+        if compact_last_seq < last_seq {
+            // the last write was a delete, its tombstone is the last entry of the last record
+            let mut last_line = None;
+            for line_result in file.iter_from_offset(last_offset)? {
+                let line = line_result?;
+                if line.offset != last_offset {
+                    break;
+                }
+                last_line = Some(line);
+            }
+            if let Some(line) = last_line.filter(|line| line.seq == last_seq) {
+                compact_last_offset = buf_offset + buf.len() as u64;
+                encode_record(&mut buf, &line.key, &line.status, line.seq)?;
+                num_garbage_records += 1;
+            }
+        }
+        compact_kvfile.append_bytes(&buf)?;
+
+        Ok(Compaction {
+            file: compact_kvfile,
+            num_records: compact_index.keys().len() as u64 + num_garbage_records,
+            index: compact_index,
+            log_size,
+            num_garbage_records,
+            last_offset: compact_last_offset,
+        })
+    }
+}
+
+impl LogWriter {
+    // counts a record about to be applied to the index
+    fn count_record(&mut self, index: &InMemoryDb<u64>, key: &str, is_delete: bool) {
+        self.num_records += 1;
+        if index.get(key).is_some() {
+            self.num_garbage_records += 1;
+        }
+        if is_delete {
+            self.num_garbage_records += 1;
         }
-        Ok(LogWithIndexDb { file, index })
     }
 }
@@ -1,13 +1,46 @@
-use std::{collections::VecDeque, fs};
+use std::{collections::VecDeque, fs, sync::Arc, time::Duration};
 
+use btree_db::BTreeDb;
+use error::DbResult;
 use in_memory_db::InMemoryDb;
-use kvdb::KVDb;
+use kv_file::RecoveryPolicy;
+use kvdb::{
+    locking_transaction::LockingDb, shared_db::SharedDb, transaction::TransactionalDb, KVDb,
+};
 use log_db::LogDb;
 use log_with_index_db::LogWithIndexDb;
+use segmented_files_db::{
+    block_cache::BlockCache,
+    compaction::{CompactionStrategy, FifoCompaction, MergeAllCompaction, SizeTieredCompaction},
+    leveled::LeveledCompaction,
+};
 use segmented_logs_with_indices_db::SegmentedLogsWithIndicesDb;
-use sstable::SSTable;
-use test::{correctness_test::CorrectnessTest, latency_test::LatencyTest, Test};
+use sstable::{
+    write_buffer::{WriteBufferBudget, WriteBufferPolicy},
+    SSTable,
+};
+use test::{
+    block_cache_test::BlockCacheTest,
+    concurrency_test::ConcurrencyTest,
+    correctness_test::CorrectnessTest,
+    crash_test::{CrashTest, OpenDb},
+    expiry_test::ExpiryTest,
+    latency_test::LatencyTest,
+    locking_transaction_test::LockingTransactionTest,
+    payload_test::PayloadTest,
+    recovery_test::RecoveryTest,
+    scan_test::ScanTest,
+    snapshot_test::SnapshotTest,
+    transaction_test::TransactionTest,
+    write_batch_test::WriteBatchTest,
+    write_buffer_budget_test::WriteBufferBudgetTest,
+    write_stall_test::WriteStallTest,
+    Test,
+};
 
+mod btree_db;
+mod crc;
+mod encoding;
 mod error;
 mod in_memory_db;
 mod kv_file;
@@ -21,17 +54,57 @@ mod test;
 mod tmp_file_names;
 mod utils;
 
+// small enough that the test data spreads over a few levels
+const LEVELED_COMPACTION: LeveledCompaction = LeveledCompaction {
+    level0_threshold: 4,
+    base_level_size: 64 << 10,
+    level_size_multiplier: 10,
+    target_file_size: 16 << 10,
+};
+
+const SIZE_TIERED_COMPACTION: SizeTieredCompaction = SizeTieredCompaction {
+    min_threshold: 4,
+    max_threshold: 32,
+    size_ratio: 1.5,
+};
+
+const BLOCK_CACHE_CAPACITY: usize = 8 << 20;
+
+const WRITE_BUFFER_POLICY: WriteBufferPolicy = WriteBufferPolicy {
+    memtable_size_threshold: 100 << 10,
+    max_immutable_memtables: 4,
+    slowdown_threshold: 3,
+    slowdown_delay: Duration::from_millis(1),
+    budget: None,
+};
+
 fn prepare_dbs(include_log_db: bool, include_all_variants: bool) -> VecDeque<Box<dyn KVDb>> {
     let _ = fs::remove_dir_all("./db_files/");
 
+    // shared by every segmented DB in the suite
+    let block_cache = Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY));
     let mut dbs: VecDeque<Box<dyn KVDb>> = VecDeque::new();
     dbs.push_back(Box::new(InMemoryDb::new()));
+    dbs.push_back(Box::new(InMemoryDb::new_sorted()));
     if include_log_db {
         // too slow
-        dbs.push_back(Box::new(LogDb::new("db_files/log_db/", "log.txt").unwrap()));
+        dbs.push_back(Box::new(
+            LogDb::new(
+                "db_files/log_db/",
+                "log.txt",
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
     }
     dbs.push_back(Box::new(
-        LogWithIndexDb::new("db_files/log_with_index_db/", "log.txt").unwrap(),
+        LogWithIndexDb::new(
+            "db_files/log_with_index_db/",
+            "log.txt",
+            0.5,
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
     ));
     if include_all_variants {
         for merge_threshold in (1..10).step_by(4) {
@@ -43,41 +116,235 @@ fn prepare_dbs(include_log_db: bool, include_all_variants: bool) -> VecDeque<Box
                             merge_threshold, size_threshold
                         ),
                         size_threshold,
-                        merge_threshold,
+                        Box::new(MergeAllCompaction {
+                            merging_threshold: merge_threshold,
+                        }),
+                        Arc::clone(&block_cache),
+                        RecoveryPolicy::TruncateTornTail,
                     )
                     .unwrap(),
                 ));
             }
         }
         for merging_threshold in (2..10).step_by(4) {
-            for sparsity in (100..=1000).step_by(400) {
-                for memtable_size_threshold in (1000..=10000).step_by(4000) {
+            for block_size in (100..=1000).step_by(400) {
+                for memtable_size_threshold in (100 << 10..=1 << 20).step_by(400 << 10) {
                     dbs.push_back(Box::new(
                         SSTable::new(
                             &format!(
                                 "db_files/sstable_{}_{}_{}/",
-                                merging_threshold, sparsity, memtable_size_threshold
+                                merging_threshold, block_size, memtable_size_threshold
                             ),
-                            merging_threshold,
-                            sparsity,
-                            memtable_size_threshold,
+                            Box::new(MergeAllCompaction { merging_threshold }),
+                            block_size,
+                            10,
+                            Arc::clone(&block_cache),
+                            WriteBufferPolicy {
+                                memtable_size_threshold,
+                                ..WRITE_BUFFER_POLICY
+                            },
+                            RecoveryPolicy::TruncateTornTail,
                         )
                         .unwrap(),
                     ));
                 }
             }
         }
+        for memtable_size_threshold in (100 << 10..=1 << 20).step_by(400 << 10) {
+            dbs.push_back(Box::new(
+                SSTable::new(
+                    &format!("db_files/leveled_sstable_{}/", memtable_size_threshold),
+                    Box::new(LEVELED_COMPACTION),
+                    500,
+                    10,
+                    Arc::clone(&block_cache),
+                    WriteBufferPolicy {
+                        memtable_size_threshold,
+                        ..WRITE_BUFFER_POLICY
+                    },
+                    RecoveryPolicy::TruncateTornTail,
+                )
+                .unwrap(),
+            ));
+        }
+        for memtable_size_threshold in (100 << 10..=1 << 20).step_by(400 << 10) {
+            dbs.push_back(Box::new(
+                SSTable::new(
+                    &format!("db_files/size_tiered_sstable_{}/", memtable_size_threshold),
+                    Box::new(SIZE_TIERED_COMPACTION),
+                    500,
+                    10,
+                    Arc::clone(&block_cache),
+                    WriteBufferPolicy {
+                        memtable_size_threshold,
+                        ..WRITE_BUFFER_POLICY
+                    },
+                    RecoveryPolicy::TruncateTornTail,
+                )
+                .unwrap(),
+            ));
+        }
+        dbs.push_back(Box::new(
+            SegmentedLogsWithIndicesDb::new(
+                "db_files/size_tiered_segmented_logs_with_indices_db/",
+                20000,
+                Box::new(SIZE_TIERED_COMPACTION),
+                Arc::clone(&block_cache),
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+        for page_size in [1024, 4096] {
+            dbs.push_back(Box::new(
+                BTreeDb::new(
+                    &format!("db_files/btree_db_{}/", page_size),
+                    "btree.txt",
+                    page_size,
+                    256,
+                    1 << 20,
+                    RecoveryPolicy::TruncateTornTail,
+                )
+                .unwrap(),
+            ));
+        }
     } else {
         dbs.push_back(Box::new(
             SegmentedLogsWithIndicesDb::new(
                 "db_files/segmented_logs_with_indices_db/",
                 1000,
+                Box::new(MergeAllCompaction {
+                    merging_threshold: 10000,
+                }),
+                Arc::clone(&block_cache),
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+        dbs.push_back(Box::new(
+            SSTable::new(
+                "db_files/sstable/",
+                Box::new(MergeAllCompaction {
+                    merging_threshold: 5,
+                }),
+                500,
+                10,
+                Arc::clone(&block_cache),
+                WRITE_BUFFER_POLICY,
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+        dbs.push_back(Box::new(
+            SSTable::new(
+                "db_files/leveled_sstable/",
+                Box::new(LEVELED_COMPACTION),
+                500,
+                10,
+                Arc::clone(&block_cache),
+                WRITE_BUFFER_POLICY,
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+        dbs.push_back(Box::new(
+            SSTable::new(
+                "db_files/size_tiered_sstable/",
+                Box::new(SIZE_TIERED_COMPACTION),
+                500,
+                10,
+                Arc::clone(&block_cache),
+                WRITE_BUFFER_POLICY,
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+        dbs.push_back(Box::new(
+            BTreeDb::new(
+                "db_files/btree_db/",
+                "btree.txt",
+                4096,
+                256,
+                1 << 20,
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+    }
+    dbs
+}
+
+// SSTables with memtables small enough to be flushed and merged many times over while a snapshot
+// lives, with each of the strategies that merge
+fn prepare_snapshot_dbs() -> VecDeque<Box<dyn KVDb>> {
+    let _ = fs::remove_dir_all("./db_files/");
+
+    let block_cache = Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY));
+    let compaction_strategies: [(&str, Box<dyn CompactionStrategy>); 3] = [
+        (
+            "sstable",
+            Box::new(MergeAllCompaction {
+                merging_threshold: 5,
+            }),
+        ),
+        ("leveled_sstable", Box::new(LEVELED_COMPACTION)),
+        ("size_tiered_sstable", Box::new(SIZE_TIERED_COMPACTION)),
+    ];
+    let mut dbs: VecDeque<Box<dyn KVDb>> = VecDeque::new();
+    for (db_name, compaction_strategy) in compaction_strategies {
+        dbs.push_back(Box::new(
+            SSTable::new(
+                &format!("db_files/snapshot_{}/", db_name),
+                compaction_strategy,
+                500,
+                10,
+                Arc::clone(&block_cache),
+                WriteBufferPolicy {
+                    memtable_size_threshold: 10 << 10,
+                    ..WRITE_BUFFER_POLICY
+                },
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
+        ));
+    }
+    dbs
+}
+
+// DBs that drop their oldest data, once they hold more than they're allowed to or once it's been
+// around for longer than a second
+fn prepare_expiring_dbs() -> VecDeque<Box<dyn KVDb>> {
+    let _ = fs::remove_dir_all("./db_files/");
+
+    let block_cache = Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY));
+    let mut dbs: VecDeque<Box<dyn KVDb>> = VecDeque::new();
+    for ttl in [None, Some(Duration::from_secs(1))] {
+        dbs.push_back(Box::new(
+            SegmentedLogsWithIndicesDb::new(
+                "db_files/fifo_segmented_logs_with_indices_db/",
                 10000,
+                Box::new(FifoCompaction {
+                    max_total_size: 100 << 10,
+                    ttl,
+                }),
+                Arc::clone(&block_cache),
+                RecoveryPolicy::TruncateTornTail,
             )
             .unwrap(),
         ));
         dbs.push_back(Box::new(
-            SSTable::new("db_files/sstable/", 5, 500, 1000).unwrap(),
+            SSTable::new(
+                "db_files/fifo_sstable/",
+                Box::new(FifoCompaction {
+                    max_total_size: 100 << 10,
+                    ttl,
+                }),
+                500,
+                10,
+                Arc::clone(&block_cache),
+                WRITE_BUFFER_POLICY,
+                RecoveryPolicy::TruncateTornTail,
+            )
+            .unwrap(),
         ));
     }
     dbs
@@ -93,7 +360,114 @@ fn run_test_suite<T: Test>(test_suite: T, mut dbs: VecDeque<Box<dyn KVDb>>) {
     }
 }
 
+// small pages, cache and WAL, so that the crashes land in splits, evictions and checkpoints
+fn open_crash_test_btree_db(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(BTreeDb::new(
+        dir_path,
+        "btree.txt",
+        256,
+        16,
+        1 << 16,
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+// compacts often, so that the crashes land in compactions
+fn open_crash_test_log_with_index_db(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(LogWithIndexDb::new(
+        dir_path,
+        "log.txt",
+        0.5,
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+// small memtables and segments, so that the crashes land in flushes and merges
+fn open_crash_test_sstable(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(SSTable::new(
+        dir_path,
+        Box::new(MergeAllCompaction {
+            merging_threshold: 3,
+        }),
+        256,
+        10,
+        Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+        WriteBufferPolicy {
+            memtable_size_threshold: 10 << 10,
+            ..WRITE_BUFFER_POLICY
+        },
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+fn open_crash_test_leveled_sstable(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(SSTable::new(
+        dir_path,
+        Box::new(LeveledCompaction {
+            level0_threshold: 2,
+            base_level_size: 8 << 10,
+            level_size_multiplier: 4,
+            target_file_size: 4 << 10,
+        }),
+        256,
+        10,
+        Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+        WriteBufferPolicy {
+            memtable_size_threshold: 10 << 10,
+            ..WRITE_BUFFER_POLICY
+        },
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+fn open_crash_test_segmented_logs_with_indices_db(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(SegmentedLogsWithIndicesDb::new(
+        dir_path,
+        2000,
+        Box::new(MergeAllCompaction {
+            merging_threshold: 3,
+        }),
+        Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+// pages big enough for the longest payload
+fn open_payload_test_btree_db(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(BTreeDb::new(
+        dir_path,
+        "btree.txt",
+        4096,
+        16,
+        1 << 16,
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+fn open_payload_test_log_db(dir_path: &str) -> DbResult<Box<dyn KVDb>> {
+    Ok(Box::new(LogDb::new(
+        dir_path,
+        "log.txt",
+        RecoveryPolicy::TruncateTornTail,
+    )?))
+}
+
+const CRASH_TEST_DBS: [(&str, OpenDb); 5] = [
+    ("btree_db", open_crash_test_btree_db),
+    ("log_with_index_db", open_crash_test_log_with_index_db),
+    ("sstable", open_crash_test_sstable),
+    ("leveled_sstable", open_crash_test_leveled_sstable),
+    (
+        "segmented_logs_with_indices_db",
+        open_crash_test_segmented_logs_with_indices_db,
+    ),
+];
+
 fn main() {
+    if CrashTest::run_child_if_spawned(&CRASH_TEST_DBS) {
+        return;
+    }
+
     /* CORRECTNESS TESTS */
     for _ in 0..5 {
         let correctness_test_suite = CorrectnessTest::new(20000, 100000, 0.5, 0.8, 0.9, false);
@@ -101,6 +475,327 @@ fn main() {
         run_test_suite(correctness_test_suite, dbs);
     }
 
+    /* SCAN TESTS */
+    let scan_test_suite = ScanTest::new(20000, 100000, 0.8, false);
+    let dbs = prepare_dbs(false, false);
+    run_test_suite(scan_test_suite, dbs);
+
+    /* WRITE BATCH TESTS */
+    let write_batch_test_suite = WriteBatchTest::new(200, 100000, 0.5, 0.8, 50);
+    let dbs = prepare_dbs(false, false);
+    run_test_suite(write_batch_test_suite, dbs);
+
+    /* PAYLOAD TESTS */
+    let payload_test_suite = PayloadTest::new(2000);
+    print!("\n\n");
+    payload_test_suite.run_with_kv_file("db_files/payload_kv_file/");
+    print!("\n\n");
+    // the DBs of the crash tests, which flush and merge often, along with the log DB
+    for (db_name, open_db) in CRASH_TEST_DBS[1..].iter().copied().chain([
+        ("btree_db", open_payload_test_btree_db as OpenDb),
+        ("log_db", open_payload_test_log_db),
+    ]) {
+        payload_test_suite.run(&format!("db_files/payload_{}/", db_name), open_db);
+        print!("\n\n");
+    }
+
+    /* RECOVERY TESTS */
+    let recovery_test_suite = RecoveryTest::new(1000);
+    print!("\n\n");
+    recovery_test_suite.run("db_files/recovery/");
+    print!("\n\n");
+    recovery_test_suite.run_with_manifest("db_files/recovery_manifest/");
+    print!("\n\n");
+    recovery_test_suite.run_with_wal("db_files/recovery_wal/");
+    print!("\n\n");
+    recovery_test_suite.run_with_hint_files("db_files/recovery_hint_files/");
+    print!("\n\n");
+    recovery_test_suite.run_with_file_numbers("db_files/recovery_file_numbers/", 3);
+    print!("\n\n");
+    recovery_test_suite.run_with_failing_merge("db_files/recovery_failing_merge/");
+    print!("\n\n");
+
+    /* SNAPSHOT TESTS */
+    let snapshot_test_suite = SnapshotTest::new(2000, 100000, 0.2, 0.8, 20, 4);
+    let dbs = prepare_snapshot_dbs();
+    run_test_suite(snapshot_test_suite, dbs);
+
+    /* EXPIRY TESTS */
+    let expiry_test_suite = ExpiryTest::new(20000, 100000, 0.5, 0.8);
+    let dbs = prepare_expiring_dbs();
+    run_test_suite(expiry_test_suite, dbs);
+
+    /* WRITE STALL TESTS */
+    let write_stall_test_suite = WriteStallTest::new(20000, 100000, 0.8);
+    // memtables far too small for the flushes to keep up, writes either only stall or are slowed
+    // down from the first immutable memtable
+    for (max_immutable_memtables, slowdown_threshold) in [(1, 2), (2, 1)] {
+        let dir_path = format!(
+            "db_files/write_stall_sstable_{}_{}/",
+            max_immutable_memtables, slowdown_threshold
+        );
+        let _ = fs::remove_dir_all(&dir_path);
+        let mut db = SSTable::new(
+            &dir_path,
+            Box::new(MergeAllCompaction {
+                merging_threshold: 5,
+            }),
+            500,
+            10,
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            WriteBufferPolicy {
+                memtable_size_threshold: 10 << 10,
+                max_immutable_memtables,
+                slowdown_threshold,
+                slowdown_delay: Duration::from_micros(100),
+                budget: None,
+            },
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap();
+        print!("\n\n");
+        write_stall_test_suite.run(&mut db);
+        print!("\n\n");
+    }
+    // flushes that stop once a segment file can't be created, with nothing merged in between
+    let dir_path = "db_files/write_stall_failing_flush_sstable/";
+    print!("\n\n");
+    write_stall_test_suite.run_with_failing_flush(dir_path, || {
+        SSTable::new(
+            dir_path,
+            Box::new(MergeAllCompaction {
+                merging_threshold: usize::MAX,
+            }),
+            500,
+            10,
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            WriteBufferPolicy {
+                memtable_size_threshold: 10 << 10,
+                max_immutable_memtables: 1,
+                slowdown_threshold: 2,
+                slowdown_delay: Duration::from_micros(100),
+                budget: None,
+            },
+            RecoveryPolicy::TruncateTornTail,
+        )
+    });
+    print!("\n\n");
+
+    /* WRITE BUFFER BUDGET TESTS */
+    let write_buffer_budget_test_suite = WriteBufferBudgetTest::new(20000, 100000, 0.8);
+    print!("\n\n");
+    // memtable size thresholds that the shared budget always runs out before
+    write_buffer_budget_test_suite.run(
+        "db_files/write_buffer_budget_sstables/",
+        3,
+        WriteBufferPolicy {
+            memtable_size_threshold: 1 << 30,
+            budget: Some(Arc::new(WriteBufferBudget::new(64 << 10))),
+            ..WRITE_BUFFER_POLICY
+        },
+    );
+    print!("\n\n");
+    // a long queue of immutable memtables that keeps the budget used up between flushes
+    write_buffer_budget_test_suite.run(
+        "db_files/write_buffer_budget_sstables_long_queue/",
+        3,
+        WriteBufferPolicy {
+            memtable_size_threshold: 1 << 30,
+            max_immutable_memtables: 16,
+            slowdown_threshold: 16,
+            budget: Some(Arc::new(WriteBufferBudget::new(64 << 10))),
+            ..WRITE_BUFFER_POLICY
+        },
+    );
+    print!("\n\n");
+
+    /* BLOCK CACHE TESTS */
+    let block_cache_test_suite = BlockCacheTest::new(20000, 100000, 0.5, 0.8, 0.9);
+    print!("\n\n");
+    block_cache_test_suite.run(
+        "db_files/block_cache_dbs/",
+        Arc::new(BlockCache::new(64 << 10)),
+    );
+    print!("\n\n");
+
+    /* CONCURRENCY TESTS */
+    let concurrency_test_suite = ConcurrencyTest::new(20000, 20000, 20000, 4, 4);
+    let _ = fs::remove_dir_all("db_files/concurrency/");
+    print!("\n\n");
+    concurrency_test_suite.run(SharedDb::new(
+        LogWithIndexDb::new(
+            "db_files/concurrency/log_with_index_db/",
+            "log.txt",
+            0.5,
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+    concurrency_test_suite.run(SharedDb::new(
+        SegmentedLogsWithIndicesDb::new(
+            "db_files/concurrency/segmented_logs_with_indices_db/",
+            2000,
+            Box::new(MergeAllCompaction {
+                merging_threshold: 3,
+            }),
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+    concurrency_test_suite.run(SharedDb::new(
+        SSTable::new(
+            "db_files/concurrency/sstable/",
+            Box::new(MergeAllCompaction {
+                merging_threshold: 5,
+            }),
+            500,
+            10,
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            WriteBufferPolicy {
+                memtable_size_threshold: 10 << 10,
+                ..WRITE_BUFFER_POLICY
+            },
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+    concurrency_test_suite.run_with_memtable();
+    print!("\n\n");
+    concurrency_test_suite.run_with_segment("db_files/concurrency/segment/");
+    print!("\n\n");
+
+    /* TRANSACTION TESTS */
+    let transaction_test_suite = TransactionTest::new(50, 2000, 200, 4, 2);
+    let _ = fs::remove_dir_all("db_files/transaction/");
+    print!("\n\n");
+    transaction_test_suite.run(TransactionalDb::new(InMemoryDb::new()));
+    print!("\n\n");
+    transaction_test_suite.run(TransactionalDb::new(
+        LogWithIndexDb::new(
+            "db_files/transaction/log_with_index_db/",
+            "log.txt",
+            0.5,
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+    transaction_test_suite.run(TransactionalDb::new(
+        SegmentedLogsWithIndicesDb::new(
+            "db_files/transaction/segmented_logs_with_indices_db/",
+            2000,
+            Box::new(MergeAllCompaction {
+                merging_threshold: 3,
+            }),
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+    transaction_test_suite.run(TransactionalDb::new(
+        SSTable::new(
+            "db_files/transaction/sstable/",
+            Box::new(MergeAllCompaction {
+                merging_threshold: 5,
+            }),
+            500,
+            10,
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            WriteBufferPolicy {
+                memtable_size_threshold: 10 << 10,
+                ..WRITE_BUFFER_POLICY
+            },
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+    transaction_test_suite.run(TransactionalDb::new(
+        BTreeDb::new(
+            "db_files/transaction/btree_db/",
+            "btree.txt",
+            4096,
+            64,
+            1 << 20,
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+    ));
+    print!("\n\n");
+
+    /* LOCKING TRANSACTION TESTS */
+    let locking_transaction_test_suite = LockingTransactionTest::new(50, 2000, 200, 4, 2);
+    let lock_wait_timeout = Duration::from_millis(200);
+    let _ = fs::remove_dir_all("db_files/locking_transaction/");
+    print!("\n\n");
+    locking_transaction_test_suite.run(LockingDb::new(InMemoryDb::new(), lock_wait_timeout));
+    print!("\n\n");
+    locking_transaction_test_suite.run(LockingDb::new(
+        LogWithIndexDb::new(
+            "db_files/locking_transaction/log_with_index_db/",
+            "log.txt",
+            0.5,
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+        lock_wait_timeout,
+    ));
+    print!("\n\n");
+    locking_transaction_test_suite.run(LockingDb::new(
+        SSTable::new(
+            "db_files/locking_transaction/sstable/",
+            Box::new(MergeAllCompaction {
+                merging_threshold: 5,
+            }),
+            500,
+            10,
+            Arc::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
+            WriteBufferPolicy {
+                memtable_size_threshold: 10 << 10,
+                ..WRITE_BUFFER_POLICY
+            },
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+        lock_wait_timeout,
+    ));
+    print!("\n\n");
+    locking_transaction_test_suite.run(LockingDb::new(
+        BTreeDb::new(
+            "db_files/locking_transaction/btree_db/",
+            "btree.txt",
+            4096,
+            64,
+            1 << 20,
+            RecoveryPolicy::TruncateTornTail,
+        )
+        .unwrap(),
+        lock_wait_timeout,
+    ));
+    print!("\n\n");
+
+    /* CRASH TESTS */
+    for batch_size in [1, 10] {
+        for (db_name, open_db) in CRASH_TEST_DBS {
+            let crash_test_suite = CrashTest::new(
+                &format!("db_files/crash_test_{}/", db_name),
+                2000,
+                200000,
+                0.7,
+                20,
+                batch_size,
+            );
+            print!("\n\n");
+            crash_test_suite.run(db_name, open_db);
+            print!("\n\n");
+        }
+    }
+
     // /* LATENCY TESTS */
     // let latency_test_suite = LatencyTest::new(50000, 20000, 0.5, 0.7, 0.8, false);
     // let dbs = prepare_dbs(false, true);
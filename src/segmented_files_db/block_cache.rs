@@ -0,0 +1,161 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::error::DbResult;
+
+// a block is identified by the file it was read from and its offset in it
+type BlockKey = (u64, u64);
+
+struct CachedBlock {
+    bytes: Arc<[u8]>,
+    last_used: u64,
+}
+
+struct CachedBlocks {
+    // ordered by file, so that the blocks of a file can be dropped together
+    blocks: BTreeMap<BlockKey, CachedBlock>,
+    // blocks by the time they were last used, to find the least recently used one
+    lru: BTreeMap<u64, BlockKey>,
+    // ids of the files that are open, blocks of the others are never cached
+    live_files: HashSet<u64>,
+    clock: u64,
+    size: usize,
+    stats: BlockCacheStats,
+}
+
+// Keeps the most recently read blocks of segment files in memory, up to a capacity in bytes. Each
+// segment file gets an id of its own when it's opened, and the cache can be shared by the segments
+// of one database or of several. Ids are never reused, so the blocks of a file that's reopened,
+// or that a merge deletes, can't be mistaken for another's.
+pub struct BlockCache {
+    capacity: usize,
+    next_file_id: AtomicU64,
+    locked_blocks: Mutex<CachedBlocks>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl fmt::Display for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block cache of {} bytes", self.capacity)
+    }
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            next_file_id: AtomicU64::new(0),
+            locked_blocks: Mutex::new(CachedBlocks {
+                blocks: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                live_files: HashSet::new(),
+                clock: 0,
+                size: 0,
+                stats: BlockCacheStats::default(),
+            }),
+        }
+    }
+    pub fn new_file_id(&self) -> DbResult<u64> {
+        let file_id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
+        self.locked_blocks.lock()?.live_files.insert(file_id);
+        Ok(file_id)
+    }
+    // the cached block at the offset of the file, or the one `read` returns, which is cached
+    // unless it's larger than the whole cache
+    pub fn get_or_read(
+        &self,
+        file_id: u64,
+        offset: u64,
+        read: impl FnOnce() -> DbResult<Vec<u8>>,
+    ) -> DbResult<Arc<[u8]>> {
+        let key = (file_id, offset);
+        {
+            let mut cached_blocks = self.locked_blocks.lock()?;
+            let cached_blocks = &mut *cached_blocks;
+            cached_blocks.clock += 1;
+            if let Some(block) = cached_blocks.blocks.get_mut(&key) {
+                cached_blocks.lru.remove(&block.last_used);
+                block.last_used = cached_blocks.clock;
+                cached_blocks.lru.insert(block.last_used, key);
+                cached_blocks.stats.hits += 1;
+                return Ok(Arc::clone(&block.bytes));
+            }
+            cached_blocks.stats.misses += 1;
+        }
+
+        // read without the lock, another reader may cache the same block in the meantime
+        let bytes: Arc<[u8]> = read()?.into();
+        if bytes.len() > self.capacity {
+            return Ok(bytes);
+        }
+        let mut cached_blocks = self.locked_blocks.lock()?;
+        // the file may have been erased while the block was read, its block would stay cached
+        // until it's evicted
+        if !cached_blocks.live_files.contains(&file_id) {
+            return Ok(bytes);
+        }
+        cached_blocks.remove(&key);
+        while cached_blocks.size + bytes.len() > self.capacity {
+            let Some((_, lru_key)) = cached_blocks.lru.pop_first() else {
+                break;
+            };
+            cached_blocks.remove(&lru_key);
+        }
+        cached_blocks.clock += 1;
+        let last_used = cached_blocks.clock;
+        cached_blocks.lru.insert(last_used, key);
+        cached_blocks.size += bytes.len();
+        cached_blocks.blocks.insert(
+            key,
+            CachedBlock {
+                bytes: Arc::clone(&bytes),
+                last_used,
+            },
+        );
+        Ok(bytes)
+    }
+    // drops the blocks of a file that's closed or deleted, they can never be read again
+    pub fn erase_file(&self, file_id: u64) -> DbResult<()> {
+        let mut cached_blocks = self.locked_blocks.lock()?;
+        cached_blocks.live_files.remove(&file_id);
+        let keys: Vec<BlockKey> = cached_blocks
+            .blocks
+            .range((file_id, 0)..=(file_id, u64::MAX))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            cached_blocks.remove(&key);
+        }
+        Ok(())
+    }
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    // bytes taken by the cached blocks
+    pub fn size(&self) -> DbResult<usize> {
+        Ok(self.locked_blocks.lock()?.size)
+    }
+    pub fn stats(&self) -> DbResult<BlockCacheStats> {
+        Ok(self.locked_blocks.lock()?.stats)
+    }
+}
+
+impl CachedBlocks {
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some(block) = self.blocks.remove(key) {
+            self.lru.remove(&block.last_used);
+            self.size -= block.bytes.len();
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+// what a compaction strategy gets to know about a past segment
+#[derive(Clone, Debug)]
+pub struct SegmentInfo {
+    pub level: usize,
+    pub size: u64,
+    // smallest and largest key in the segment, `None` if it isn't known
+    pub key_range: Option<(String, String)>,
+    pub last_modified: SystemTime,
+}
+
+// Segments are referred to by their position in the slice the strategy was given.
+#[derive(Debug)]
+pub enum CompactionTask {
+    // merges the segments into new ones in `output_level`, cut at about `max_output_size` bytes
+    // of keys and values, tombstones are dropped once no older segment can hold their key
+    // Merged segments must sit next to each other in age, apart from ones whose keys they don't
+    // overlap, and a merge into level 0 always makes a single segment that takes the place of the
+    // newest input.
+    Merge {
+        inputs: Vec<usize>,
+        output_level: usize,
+        max_output_size: Option<u64>,
+    },
+    // deletes the segments along with everything in them
+    Drop(Vec<usize>),
+}
+
+// Decides which past segments to compact and when. It's asked after every segment is archived,
+// and again after every compaction until it has nothing left to do.
+pub trait CompactionStrategy: fmt::Display + Send + Sync {
+    // the next compaction to run, given the past segments from the oldest to the newest
+    fn pick(&self, segments: &[SegmentInfo]) -> Option<CompactionTask>;
+}
+
+// Merges every past segment into one once there are too many of them.
+pub struct MergeAllCompaction {
+    pub merging_threshold: usize,
+}
+
+impl CompactionStrategy for MergeAllCompaction {
+    fn pick(&self, segments: &[SegmentInfo]) -> Option<CompactionTask> {
+        (segments.len() > self.merging_threshold).then(|| CompactionTask::Merge {
+            inputs: (0..segments.len()).collect(),
+            output_level: 0,
+            max_output_size: None,
+        })
+    }
+}
+
+impl fmt::Display for MergeAllCompaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "merging threshold of {} files", self.merging_threshold)
+    }
+}
+
+// Merges runs of neighbouring segments of about the same size, so that segments grow in tiers
+// and each record is only rewritten about once per tier. A run starts at a segment and takes in
+// the next older one as long as it isn't much bigger than the whole run so far, the newest
+// segments are tried first.
+pub struct SizeTieredCompaction {
+    // the fewest segments merged at once
+    pub min_threshold: usize,
+    // the most segments merged at once
+    pub max_threshold: usize,
+    // an older segment joins the run if it's at most this many times the size of the run
+    pub size_ratio: f64,
+}
+
+impl CompactionStrategy for SizeTieredCompaction {
+    fn pick(&self, segments: &[SegmentInfo]) -> Option<CompactionTask> {
+        let size = |idx: usize| segments[idx].size.max(1) as f64;
+        // merging a single segment would never end
+        let min_threshold = self.min_threshold.max(2);
+        // only level 0 is tiered, deeper levels come first and are left alone
+        let first = segments.iter().position(|segment| segment.level == 0)?;
+        for end in (first + 1..=segments.len()).rev() {
+            let mut start = end - 1;
+            let mut total = size(start);
+            while start > first
+                && end - start < self.max_threshold
+                && size(start - 1) <= total * self.size_ratio
+            {
+                start -= 1;
+                total += size(start);
+            }
+            if end - start >= min_threshold {
+                return Some(CompactionTask::Merge {
+                    inputs: (start..end).collect(),
+                    output_level: 0,
+                    max_output_size: None,
+                });
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for SizeTieredCompaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "size-tiered compaction of {} to {} files within a size ratio of {}",
+            self.min_threshold, self.max_threshold, self.size_ratio
+        )
+    }
+}
+
+// Never merges, instead drops the oldest segments once the segments take up too much space, or
+// once they haven't been written to for longer than the TTL. Meant for data that's only
+// interesting for a while, like logs or metrics.
+pub struct FifoCompaction {
+    pub max_total_size: u64,
+    pub ttl: Option<Duration>,
+}
+
+impl CompactionStrategy for FifoCompaction {
+    fn pick(&self, segments: &[SegmentInfo]) -> Option<CompactionTask> {
+        let mut total_size: u64 = segments.iter().map(|segment| segment.size).sum();
+        let mut dropped = vec![];
+        for (idx, segment) in segments.iter().enumerate() {
+            let expired = self.ttl.is_some_and(|ttl| {
+                segment
+                    .last_modified
+                    .elapsed()
+                    .is_ok_and(|elapsed| elapsed > ttl)
+            });
+            if !expired && total_size <= self.max_total_size {
+                break;
+            }
+            total_size -= segment.size;
+            dropped.push(idx);
+        }
+        (!dropped.is_empty()).then_some(CompactionTask::Drop(dropped))
+    }
+}
+
+impl fmt::Display for FifoCompaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FIFO compaction with total size limit of {} bytes",
+            self.max_total_size
+        )?;
+        if let Some(ttl) = self.ttl {
+            write!(f, " and TTL of {:?}", ttl)?;
+        }
+        Ok(())
+    }
+}
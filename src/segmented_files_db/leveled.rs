@@ -0,0 +1,103 @@
+use std::{collections::BTreeMap, fmt};
+
+use super::compaction::{CompactionStrategy, CompactionTask, SegmentInfo};
+
+// LevelDB-style leveled compaction. Archived segments land in level 0, where their key ranges can
+// overlap. Every deeper level holds segments with disjoint key ranges, and can hold
+// `level_size_multiplier` times as many bytes as the one above it. Once a level goes over its
+// budget, one of its segments is merged into the segments it overlaps in the next level (all of
+// them for level 0), so a compaction only rewrites a slice of the data instead of all of it.
+// Segments have to keep their keys sorted.
+#[derive(Clone, Copy, Debug)]
+pub struct LeveledCompaction {
+    // level 0 is compacted once it has this many segments
+    pub level0_threshold: usize,
+    // the number of bytes level 1 can hold
+    pub base_level_size: u64,
+    pub level_size_multiplier: u64,
+    // compacted segments are cut at about this many bytes of keys and values
+    pub target_file_size: u64,
+}
+
+impl fmt::Display for LeveledCompaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "leveled compaction with level 0 threshold of {} files, base level size of {} bytes, level size multiplier of {} and target file size of {} bytes",
+            self.level0_threshold, self.base_level_size, self.level_size_multiplier, self.target_file_size
+        )
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    fn pick(&self, segments: &[SegmentInfo]) -> Option<CompactionTask> {
+        let level = self.pick_level(segments)?;
+        let mut inputs: Vec<usize> = match level {
+            0 => (0..segments.len())
+                .filter(|&idx| segments[idx].level == 0)
+                .collect(),
+            // the segment that went the longest without being rewritten, which makes compactions
+            // cycle through the key space
+            _ => (0..segments.len())
+                .find(|&idx| segments[idx].level == level)
+                .into_iter()
+                .collect(),
+        };
+        let output_level = level + 1;
+        let key_range = inputs
+            .iter()
+            .filter_map(|&idx| segments[idx].key_range.as_ref())
+            .fold(
+                None,
+                |range: Option<(&str, &str)>, (first_key, last_key)| {
+                    Some(match range {
+                        None => (first_key, last_key),
+                        Some((first, last)) => (first.min(first_key), last.max(last_key)),
+                    })
+                },
+            );
+        if let Some((first_key, last_key)) = key_range {
+            inputs.extend((0..segments.len()).filter(|&idx| {
+                segments[idx].level == output_level
+                    && segments[idx]
+                        .key_range
+                        .as_ref()
+                        .is_some_and(|(first, last)| {
+                            first.as_str() <= last_key && first_key <= last.as_str()
+                        })
+            }));
+        }
+        Some(CompactionTask::Merge {
+            inputs,
+            output_level,
+            max_output_size: Some(self.target_file_size),
+        })
+    }
+}
+
+impl LeveledCompaction {
+    fn max_level_size(&self, level: usize) -> u64 {
+        self.level_size_multiplier
+            .saturating_pow(level as u32 - 1)
+            .saturating_mul(self.base_level_size)
+    }
+    // the level furthest over its budget, if any is
+    fn pick_level(&self, segments: &[SegmentInfo]) -> Option<usize> {
+        let mut num_level0_segments = 0;
+        let mut level_sizes = BTreeMap::new();
+        for segment in segments {
+            match segment.level {
+                0 => num_level0_segments += 1,
+                level => *level_sizes.entry(level).or_insert(0) += segment.size,
+            }
+        }
+        let mut picked = (num_level0_segments as f64 / self.level0_threshold as f64, 0);
+        for (level, size) in level_sizes {
+            let score = size as f64 / self.max_level_size(level) as f64;
+            if score > picked.0 {
+                picked = (score, level);
+            }
+        }
+        (picked.0 >= 1.0).then_some(picked.1)
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+};
+
+use crate::crc::crc32;
+use crate::encoding::{
+    decode_record_header, put_record_header, put_str, put_u32, put_u64, Decoder, RECORD_HEADER_SIZE,
+};
+use crate::error::{DbResult, Error};
+use crate::kv_file::RecoveryPolicy;
+use crate::tmp_file_names::TMP_MANIFEST_FILE_NAME;
+use crate::utils::sync_dir;
+
+// The manifest is the record of which segments are live. It's a log of version edits, each one
+// adding and removing segments in a single record, so a crash leaves the segment set either as it
+// was before an edit or as it is after it, whatever state the files themselves were left in.
+//
+// It also hands out the file numbers of new segments, every edit records the next one so that a
+// number is never given out twice, and records the last sequence number written for the same
+// reason.
+//
+// record layout: checksum of the payload (4 bytes), payload length (4 bytes), checksum of the two
+// fields before it (4 bytes), payload
+// payload layout: next file number (8 bytes), last sequence number (8 bytes), number of removed
+// segments (4 bytes), then the file
+// number (8 bytes) of each, number of added segments (4 bytes), then for each: file number (8
+// bytes), level (4 bytes), order (8 bytes), key range flag (1 byte), first and last key if the
+// flag is set
+pub const MANIFEST_FILE_NAME: &str = "manifest.txt";
+// the manifest is rewritten with just the live segments once it has this many edits
+const MAX_MANIFEST_EDITS: usize = 1000;
+
+// the next file number and the last sequence number, as of the edit they're recorded with
+type RecordHeader = (usize, u64);
+
+#[derive(Clone, Debug)]
+pub struct SegmentRecord {
+    pub file_number: usize,
+    pub level: usize,
+    pub order: usize,
+    pub key_range: Option<(String, String)>,
+}
+
+// Added segments replace any live segment with the same file number, which is how a segment's key
+// range gets updated once it's sealed.
+#[derive(Default)]
+pub struct VersionEdit {
+    // file numbers of the removed segments
+    pub removed: Vec<usize>,
+    pub added: Vec<SegmentRecord>,
+}
+
+impl VersionEdit {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u32(buf, self.removed.len() as u32);
+        for &file_number in &self.removed {
+            put_u64(buf, file_number as u64);
+        }
+        put_u32(buf, self.added.len() as u32);
+        for record in &self.added {
+            put_u64(buf, record.file_number as u64);
+            put_u32(buf, record.level as u32);
+            put_u64(buf, record.order as u64);
+            match &record.key_range {
+                Some((first_key, last_key)) => {
+                    buf.push(1);
+                    put_str(buf, first_key);
+                    put_str(buf, last_key);
+                }
+                None => buf.push(0),
+            }
+        }
+    }
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        let mut edit = VersionEdit::default();
+        for _ in 0..decoder.u32()? {
+            edit.removed.push(decoder.u64()? as usize);
+        }
+        for _ in 0..decoder.u32()? {
+            let file_number = decoder.u64()? as usize;
+            let level = decoder.u32()? as usize;
+            let order = decoder.u64()? as usize;
+            let key_range = match decoder.u8()? {
+                0 => None,
+                _ => Some((decoder.str()?, decoder.str()?)),
+            };
+            edit.added.push(SegmentRecord {
+                file_number,
+                level,
+                order,
+                key_range,
+            });
+        }
+        Ok(edit)
+    }
+}
+
+pub struct Manifest {
+    dir_path: String,
+    file: File,
+    // every live segment, by file number
+    live: BTreeMap<usize, SegmentRecord>,
+    next_file_number: usize,
+    last_seq: u64,
+    num_edits: usize,
+}
+
+impl Manifest {
+    // the live segments along with the next file number and the last sequence number, or `None`
+    // if the directory has no manifest
+    pub fn read(
+        dir_path: &str,
+        recovery_policy: RecoveryPolicy,
+    ) -> DbResult<Option<(Vec<SegmentRecord>, usize, u64)>> {
+        let file_path = dir_path.to_owned() + MANIFEST_FILE_NAME;
+        let bytes = match fs::read(&file_path) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut live = BTreeMap::new();
+        let (mut next_file_number, mut last_seq) = (0, 0);
+        let mut pos = 0;
+        while let Some((header, edit, len)) = read_record(&file_path, &bytes, pos)? {
+            apply(&mut live, &edit);
+            (next_file_number, last_seq) = header;
+            pos += len;
+        }
+        // the tail is dropped when the manifest is rewritten on open
+        if pos < bytes.len() {
+            if let RecoveryPolicy::Refuse = recovery_policy {
+                return Err(Error::TornTail(file_path, pos as u64));
+            }
+        }
+        Ok(Some((to_records(&live), next_file_number, last_seq)))
+    }
+    // starts a new manifest listing the given segments, in place of any there was
+    pub fn create(
+        dir_path: &str,
+        segments: Vec<SegmentRecord>,
+        next_file_number: usize,
+        last_seq: u64,
+    ) -> DbResult<Manifest> {
+        let live = segments
+            .into_iter()
+            .map(|record| (record.file_number, record))
+            .collect();
+        Ok(Manifest {
+            dir_path: dir_path.to_owned(),
+            file: write_snapshot(dir_path, &live, next_file_number, last_seq)?,
+            live,
+            next_file_number,
+            last_seq,
+            num_edits: 1,
+        })
+    }
+    // recorded along with the next edit
+    pub fn set_last_seq(&mut self, last_seq: u64) {
+        self.last_seq = self.last_seq.max(last_seq);
+    }
+    // the number for a new segment file, it's recorded along with the next edit, which is the one
+    // that adds the segment
+    pub fn new_file_number(&mut self) -> usize {
+        self.next_file_number += 1;
+        self.next_file_number - 1
+    }
+    // appends the edit, it's durable once this returns
+    pub fn log(&mut self, edit: &VersionEdit) -> DbResult<()> {
+        // the directory entries of the segments it adds are made durable first, so a crash can't
+        // leave the manifest listing a file that isn't there; the segments it removes only have
+        // their files deleted once this returns
+        if !edit.added.is_empty() {
+            sync_dir(&self.dir_path)?;
+        }
+        let mut buf = vec![];
+        encode_record(&mut buf, (self.next_file_number, self.last_seq), edit);
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        apply(&mut self.live, edit);
+        self.num_edits += 1;
+        if self.num_edits >= MAX_MANIFEST_EDITS {
+            self.file = write_snapshot(
+                &self.dir_path,
+                &self.live,
+                self.next_file_number,
+                self.last_seq,
+            )?;
+            self.num_edits = 1;
+        }
+        Ok(())
+    }
+}
+
+// writes the live segments as a single edit next to the manifest and renames it over it, the rename
+// is atomic so a crash leaves either the old manifest or the new one, and it's durable once the
+// directory is synced, returns the new manifest opened for appending
+fn write_snapshot(
+    dir_path: &str,
+    live: &BTreeMap<usize, SegmentRecord>,
+    next_file_number: usize,
+    last_seq: u64,
+) -> DbResult<File> {
+    let mut buf = vec![];
+    encode_record(
+        &mut buf,
+        (next_file_number, last_seq),
+        &VersionEdit {
+            removed: vec![],
+            added: to_records(live),
+        },
+    );
+    let tmp_file_path = dir_path.to_owned() + TMP_MANIFEST_FILE_NAME;
+    let mut tmp_file = File::create(&tmp_file_path)?;
+    tmp_file.write_all(&buf)?;
+    tmp_file.sync_all()?;
+    let file_path = dir_path.to_owned() + MANIFEST_FILE_NAME;
+    fs::rename(tmp_file_path, &file_path)?;
+    sync_dir(dir_path)?;
+    Ok(OpenOptions::new().append(true).open(file_path)?)
+}
+
+fn apply(live: &mut BTreeMap<usize, SegmentRecord>, edit: &VersionEdit) {
+    for file_number in &edit.removed {
+        live.remove(file_number);
+    }
+    for record in &edit.added {
+        live.insert(record.file_number, record.clone());
+    }
+}
+
+fn to_records(live: &BTreeMap<usize, SegmentRecord>) -> Vec<SegmentRecord> {
+    live.values().cloned().collect()
+}
+
+fn encode_record(
+    buf: &mut Vec<u8>,
+    (next_file_number, last_seq): RecordHeader,
+    edit: &VersionEdit,
+) {
+    let mut payload = vec![];
+    put_u64(&mut payload, next_file_number as u64);
+    put_u64(&mut payload, last_seq);
+    edit.encode(&mut payload);
+    put_record_header(buf, &payload);
+    buf.extend_from_slice(&payload);
+}
+
+// the header and edit at `pos` along with their encoded length, `None` at the end of the manifest
+// or at an edit that was only partly written
+fn read_record(
+    file_path: &str,
+    bytes: &[u8],
+    pos: usize,
+) -> DbResult<Option<(RecordHeader, VersionEdit, usize)>> {
+    let rest = &bytes[pos..];
+    if rest.len() < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+    let corrupted = |msg: &str| Error::Corrupted(file_path.to_owned(), pos as u64, msg.to_owned());
+    // a header that doesn't match its checksum is only a torn edit if nothing follows it, otherwise
+    // its length can't be trusted to tell where the edit ends and the manifest is corrupted
+    let (crc, payload_len) = match decode_record_header(&rest[..RECORD_HEADER_SIZE]) {
+        Ok(fields) => fields,
+        Err(_) if rest.len() == RECORD_HEADER_SIZE => return Ok(None),
+        Err(msg) => return Err(corrupted(&msg)),
+    };
+    // the header's checksum matched, so the edit was cut off by the end of the manifest
+    if rest.len() - RECORD_HEADER_SIZE < payload_len {
+        return Ok(None);
+    }
+    let payload = &rest[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload_len];
+    let len = RECORD_HEADER_SIZE + payload_len;
+    if crc32(payload) != crc {
+        if len == rest.len() {
+            return Ok(None);
+        }
+        return Err(corrupted("checksum mismatch"));
+    }
+
+    let mut decoder = Decoder::new(payload);
+    let next_file_number = decoder.u64().map_err(|msg| corrupted(&msg))? as usize;
+    let last_seq = decoder.u64().map_err(|msg| corrupted(&msg))?;
+    match VersionEdit::decode(&mut decoder) {
+        Ok(edit) if decoder.is_empty() => Ok(Some(((next_file_number, last_seq), edit, len))),
+        Ok(_) => Err(corrupted("trailing bytes after the edit")),
+        Err(msg) => Err(corrupted(&msg)),
+    }
+}
@@ -0,0 +1,115 @@
+use std::cmp::Reverse;
+
+use crate::error::DbResult;
+use crate::kvdb::{
+    KeyStatus::{self, Deleted, Present},
+    ScanDirection, StatusIterator,
+};
+
+// a status of a key along with the sequence number of the write that set it
+pub type Version = (KeyStatus<String>, u64);
+
+// Merges sorted sources into one sorted stream of live key-value pairs as of a sequence number.
+// A source can hold several versions of a key, in any order, and the newest version that isn't
+// newer than the sequence number wins. Sources are ordered from newest to oldest, so when two of
+// them have a copy of the same write, the first one's comes first.
+pub struct MergingIterator<'a> {
+    sources: Vec<StatusIterator<'a>>,
+    heads: Vec<Option<(String, KeyStatus<String>, u64)>>,
+    direction: ScanDirection,
+    seq: u64,
+    started: bool,
+}
+
+impl<'a> MergingIterator<'a> {
+    pub fn new(sources: Vec<StatusIterator<'a>>, direction: ScanDirection) -> Self {
+        Self::as_of(sources, direction, u64::MAX)
+    }
+    // versions written after `seq` are skipped
+    pub fn as_of(sources: Vec<StatusIterator<'a>>, direction: ScanDirection, seq: u64) -> Self {
+        MergingIterator {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+            direction,
+            seq,
+            started: false,
+        }
+    }
+    fn advance(&mut self, idx: usize) -> DbResult<()> {
+        self.heads[idx] = self.sources[idx].next().transpose()?;
+        Ok(())
+    }
+    // every version of the next key, tombstones included, from the newest to the oldest, whatever
+    // the iterator's sequence number is
+    pub fn next_versions(&mut self) -> DbResult<Option<(String, Vec<Version>)>> {
+        if !self.started {
+            self.started = true;
+            for idx in 0..self.sources.len() {
+                self.advance(idx)?;
+            }
+        }
+
+        let mut next_idx: Option<usize> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            let Some((key, _, _)) = head else {
+                continue;
+            };
+            let precedes = match next_idx {
+                None => true,
+                Some(next_idx) => {
+                    let (next_key, _, _) = self.heads[next_idx].as_ref().unwrap();
+                    self.direction.precedes(key, next_key)
+                }
+            };
+            if precedes {
+                next_idx = Some(idx);
+            }
+        }
+        let Some(next_idx) = next_idx else {
+            return Ok(None);
+        };
+
+        let key = self.heads[next_idx].as_ref().unwrap().0.clone();
+        let mut versions = vec![];
+        for idx in 0..self.heads.len() {
+            while matches!(&self.heads[idx], Some((other_key, _, _)) if *other_key == key) {
+                let (_, status, seq) = self.heads[idx].take().unwrap();
+                versions.push((status, seq));
+                self.advance(idx)?;
+            }
+        }
+        // the sort is stable, copies of a write stay in the order of their sources
+        versions.sort_by_key(|&(_, seq)| Reverse(seq));
+        Ok(Some((key, versions)))
+    }
+    // the newest status of the next key as of the iterator's sequence number, tombstones
+    // included, along with its sequence number, keys first written after it are skipped
+    pub fn next_status(&mut self) -> DbResult<Option<(String, KeyStatus<String>, u64)>> {
+        while let Some((key, versions)) = self.next_versions()? {
+            if let Some((status, seq)) = versions.into_iter().find(|&(_, seq)| seq <= self.seq) {
+                return Ok(Some((key, status, seq)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for MergingIterator<'a> {
+    type Item = DbResult<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_status() {
+                Ok(None) => return None,
+                Ok(Some((key, Present(value), _))) => return Some(Ok((key, value))),
+                // tombstones hide the key
+                Ok(Some((_, Deleted, _))) => continue,
+                Err(e) => {
+                    self.sources.clear();
+                    self.heads.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
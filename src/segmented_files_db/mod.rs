@@ -1,120 +1,194 @@
-use self::segment::Segment;
+use self::compaction::{CompactionStrategy, CompactionTask, SegmentInfo};
+use self::manifest::{Manifest, VersionEdit};
+use self::merging_iterator::MergingIterator;
+use self::segment::{get_segment_file_name, parse_segment_file_path, sort_by_age, Segment};
 use self::segment_file::{SegmentFile, SegmentFileFactory};
+use self::snapshots::{visible_versions, LiveSnapshots};
 use crate::error::DbResult;
-use crate::tmp_file_names::TMP_SEGMENT_FILE_NAME;
+use crate::kv_file::RecoveryPolicy;
+use crate::kvdb::{write_batch::WriteBatch, KeyRange, KeyStatus, ScanDirection, StatusIterator};
 use crate::{
     check_key_status,
     error::Error,
     kvdb::KeyStatus::{Deleted, Present},
     utils::{is_thread_running, process_dir_contents},
 };
-use segment_file::SegmentReaderFactory;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::{
+    cmp::Reverse,
     fs::create_dir_all,
     mem::replace,
-    sync::{Arc, RwLock},
+    panic::resume_unwind,
+    sync::{Arc, Mutex, RwLock},
     thread::{spawn, JoinHandle},
 };
 
+pub mod block_cache;
+pub mod compaction;
+pub mod leveled;
+mod manifest;
+pub mod merging_iterator;
 mod segment;
 pub mod segment_file;
+pub mod snapshots;
 
 pub enum SegmentCreationPolicy {
     Triggered,
     Automatic,
 }
 
-pub struct SegmentedFilesDb<F, U, V>
+// a merge ready to run, with everything it needs from the segments taken out beforehand so that
+// it runs without holding the segments' lock
+struct MergeJob {
+    // file numbers of the segments being replaced
+    inputs: Vec<usize>,
+    // scans over the inputs, from the newest to the oldest
+    sources: Vec<StatusIterator<'static>>,
+    output_level: usize,
+    max_output_size: Option<u64>,
+    // key ranges of the segments older than the inputs, `None` for ones that could hold any key,
+    // a tombstone outside all of them has nothing left to hide
+    older_key_ranges: Vec<Option<(String, String)>>,
+    // the order of an output in level 0, where it takes the place of the newest input among
+    // segments that can overlap
+    newest_input_order: usize,
+    // the sequence numbers live snapshots read at when the merge was planned, the versions they
+    // read are kept
+    snapshot_seqs: Vec<u64>,
+}
+
+pub struct SegmentedFilesDb<F, U>
 where
     F: SegmentFile + Sync + Send + 'static,
     U: SegmentFileFactory<F> + Sync + Send + 'static,
-    V: SegmentReaderFactory<F> + Sync + Send + 'static,
 {
-    merging_threshold: u64,
+    compaction_strategy: Arc<dyn CompactionStrategy>,
     segment_creation_policy: SegmentCreationPolicy,
     locked_past_segments: Arc<RwLock<VecDeque<Segment<F>>>>,
     current_segment: Segment<F>,
+    manifest: Arc<Mutex<Manifest>>,
     file_factory: Arc<U>,
-    reader_factory: Arc<V>,
-    merging_thread_join_handle: Option<JoinHandle<()>>,
+    merging_thread_join_handle: Option<JoinHandle<DbResult<()>>>,
+    // the error a merge failed with, every write fails with it until the DB is reopened, as the
+    // segments might not match the manifest anymore
+    merge_error: Option<Error>,
+    live_snapshots: Arc<LiveSnapshots>,
+    // the highest sequence number written, the manifest records it whenever a segment is sealed,
+    // so it's kept even once a merge drops the records that had it
+    last_seq: u64,
 }
 
-impl<F, U, V> SegmentedFilesDb<F, U, V>
+impl<F, U> SegmentedFilesDb<F, U>
 where
     F: SegmentFile + Sync + Send + 'static,
     U: SegmentFileFactory<F> + Sync + Send + 'static,
-    V: SegmentReaderFactory<F> + Sync + Send + 'static,
 {
-    pub fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
+    // sequence numbers are given by the caller, a write has to get a higher one than the writes
+    // before it
+    pub fn set_status(&mut self, key: &str, status: &KeyStatus<String>, seq: u64) -> DbResult<()> {
+        self.check_merge_error()?;
         self.maybe_create_fresh_segment()?;
         self.current_segment
             .locked_file
             .write()?
-            .set_status(key, &Present(value.to_owned()))
+            .set_status(key, status, seq)?;
+        self.last_seq = self.last_seq.max(seq);
+        Ok(())
     }
-    pub fn delete(&mut self, key: &str) -> DbResult<()> {
+    // the whole batch goes to the current segment, even if it takes the segment past its size
+    pub fn write(&mut self, batch: &WriteBatch, first_seq: u64) -> DbResult<()> {
+        self.check_merge_error()?;
         self.maybe_create_fresh_segment()?;
         self.current_segment
             .locked_file
             .write()?
-            .set_status(key, &Deleted)
+            .write_batch(batch, first_seq)?;
+        self.last_seq = self.last_seq.max(first_seq + batch.len() as u64 - 1);
+        Ok(())
     }
-    pub fn get(&mut self, key: &str) -> DbResult<Option<String>> {
-        {
-            check_key_status!(self.current_segment.locked_file.write()?.get_status(key)?);
-        }
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+    // reads only share the segments' locks, so any number of them can run at once
+    pub fn get(&self, key: &str) -> DbResult<Option<String>> {
+        self.find_status(key, |file| file.get_status(key))
+    }
+    // the value of the key as of `seq`, for segments that keep several versions of a key
+    pub fn get_at(&self, key: &str, seq: u64) -> DbResult<Option<String>> {
+        self.find_status(key, |file| file.get_status_at(key, seq))
+    }
+    // a snapshot registered here keeps every version it reads from being merged away until it's
+    // released
+    pub fn live_snapshots(&self) -> &Arc<LiveSnapshots> {
+        &self.live_snapshots
+    }
+    // one iterator per segment, from the newest segment to the oldest
+    pub fn scan(
+        &self,
+        range: &KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<Vec<StatusIterator<'static>>> {
+        let mut iterators = vec![self
+            .current_segment
+            .locked_file
+            .read()?
+            .scan(range, direction)?];
 
-        {
-            let past_segments = self.locked_past_segments.read()?;
-            for segment in past_segments.iter().rev() {
-                check_key_status!(segment.locked_file.write()?.get_status(key)?);
+        let past_segments = self.locked_past_segments.read()?;
+        for segment in past_segments.iter().rev() {
+            if let Some((first_key, last_key)) = &segment.key_range {
+                if range.is_before_start(last_key) || range.is_after_end(first_key) {
+                    continue;
+                }
             }
+            iterators.push(segment.locked_file.read()?.scan(range, direction)?);
         }
-
-        Ok(None)
+        Ok(iterators)
     }
     pub fn create_fresh_segment(&mut self) -> DbResult<()> {
+        self.check_merge_error()?;
         let should_do = self
             .current_segment
             .locked_file
             .write()?
             .ready_to_be_archived()?;
         if should_do {
-            let past_segments_len;
+            let needs_compaction;
             {
                 let mut past_segments = self.locked_past_segments.write()?;
 
-                let latest_past_segment_id: usize = past_segments
-                    .back()
-                    .map(|segment| segment.id + 1)
-                    .get_or_insert(0)
-                    .clone();
                 self.current_segment
-                    .change_id(latest_past_segment_id)
-                    .map_err(|e| Error::wrap("error in changing id of current segment", e))?;
+                    .seal()
+                    .map_err(|e| Error::wrap("error in sealing current segment", e))?;
 
-                let new_segment_id = latest_past_segment_id + 1;
-                let new_segment = Segment::new(new_segment_id, &(*self.file_factory))?;
+                // file numbers only grow, so the new segment is ordered after every past one
+                let mut manifest = self.manifest.lock()?;
+                manifest.set_last_seq(self.last_seq);
+                let new_segment = Segment::new(manifest.new_file_number(), &(*self.file_factory))?;
+                manifest.log(&VersionEdit {
+                    removed: vec![],
+                    added: vec![self.current_segment.record(), new_segment.record()],
+                })?;
+                drop(manifest);
                 let latest_past_segment = replace(&mut self.current_segment, new_segment);
 
                 past_segments.push_back(latest_past_segment);
-                past_segments_len = past_segments.len();
+                let infos: Vec<SegmentInfo> = past_segments.iter().map(Segment::info).collect();
+                needs_compaction = self.compaction_strategy.pick(&infos).is_some();
             }
 
-            if u64::try_from(past_segments_len).unwrap() > self.merging_threshold {
-                self.maybe_merge_past_segments_in_background();
+            if needs_compaction {
+                self.maybe_compact_past_segments_in_background()?;
             }
         }
         Ok(())
     }
 }
 
-impl<F, U, V> Drop for SegmentedFilesDb<F, U, V>
+impl<F, U> Drop for SegmentedFilesDb<F, U>
 where
     F: SegmentFile + Sync + Send + 'static,
     U: SegmentFileFactory<F> + Sync + Send + 'static,
-    V: SegmentReaderFactory<F> + Sync + Send + 'static,
 {
     fn drop(&mut self) {
         if let Some(handle) = self.merging_thread_join_handle.take() {
@@ -123,45 +197,132 @@ where
     }
 }
 
-impl<F, U, V> SegmentedFilesDb<F, U, V>
+impl<F, U> SegmentedFilesDb<F, U>
 where
     F: SegmentFile + Sync + Send + 'static,
     U: SegmentFileFactory<F> + Sync + Send + 'static,
-    V: SegmentReaderFactory<F> + Sync + Send + 'static,
 {
     pub fn new(
         dir_path: &str,
-        merging_threshold: u64,
+        compaction_strategy: Box<dyn CompactionStrategy>,
         segment_creation_policy: SegmentCreationPolicy,
+        recovery_policy: RecoveryPolicy,
         file_factory: U,
-        reader_factory: V,
     ) -> DbResult<Self> {
         create_dir_all(dir_path)?;
 
+        let (records, mut next_file_number, mut last_seq) =
+            Manifest::read(dir_path, recovery_policy)?.unwrap_or((vec![], 0, 0));
+
         let mut segments = vec![];
+        for record in &records {
+            let segment = Segment::from_disk(
+                record.file_number,
+                record.level,
+                record.order,
+                &file_factory,
+            )?;
+            // the segment that was being written to has writes made after the last edit
+            last_seq = last_seq.max(segment.locked_file.read()?.last_seq());
+            segments.push(segment);
+        }
+        // files of segments that were never installed, or that were removed before their files
+        // got deleted, a number some file has is never given out again
+        let live: HashSet<usize> = records.iter().map(|record| record.file_number).collect();
         process_dir_contents(dir_path, &mut |path| {
-            if let Some(segment) = Segment::try_from_disk(&path, &file_factory)? {
-                segments.push(segment);
+            let Some(file_name) = path.file_name().and_then(|file_name| file_name.to_str()) else {
+                return Ok(());
+            };
+            if let Some(file_number) = parse_segment_file_path(&path) {
+                next_file_number = next_file_number.max(file_number + 1);
+                if !live.contains(&file_number) {
+                    file_factory.new(file_name)?.delete()?;
+                }
             }
             Ok(())
         })?;
-        segments.sort_by_key(|segment| segment.id);
+        sort_by_age(&mut segments);
 
-        let current_segment = match segments.pop() {
-            Some(segment) => segment,
-            None => Segment::new(0, &file_factory)?,
-        };
+        // segments left on disk are all archived, writes go to a fresh segment; one that nothing
+        // was written to, like the segment that was current if no write came after it, is dropped
+        // instead, or every reopen would add an empty segment that merges take for one that could
+        // hold any key
+        let mut empty_segments = vec![];
+        let mut past_segments = vec![];
+        for mut segment in segments {
+            if segment.locked_file.read()?.size()? == 0 {
+                empty_segments.push(segment);
+                continue;
+            }
+            segment.seal()?;
+            past_segments.push(segment);
+        }
+        let current_segment = Segment::new(next_file_number, &file_factory)?;
+        let manifest = Manifest::create(
+            dir_path,
+            past_segments
+                .iter()
+                .chain([&current_segment])
+                .map(Segment::record)
+                .collect(),
+            next_file_number + 1,
+            last_seq,
+        )?;
+        // only once the manifest no longer lists them
+        for segment in empty_segments {
+            segment.locked_file.into_inner()?.delete()?;
+        }
 
         Ok(SegmentedFilesDb {
-            merging_threshold,
+            compaction_strategy: Arc::from(compaction_strategy),
             segment_creation_policy,
-            locked_past_segments: Arc::new(RwLock::new(VecDeque::from(segments))),
-            current_segment: current_segment,
-            reader_factory: Arc::new(reader_factory),
+            locked_past_segments: Arc::new(RwLock::new(VecDeque::from(past_segments))),
+            current_segment,
+            manifest: Arc::new(Mutex::new(manifest)),
             file_factory: Arc::new(file_factory),
             merging_thread_join_handle: None,
+            merge_error: None,
+            live_snapshots: Arc::new(LiveSnapshots::default()),
+            last_seq,
         })
     }
+    // the first status `get_status` finds, going from the newest segment to the oldest
+    fn find_status(
+        &self,
+        key: &str,
+        get_status: impl Fn(&F) -> DbResult<Option<KeyStatus<String>>>,
+    ) -> DbResult<Option<String>> {
+        {
+            check_key_status!(get_status(&*self.current_segment.locked_file.read()?)?);
+        }
+
+        {
+            let past_segments = self.locked_past_segments.read()?;
+            for segment in past_segments.iter().rev() {
+                if !segment.may_contain(key) {
+                    continue;
+                }
+                check_key_status!(get_status(&*segment.locked_file.read()?)?);
+            }
+        }
+
+        Ok(None)
+    }
+    // picks up the error of a merge that's done, if it failed
+    fn check_merge_error(&mut self) -> DbResult<()> {
+        if !is_thread_running(&self.merging_thread_join_handle) {
+            if let Some(handle) = self.merging_thread_join_handle.take() {
+                match handle.join() {
+                    Ok(result) => self.merge_error = result.err(),
+                    Err(e) => resume_unwind(e),
+                }
+            }
+        }
+        match &self.merge_error {
+            Some(e) => Err(Error::wrap("error in merging segments", e.clone())),
+            None => Ok(()),
+        }
+    }
     fn maybe_create_fresh_segment(&mut self) -> DbResult<()> {
         if is_thread_running(&self.merging_thread_join_handle) {
             return Ok(());
@@ -171,54 +332,197 @@ where
         }
         Ok(())
     }
-    fn maybe_merge_past_segments_in_background(&mut self) {
+    fn maybe_compact_past_segments_in_background(&mut self) -> DbResult<()> {
+        // the last merge might have finished since the error was checked for
+        self.check_merge_error()?;
         if is_thread_running(&self.merging_thread_join_handle) {
-            return;
+            return Ok(());
         }
 
         let locked_past_segments = Arc::clone(&self.locked_past_segments);
+        let manifest = Arc::clone(&self.manifest);
         let file_factory = Arc::clone(&self.file_factory);
-        let reader_factory = Arc::clone(&self.reader_factory);
+        let compaction_strategy = Arc::clone(&self.compaction_strategy);
+        let live_snapshots = Arc::clone(&self.live_snapshots);
         self.merging_thread_join_handle = Some(spawn(move || {
-            if let Err(e) =
-                Self::merge_past_segments(locked_past_segments, file_factory, reader_factory)
-            {
-                panic!("error in merging thread: {e}")
-            }
+            Self::compact_past_segments(
+                &locked_past_segments,
+                &manifest,
+                &*file_factory,
+                &*compaction_strategy,
+                &live_snapshots,
+            )
         }));
+        Ok(())
     }
-    fn merge_past_segments(
-        locked_past_segments: Arc<RwLock<VecDeque<Segment<F>>>>,
-        file_factory: Arc<U>,
-        reader_factory: Arc<V>,
+    // runs the strategy's compactions until it has nothing left to do
+    fn compact_past_segments(
+        locked_past_segments: &RwLock<VecDeque<Segment<F>>>,
+        manifest: &Mutex<Manifest>,
+        file_factory: &U,
+        compaction_strategy: &dyn CompactionStrategy,
+        live_snapshots: &LiveSnapshots,
     ) -> DbResult<()> {
-        let mut merged_segment_file = file_factory.new(TMP_SEGMENT_FILE_NAME)?;
+        loop {
+            let job = {
+                let past_segments = locked_past_segments.read()?;
+                let infos: Vec<SegmentInfo> = past_segments.iter().map(Segment::info).collect();
+                match compaction_strategy.pick(&infos) {
+                    None => return Ok(()),
+                    Some(CompactionTask::Drop(dropped)) => {
+                        let dropped: Vec<usize> = dropped
+                            .into_iter()
+                            .map(|idx| past_segments[idx].file_number)
+                            .collect();
+                        drop(past_segments);
+                        let mut past_segments = locked_past_segments.write()?;
+                        manifest.lock()?.log(&VersionEdit {
+                            removed: dropped.clone(),
+                            added: vec![],
+                        })?;
+                        Self::remove_segments(&mut past_segments, dropped)?;
+                        continue;
+                    }
+                    Some(CompactionTask::Merge {
+                        inputs,
+                        output_level,
+                        max_output_size,
+                    }) => Self::plan_merge(
+                        &past_segments,
+                        inputs,
+                        output_level,
+                        max_output_size,
+                        live_snapshots.seqs()?,
+                    )?,
+                }
+            };
 
-        let mut num_past_segments_merged = 0;
-        for segment in locked_past_segments.read()?.iter().rev() {
-            let file = segment.locked_file.read()?;
-            let mut segment_reader = reader_factory.new(&file)?;
-            merged_segment_file.absorb(&mut segment_reader)?;
-            num_past_segments_merged += 1;
-        }
-
-        merged_segment_file.compact()?;
+            let inputs = job.inputs.clone();
+            let outputs = Self::run_merge(job, manifest, file_factory)?;
 
-        {
             let mut past_segments = locked_past_segments.write()?;
-            // more segments might have been added during compaction
-            assert!(num_past_segments_merged <= past_segments.len());
-            for _ in 0..num_past_segments_merged {
-                let past_segment = past_segments.pop_front().unwrap();
-                past_segment.locked_file.into_inner()?.delete()?;
+            // the outputs are sealed, so they can take the place of the inputs
+            manifest.lock()?.log(&VersionEdit {
+                removed: inputs.clone(),
+                added: outputs.iter().map(Segment::record).collect(),
+            })?;
+            Self::remove_segments(&mut past_segments, inputs)?;
+            past_segments.extend(outputs);
+            sort_by_age(past_segments.make_contiguous());
+        }
+    }
+    fn plan_merge(
+        past_segments: &VecDeque<Segment<F>>,
+        mut inputs: Vec<usize>,
+        output_level: usize,
+        max_output_size: Option<u64>,
+        snapshot_seqs: Vec<u64>,
+    ) -> DbResult<MergeJob> {
+        inputs.sort_by_key(|&idx| Reverse(idx));
+        inputs.dedup();
+        let newest_input = inputs[0];
+        let sources = inputs
+            .iter()
+            .map(|&idx| {
+                past_segments[idx]
+                    .locked_file
+                    .read()?
+                    .scan(&KeyRange::new(..), ScanDirection::Forward)
+            })
+            .collect::<DbResult<Vec<StatusIterator<'static>>>>()?;
+        Ok(MergeJob {
+            sources,
+            output_level,
+            max_output_size,
+            older_key_ranges: (0..newest_input)
+                .filter(|idx| !inputs.contains(idx))
+                .map(|idx| past_segments[idx].key_range.clone())
+                .collect(),
+            newest_input_order: past_segments[newest_input].order,
+            snapshot_seqs,
+            inputs: inputs
+                .iter()
+                .map(|&idx| past_segments[idx].file_number)
+                .collect(),
+        })
+    }
+    // merges the inputs into sealed segments, each written to a file with a new number, so none
+    // of them is in the way of the inputs
+    fn run_merge(
+        job: MergeJob,
+        manifest: &Mutex<Manifest>,
+        file_factory: &U,
+    ) -> DbResult<Vec<Segment<F>>> {
+        let mut merged = MergingIterator::new(job.sources, ScanDirection::Forward);
+        let mut outputs = vec![];
+        // the file being written, its number and the bytes written to it
+        let mut output: Option<(F, usize, u64)> = None;
+        let new_output = || -> DbResult<(F, usize, u64)> {
+            let file_number = manifest.lock()?.new_file_number();
+            Ok((
+                file_factory.new(&get_segment_file_name(file_number))?,
+                file_number,
+                0,
+            ))
+        };
+        let seal_output = |(file, file_number, _): (F, usize, u64)| {
+            let order = match job.output_level {
+                0 => job.newest_input_order,
+                _ => file_number,
+            };
+            Segment::from_file(file, file_number, job.output_level, order)
+        };
+        while let Some((key, versions)) = merged.next_versions()? {
+            let mut versions = visible_versions(versions, &job.snapshot_seqs);
+            // a tombstone older than every other version kept has nothing left to hide once no
+            // older segment can hold the key
+            let older_may_contain = job
+                .older_key_ranges
+                .iter()
+                .any(|key_range| match key_range {
+                    Some((first, last)) => first <= &key && &key <= last,
+                    None => true,
+                });
+            while !older_may_contain && matches!(versions.last(), Some((Deleted, _))) {
+                versions.pop();
             }
-
-            for (idx, segment) in past_segments.iter_mut().enumerate() {
-                segment.change_id(idx + 1)?;
+            if versions.is_empty() {
+                continue;
+            }
+            let (file, _, written) = match output.as_mut() {
+                Some(output) => output,
+                None => output.insert(new_output()?),
+            };
+            // the versions of a key go to the same output
+            for (status, seq) in versions {
+                file.set_status(&key, &status, seq)?;
+                *written += match &status {
+                    Present(value) => key.len() + value.len(),
+                    Deleted => key.len(),
+                } as u64;
+            }
+            if job.output_level > 0 && job.max_output_size.is_some_and(|max| *written >= max) {
+                outputs.push(seal_output(output.take().unwrap())?);
             }
-            past_segments.push_front(Segment::from_file(merged_segment_file, 0)?);
         }
-
+        if let Some(output) = output {
+            outputs.push(seal_output(output)?);
+        }
+        Ok(outputs)
+    }
+    // removes the segments with the given file numbers, and deletes their files, in that order
+    fn remove_segments(
+        past_segments: &mut VecDeque<Segment<F>>,
+        removed: Vec<usize>,
+    ) -> DbResult<()> {
+        for file_number in removed {
+            let idx = past_segments
+                .iter()
+                .position(|segment| segment.file_number == file_number)
+                .unwrap();
+            let segment = past_segments.remove(idx).unwrap();
+            segment.locked_file.into_inner()?.delete()?;
+        }
         Ok(())
     }
 }
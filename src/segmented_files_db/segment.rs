@@ -1,14 +1,26 @@
-use std::{path::PathBuf, sync::RwLock};
+use std::{cmp::Reverse, path::Path, sync::RwLock, time::SystemTime};
 
 use crate::error::DbResult;
 
+use super::compaction::SegmentInfo;
+use super::manifest::SegmentRecord;
 use super::segment_file::{SegmentFile, SegmentFileFactory};
 
 pub struct Segment<T>
 where
     T: SegmentFile,
 {
-    pub id: usize,
+    // names the segment's file, given out once by the manifest and never reused, so a segment
+    // keeps its file for as long as it lives
+    pub file_number: usize,
+    pub level: usize,
+    // orders the segments of a level from the oldest to the newest data, a fresh segment takes its
+    // file number and the output of a merge into level 0 takes the order of its newest input
+    pub order: usize,
+    // smallest and largest key in the segment, known once it's sealed
+    pub key_range: Option<(String, String)>,
+    pub size: u64,
+    pub last_modified: SystemTime,
     pub locked_file: RwLock<T>,
 }
 
@@ -16,48 +28,96 @@ impl<T> Segment<T>
 where
     T: SegmentFile,
 {
-    pub fn new<U: SegmentFileFactory<T>>(id: usize, file_factory: &U) -> DbResult<Self> {
+    pub fn new<U: SegmentFileFactory<T>>(file_number: usize, file_factory: &U) -> DbResult<Self> {
         Ok(Segment {
-            id,
-            locked_file: RwLock::new(file_factory.new(get_segment_file_name(id).as_str())?),
+            file_number,
+            level: 0,
+            order: file_number,
+            key_range: None,
+            size: 0,
+            last_modified: SystemTime::now(),
+            locked_file: RwLock::new(file_factory.new(&get_segment_file_name(file_number))?),
         })
     }
-    pub fn try_from_disk<U: SegmentFileFactory<T>>(
-        path: &PathBuf,
+    pub fn from_disk<U: SegmentFileFactory<T>>(
+        file_number: usize,
+        level: usize,
+        order: usize,
         file_factory: &U,
-    ) -> DbResult<Option<Self>> {
-        if let Some(file_name_os_str) = path.file_name() {
-            if let Some(file_name) = file_name_os_str.to_str() {
-                if let Some(file_stem_os_str) = path.file_stem() {
-                    if let Some(file_stem) = file_stem_os_str.to_str() {
-                        if let Ok(id) = file_stem.parse::<usize>() {
-                            return Ok(Some(Segment {
-                                id,
-                                locked_file: RwLock::new(file_factory.from_disk(file_name)?),
-                            }));
-                        }
-                    }
-                }
-            }
-        }
-        Ok(None)
+    ) -> DbResult<Self> {
+        Ok(Segment {
+            file_number,
+            level,
+            order,
+            key_range: None,
+            size: 0,
+            last_modified: SystemTime::now(),
+            locked_file: RwLock::new(file_factory.from_disk(&get_segment_file_name(file_number))?),
+        })
     }
-    pub fn from_file(file: T, id: usize) -> DbResult<Self> {
+    // a sealed segment out of a file written under the name that goes with the file number
+    pub fn from_file(file: T, file_number: usize, level: usize, order: usize) -> DbResult<Self> {
         let mut segment = Segment {
-            id: 0,
+            file_number,
+            level,
+            order,
+            key_range: None,
+            size: 0,
+            last_modified: SystemTime::now(),
             locked_file: RwLock::new(file),
         };
-        segment.change_id(id)?;
+        segment.seal()?;
         Ok(segment)
     }
-    pub fn change_id(&mut self, id: usize) -> DbResult<()> {
-        self.id = id;
-        self.locked_file
-            .write()?
-            .rename(get_segment_file_name(id).as_str())
+    // seals the file and remembers the keys it holds and its size, nothing is written to it
+    // afterwards
+    pub fn seal(&mut self) -> DbResult<()> {
+        let mut file = self.locked_file.write()?;
+        file.seal()?;
+        self.key_range = file.key_range()?;
+        self.size = file.size()?;
+        self.last_modified = file.last_modified()?;
+        Ok(())
+    }
+    pub fn info(&self) -> SegmentInfo {
+        SegmentInfo {
+            level: self.level,
+            size: self.size,
+            key_range: self.key_range.clone(),
+            last_modified: self.last_modified,
+        }
+    }
+    pub fn record(&self) -> SegmentRecord {
+        SegmentRecord {
+            file_number: self.file_number,
+            level: self.level,
+            order: self.order,
+            key_range: self.key_range.clone(),
+        }
+    }
+    pub fn may_contain(&self, key: &str) -> bool {
+        match &self.key_range {
+            Some((first_key, last_key)) => first_key.as_str() <= key && key <= last_key.as_str(),
+            None => true,
+        }
+    }
+}
+
+pub fn get_segment_file_name(file_number: usize) -> String {
+    format!("{}.txt", file_number)
+}
+
+// the file number of the segment file at the path, `None` if it isn't one, other files can live
+// next to the segments, like the hint files of segmented logs
+pub fn parse_segment_file_path(path: &Path) -> Option<usize> {
+    if path.extension().and_then(|extension| extension.to_str()) != Some("txt") {
+        return None;
     }
+    path.file_stem()?.to_str()?.parse().ok()
 }
 
-fn get_segment_file_name(id: usize) -> String {
-    format!("{}.txt", id)
+// orders segments from the oldest to the newest data: the deepest level first, and by order
+// within a level
+pub fn sort_by_age<T: SegmentFile>(segments: &mut [Segment<T>]) {
+    segments.sort_by_key(|segment| (Reverse(segment.level), segment.order));
 }
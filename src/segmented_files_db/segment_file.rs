@@ -1,30 +1,62 @@
-use crate::error::DbResult;
-use crate::kvdb::KeyStatus;
+use std::time::SystemTime;
 
-pub trait SegmentReader<'a> {
-    fn get_status(&mut self, key: &str) -> DbResult<Option<KeyStatus<String>>>;
-}
+use crate::error::{DbResult, Error};
+use crate::kvdb::{write_batch::WriteBatch, KeyRange, KeyStatus, ScanDirection, StatusIterator};
 
 pub trait SegmentFile {
-    type Reader<'a>: SegmentReader<'a>;
-
-    fn get_status(&mut self, key: &str) -> DbResult<Option<KeyStatus<String>>>;
+    fn get_status(&self, key: &str) -> DbResult<Option<KeyStatus<String>>>;
+    // the status of the newest version of the key as of `seq`, for segments that can hold several
+    // versions of a key, the others are only ever written the newest one
+    fn get_status_at(&self, _key: &str, _seq: u64) -> DbResult<Option<KeyStatus<String>>> {
+        Err(Error::InvalidInput(
+            "segment doesn't support snapshots".to_string(),
+        ))
+    }
+    // statuses of the keys in the range along with their sequence numbers, sorted by key in the
+    // given direction, every version of a key is yielded, the iterator must stay valid after the
+    // segment is merged away
+    fn scan(
+        &self,
+        _range: &KeyRange,
+        _direction: ScanDirection,
+    ) -> DbResult<StatusIterator<'static>> {
+        Err(Error::InvalidInput(
+            "segment doesn't support ordered scans".to_string(),
+        ))
+    }
     fn ready_to_be_archived(&mut self) -> DbResult<bool> {
         Ok(true)
     }
+    // called once the segment is archived, nothing is written to it afterwards
+    fn seal(&mut self) -> DbResult<()> {
+        Ok(())
+    }
+    // smallest and largest key in the segment, `None` if it's empty or its keys aren't sorted
+    fn key_range(&mut self) -> DbResult<Option<(String, String)>> {
+        Ok(None)
+    }
+    fn size(&self) -> DbResult<u64>;
+    fn last_modified(&self) -> DbResult<SystemTime>;
+    // the highest sequence number written to the segment, 0 for segments whose writes are
+    // accounted for somewhere else
+    fn last_seq(&self) -> u64 {
+        0
+    }
 
-    fn set_status(&mut self, key: &str, status: &KeyStatus<String>) -> DbResult<()>;
-    fn absorb<'a>(&mut self, other: &mut Self::Reader<'a>) -> DbResult<()>;
-    fn rename(&mut self, new_file_name: &str) -> DbResult<()>;
-    fn compact(&mut self) -> DbResult<()>;
+    // a merge that keeps several versions of a key writes them one after the other, from the
+    // newest
+    fn set_status(&mut self, key: &str, status: &KeyStatus<String>, seq: u64) -> DbResult<()>;
+    // writes all of the batch or none of it, even if it's interrupted, its entries are numbered
+    // from `first_seq` on
+    fn write_batch(&mut self, _batch: &WriteBatch, _first_seq: u64) -> DbResult<()> {
+        Err(Error::InvalidInput(
+            "segment doesn't support atomic batches".to_string(),
+        ))
+    }
 
     fn delete(self) -> DbResult<()>;
 }
 
-pub trait SegmentReaderFactory<F: SegmentFile> {
-    fn new<'a>(&self, file: &'a F) -> DbResult<F::Reader<'a>>;
-}
-
 pub trait SegmentFileFactory<F: SegmentFile> {
     fn new(&self, file_name: &str) -> DbResult<F>;
     fn from_disk(&self, file_name: &str) -> DbResult<F>;
@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::merging_iterator::Version;
+use crate::error::DbResult;
+
+// The sequence numbers live snapshots read at, shared by the DB's handles and its flushes and
+// merges, which keep every version of a key some snapshot still reads.
+#[derive(Default)]
+pub struct LiveSnapshots {
+    // the number of live snapshots by the sequence number they read at
+    locked_seqs: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl LiveSnapshots {
+    pub fn register(&self, seq: u64) -> DbResult<()> {
+        *self.locked_seqs.lock()?.entry(seq).or_default() += 1;
+        Ok(())
+    }
+    pub fn release(&self, seq: u64) -> DbResult<()> {
+        let mut seqs = self.locked_seqs.lock()?;
+        if let Some(count) = seqs.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                seqs.remove(&seq);
+            }
+        }
+        Ok(())
+    }
+    // in increasing order, a snapshot taken afterwards reads at a sequence number at least as
+    // high as that of every write made so far, so it only needs the newest version of each key
+    pub fn seqs(&self) -> DbResult<Vec<u64>> {
+        Ok(self.locked_seqs.lock()?.keys().copied().collect())
+    }
+}
+
+// out of the versions of a key, from the newest to the oldest, the newest one and the ones some
+// snapshot reads, that is the newest as of the snapshot's sequence number
+pub fn visible_versions(versions: Vec<Version>, snapshot_seqs: &[u64]) -> Vec<Version> {
+    let mut newer_seq = None;
+    let mut visible = vec![];
+    for (status, seq) in versions {
+        let is_visible = match newer_seq {
+            None => true,
+            // read by the snapshots between this version and the next newer one
+            Some(newer_seq) => {
+                let idx = snapshot_seqs.partition_point(|&snapshot_seq| snapshot_seq < seq);
+                snapshot_seqs
+                    .get(idx)
+                    .is_some_and(|&snapshot_seq| snapshot_seq < newer_seq)
+            }
+        };
+        newer_seq = Some(seq);
+        if is_visible {
+            visible.push((status, seq));
+        }
+    }
+    visible
+}
@@ -0,0 +1,103 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use crate::{
+    crc::crc32,
+    encoding::{put_str, put_u32, put_u64, Decoder},
+    error::DbResult,
+    in_memory_db::InMemoryDb,
+};
+
+// A hint file sits next to an archived segment ("3.txt" -> "3.hint") and holds the segment's index
+// without the values, so opening the database doesn't have to read the whole segment.
+//
+// layout: checksum of the rest (4 bytes), size of the segment it describes (8 bytes), highest
+// sequence number in the segment (8 bytes), number of entries (4 bytes), then for each entry: key,
+// record offset (8 bytes)
+const HINT_FILE_EXTENSION: &str = "hint";
+const HINT_HEADER_SIZE: usize = 4;
+
+pub fn get_hint_file_name(segment_file_name: &str) -> String {
+    Path::new(segment_file_name)
+        .with_extension(HINT_FILE_EXTENSION)
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn write(
+    dir_path: &str,
+    segment_file_name: &str,
+    segment_size: u64,
+    last_seq: u64,
+    index: &InMemoryDb<u64>,
+) -> DbResult<()> {
+    let keys = index.keys();
+    let mut body = vec![];
+    put_u64(&mut body, segment_size);
+    put_u64(&mut body, last_seq);
+    put_u32(&mut body, keys.len() as u32);
+    for key in keys {
+        put_str(&mut body, key);
+        put_u64(&mut body, index.get(key).unwrap());
+    }
+
+    let mut buf = Vec::with_capacity(HINT_HEADER_SIZE + body.len());
+    put_u32(&mut buf, crc32(&body));
+    buf.extend_from_slice(&body);
+    let mut file = File::create(dir_path.to_owned() + &get_hint_file_name(segment_file_name))?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// the highest sequence number and the index stored in the segment's hint file, or `None` if there
+// is no hint file or it doesn't describe the segment as it is on disk, in which case the segment has
+// to be scanned
+pub fn read(
+    dir_path: &str,
+    segment_file_name: &str,
+    segment_size: u64,
+) -> DbResult<Option<(u64, InMemoryDb<u64>)>> {
+    let bytes = match fs::read(dir_path.to_owned() + &get_hint_file_name(segment_file_name)) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.len() < HINT_HEADER_SIZE {
+        return Ok(None);
+    }
+    let (header, body) = bytes.split_at(HINT_HEADER_SIZE);
+    if u32::from_le_bytes(header.try_into().unwrap()) != crc32(body) {
+        return Ok(None);
+    }
+    Ok(decode(body, segment_size).ok().flatten())
+}
+
+pub fn delete(dir_path: &str, segment_file_name: &str) -> DbResult<()> {
+    match fs::remove_file(dir_path.to_owned() + &get_hint_file_name(segment_file_name)) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn decode(body: &[u8], segment_size: u64) -> Result<Option<(u64, InMemoryDb<u64>)>, String> {
+    let mut decoder = Decoder::new(body);
+    if decoder.u64()? != segment_size {
+        // the segment was written to after the hint was
+        return Ok(None);
+    }
+    let last_seq = decoder.u64()?;
+    let mut index = InMemoryDb::new();
+    for _ in 0..decoder.u32()? {
+        let key = decoder.str()?;
+        index.set(&key, &decoder.u64()?);
+    }
+    if !decoder.is_empty() {
+        return Err("trailing bytes after the last entry".to_string());
+    }
+    Ok(Some((last_seq, index)))
+}
@@ -1,17 +1,30 @@
-use segment_file::ReaderFactory;
-
 use self::segment_file::{Factory, File};
 use crate::error::DbResult;
+use crate::kv_file::RecoveryPolicy;
 use crate::{
-    kvdb::KVDb,
-    segmented_files_db::{SegmentCreationPolicy, SegmentedFilesDb},
+    kvdb::{
+        shared_db::ConcurrentKVDb,
+        write_batch::WriteBatch,
+        KVDb, KeyRange,
+        KeyStatus::{Deleted, Present},
+        ScanDirection, ScanIterator,
+    },
+    segmented_files_db::{
+        block_cache::BlockCache, compaction::CompactionStrategy, merging_iterator::MergingIterator,
+        SegmentCreationPolicy, SegmentedFilesDb,
+    },
 };
+use std::iter::empty;
+use std::sync::{Arc, PoisonError, RwLock};
 
-mod segment_file;
+mod hint_file;
+pub mod segment_file;
 
 pub struct SegmentedLogsWithIndicesDb {
     description: String,
-    segmented_files_db: SegmentedFilesDb<File, Factory, ReaderFactory>,
+    // writes take the lock to themselves for as long as an append, or the seal of a full segment,
+    // and reads share it, merges run in the background without it
+    locked_segmented_files_db: RwLock<SegmentedFilesDb<File, Factory>>,
 }
 
 impl KVDb for SegmentedLogsWithIndicesDb {
@@ -19,13 +32,60 @@ impl KVDb for SegmentedLogsWithIndicesDb {
         self.description.clone()
     }
     fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
-        self.segmented_files_db.set(key, value)
+        self.set_shared(key, value)
     }
     fn delete(&mut self, key: &str) -> DbResult<()> {
-        self.segmented_files_db.delete(key)
+        self.delete_shared(key)
+    }
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        self.write_shared(batch)
+    }
+    fn last_seq(&self) -> u64 {
+        self.locked_segmented_files_db
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .last_seq()
     }
     fn get(&mut self, key: &str) -> DbResult<Option<String>> {
-        self.segmented_files_db.get(key)
+        self.get_shared(key)
+    }
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>> {
+        self.scan_shared(range, direction)
+    }
+}
+
+impl ConcurrentKVDb for SegmentedLogsWithIndicesDb {
+    fn set_shared(&self, key: &str, value: &str) -> DbResult<()> {
+        let mut segmented_files_db = self.locked_segmented_files_db.write()?;
+        let seq = segmented_files_db.last_seq() + 1;
+        segmented_files_db.set_status(key, &Present(value.to_owned()), seq)
+    }
+    fn delete_shared(&self, key: &str) -> DbResult<()> {
+        let mut segmented_files_db = self.locked_segmented_files_db.write()?;
+        let seq = segmented_files_db.last_seq() + 1;
+        segmented_files_db.set_status(key, &Deleted, seq)
+    }
+    fn write_shared(&self, batch: &WriteBatch) -> DbResult<()> {
+        let mut segmented_files_db = self.locked_segmented_files_db.write()?;
+        let first_seq = segmented_files_db.last_seq() + 1;
+        segmented_files_db.write(batch, first_seq)
+    }
+    fn get_shared(&self, key: &str) -> DbResult<Option<String>> {
+        self.locked_segmented_files_db.read()?.get(key)
+    }
+    fn scan_shared(
+        &self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>> {
+        if range.is_empty() {
+            return Ok(Box::new(empty()));
+        }
+        let sources = self
+            .locked_segmented_files_db
+            .read()?
+            .scan(&range, direction)?;
+        Ok(Box::new(MergingIterator::new(sources, direction)))
     }
 }
 
@@ -33,21 +93,28 @@ impl SegmentedLogsWithIndicesDb {
     pub fn new(
         dir_path: &str,
         file_size_threshold: u64,
-        merging_threshold: u64,
+        compaction_strategy: Box<dyn CompactionStrategy>,
+        block_cache: Arc<BlockCache>,
+        recovery_policy: RecoveryPolicy,
     ) -> DbResult<SegmentedLogsWithIndicesDb> {
-        let description = format!("Segmented logs with indices DB, with file size threshold of {} bytes and merging threshold of {} files", file_size_threshold, merging_threshold);
+        let description = format!(
+            "Segmented logs with indices DB, with file size threshold of {} bytes, {} and {}",
+            file_size_threshold, block_cache, compaction_strategy
+        );
         Ok(SegmentedLogsWithIndicesDb {
             description,
-            segmented_files_db: SegmentedFilesDb::<File, Factory, ReaderFactory>::new(
+            locked_segmented_files_db: RwLock::new(SegmentedFilesDb::<File, Factory>::new(
                 dir_path,
-                merging_threshold,
+                compaction_strategy,
                 SegmentCreationPolicy::Automatic,
+                recovery_policy,
                 Factory {
                     dir_path: dir_path.to_owned(),
                     file_size_threshold,
+                    block_cache,
+                    recovery_policy,
                 },
-                ReaderFactory {},
-            )?,
+            )?),
         })
     }
 }
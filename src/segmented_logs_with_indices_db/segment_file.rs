@@ -1,155 +1,197 @@
+use super::hint_file;
 use crate::error::DbResult;
-use crate::tmp_file_names::TMP_COMPACTION_FILE_NAME;
 use crate::{
     in_memory_db::InMemoryDb,
-    kv_file::KVFile,
-    kvdb::KeyStatus,
-    segmented_files_db::segment_file::{
-        SegmentFile, SegmentFileFactory, SegmentReader, SegmentReaderFactory,
+    kv_file::{KVFile, KVLine, RecoveryPolicy},
+    kvdb::{write_batch::WriteBatch, KeyRange, KeyStatus, ScanDirection, StatusIterator},
+    segmented_files_db::{
+        block_cache::BlockCache,
+        segment_file::{SegmentFile, SegmentFileFactory},
     },
 };
-use std::mem::replace;
-use KeyStatus::{Deleted, Present};
-
-pub struct Reader<'a> {
-    kvfile: KVFile,
-    index: &'a InMemoryDb<KeyStatus<u64>>,
-}
-
-impl<'a> SegmentReader<'a> for Reader<'a> {
-    fn get_status(&mut self, key: &str) -> DbResult<Option<KeyStatus<String>>> {
-        get_status(self.index, &mut self.kvfile, key)
-    }
-}
+use std::iter::empty;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 pub struct File {
     kvfile: KVFile,
-    index: InMemoryDb<KeyStatus<u64>>,
+    // lookups read the record the index points at through the cache, a record is all there is to
+    // a block here
+    block_cache: Arc<BlockCache>,
+    file_id: u64,
+    // offset of the latest record of each key, tombstones included so that their sequence number
+    // can be read back
+    index: InMemoryDb<u64>,
     file_size_threshold: u64,
+    last_seq: u64,
+    // whether the hint file on disk matches the index
+    has_hint: bool,
 }
 
 impl SegmentFile for File {
-    type Reader<'a> = Reader<'a>;
-
-    fn get_status(&mut self, key: &str) -> DbResult<Option<KeyStatus<String>>> {
-        get_status(&self.index, &mut self.kvfile, key)
+    fn get_status(&self, key: &str) -> DbResult<Option<KeyStatus<String>>> {
+        match self.index.get(key) {
+            Some(offset) => Ok(self.read_line(key, offset)?.map(|line| line.status)),
+            None => Ok(None),
+        }
+    }
+    fn scan(
+        &self,
+        range: &KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<StatusIterator<'static>> {
+        // the index is hashed, so the keys in the range are picked out of all of them and sorted
+        let entries: Vec<(String, u64)> = self
+            .index
+            .range(range, direction)
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect();
+        if entries.is_empty() {
+            return Ok(Box::new(empty()));
+        }
+        // the scan shares the file's handle, so it keeps working after a merge deletes the file
+        let kvfile = KVFile::share(&self.kvfile)?;
+        Ok(Box::new(entries.into_iter().filter_map(
+            move |(key, offset)| {
+                kvfile
+                    .read_at_offset(offset, &key)
+                    .transpose()
+                    .map(|line| line.map(|line| (key, line.status, line.seq)))
+            },
+        )))
     }
     fn ready_to_be_archived(&mut self) -> DbResult<bool> {
         Ok(self.kvfile.size()? > self.file_size_threshold)
     }
-    fn set_status(&mut self, key: &str, status: &KeyStatus<String>) -> DbResult<()> {
-        set_status(&mut self.index, &mut self.kvfile, key, status)
+    fn size(&self) -> DbResult<u64> {
+        self.kvfile.size()
     }
-    fn absorb<'a>(&mut self, other: &mut Self::Reader<'a>) -> DbResult<()> {
-        for key in other.index.keys() {
-            if self.index.get(key).is_none() {
-                self.set_status(key.as_str(), &other.get_status(key)?.unwrap())?;
-            }
+    fn last_modified(&self) -> DbResult<SystemTime> {
+        self.kvfile.last_modified()
+    }
+    fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+    fn seal(&mut self) -> DbResult<()> {
+        if !self.has_hint {
+            self.write_hint()?;
         }
         Ok(())
     }
-    fn rename(&mut self, new_file_name: &str) -> DbResult<()> {
-        self.kvfile.rename(new_file_name)
+    fn set_status(&mut self, key: &str, status: &KeyStatus<String>, seq: u64) -> DbResult<()> {
+        self.has_hint = false;
+        let offset = self.kvfile.append_line(key, status, seq)?;
+        self.index.set(key, &offset);
+        self.last_seq = self.last_seq.max(seq);
+        Ok(())
     }
-    fn compact(&mut self) -> DbResult<()> {
-        let mut compact_kvfile = KVFile::new(&self.kvfile.dir_path, TMP_COMPACTION_FILE_NAME)?;
-        let mut compact_index = InMemoryDb::new();
-        for key in self.index.keys() {
-            // skip deleted entries
-            if let Some(Present(value)) = get_status(&self.index, &mut self.kvfile, key)? {
-                set_status(
-                    &mut compact_index,
-                    &mut compact_kvfile,
-                    key,
-                    &Present(value),
-                )?;
-            }
+    fn write_batch(&mut self, batch: &WriteBatch, first_seq: u64) -> DbResult<()> {
+        self.has_hint = false;
+        let offset = self.kvfile.append_batch(batch, first_seq)?;
+        for (key, _) in batch.entries() {
+            self.index.set(key, &offset);
         }
-
-        self.index = compact_index;
-        let mut old_kvfile = replace(&mut self.kvfile, compact_kvfile);
-        let file_name = (&old_kvfile.file_name).to_string();
-        old_kvfile.delete()?;
-        self.kvfile.rename(&file_name)?;
-
+        self.last_seq = self.last_seq.max(first_seq + batch.len() as u64 - 1);
         Ok(())
     }
     fn delete(mut self) -> DbResult<()> {
+        hint_file::delete(&self.kvfile.dir_path, &self.kvfile.file_name)?;
         self.kvfile.delete()
     }
 }
 
-pub struct ReaderFactory {}
+impl File {
+    // the line the record at the offset holds for the key, read through the cache
+    fn read_line(&self, key: &str, offset: u64) -> DbResult<Option<KVLine>> {
+        let record = self.block_cache.get_or_read(self.file_id, offset, || {
+            self.kvfile.read_record_bytes(offset)
+        })?;
+        // a batch record holds other keys too, its last entry for the key is the one
+        let mut found = None;
+        for line_result in self.kvfile.iter_block(offset, record)? {
+            let line = line_result?;
+            if line.key == key {
+                found = Some(line);
+            }
+        }
+        Ok(found)
+    }
+    fn write_hint(&mut self) -> DbResult<()> {
+        // the hint must not describe records that could still be lost
+        self.kvfile.sync()?;
+        let segment_size = self.kvfile.size()?;
+        hint_file::write(
+            &self.kvfile.dir_path,
+            &self.kvfile.file_name,
+            segment_size,
+            self.last_seq,
+            &self.index,
+        )?;
+        self.has_hint = true;
+        Ok(())
+    }
+}
 
-impl SegmentReaderFactory<File> for ReaderFactory {
-    fn new<'a>(&self, file: &'a File) -> DbResult<<File as SegmentFile>::Reader<'a>> {
-        return Ok(Reader {
-            kvfile: KVFile::copy(&file.kvfile)?,
-            index: &file.index,
-        });
+impl Drop for File {
+    fn drop(&mut self) {
+        // the id isn't handed out again, the file's records would only take up room
+        let _ = self.block_cache.erase_file(self.file_id);
     }
 }
 
 pub struct Factory {
     pub dir_path: String,
     pub file_size_threshold: u64,
+    pub block_cache: Arc<BlockCache>,
+    pub recovery_policy: RecoveryPolicy,
 }
 
 impl SegmentFileFactory<File> for Factory {
     fn new(&self, file_name: &str) -> DbResult<File> {
         let kvfile = KVFile::new(&self.dir_path, file_name)?;
         let index = InMemoryDb::new();
+        // left behind by a segment that used to have this name
+        hint_file::delete(&self.dir_path, file_name)?;
         Ok(File {
             kvfile,
+            block_cache: Arc::clone(&self.block_cache),
+            file_id: self.block_cache.new_file_id()?,
             index,
             file_size_threshold: self.file_size_threshold,
+            last_seq: 0,
+            has_hint: false,
         })
     }
     fn from_disk(&self, file_name: &str) -> DbResult<File> {
         let mut kvfile = KVFile::new(&self.dir_path, file_name)?;
-        let mut index = InMemoryDb::new();
-        for line_result in kvfile.iter()? {
-            let line = line_result?;
-            match line.status {
-                Present(_) => index.set(&line.key, &Present(line.offset)),
-                Deleted => index.set(&line.key, &Deleted),
-            }
+        if let Some((last_seq, index)) = hint_file::read(&self.dir_path, file_name, kvfile.size()?)?
+        {
+            return Ok(File {
+                kvfile,
+                block_cache: Arc::clone(&self.block_cache),
+                file_id: self.block_cache.new_file_id()?,
+                index,
+                file_size_threshold: self.file_size_threshold,
+                last_seq,
+                has_hint: true,
+            });
         }
+
+        let mut index = InMemoryDb::new();
+        let mut last_seq = 0;
+        kvfile.recover(self.recovery_policy, &mut |line| {
+            index.set(&line.key, &line.offset);
+            last_seq = last_seq.max(line.seq);
+            Ok(())
+        })?;
         Ok(File {
             kvfile,
+            block_cache: Arc::clone(&self.block_cache),
+            file_id: self.block_cache.new_file_id()?,
             index,
             file_size_threshold: self.file_size_threshold,
+            last_seq,
+            has_hint: false,
         })
     }
 }
-
-fn get_status(
-    index: &InMemoryDb<KeyStatus<u64>>,
-    kvfile: &mut KVFile,
-    key: &str,
-) -> DbResult<Option<KeyStatus<String>>> {
-    match index.get(key) {
-        Some(Present(offset)) => kvfile
-            .read_at_offset(offset)
-            .and_then(|maybe_value| Ok(maybe_value.and_then(|value| Some(Present(value))))),
-        Some(Deleted) => Ok(Some(Deleted)),
-        None => Ok(None),
-    }
-}
-
-fn set_status(
-    index: &mut InMemoryDb<KeyStatus<u64>>,
-    kvfile: &mut KVFile,
-    key: &str,
-    status: &KeyStatus<String>,
-) -> DbResult<()> {
-    kvfile.append_line(key, &status).and_then(|offset| {
-        Ok(index.set(
-            key,
-            &match status {
-                Present(_) => Present(offset),
-                Deleted => Deleted,
-            },
-        ))
-    })
-}
@@ -0,0 +1,101 @@
+use crate::encoding::{put_str, put_u32, put_u64, Decoder};
+
+// segment layout: data blocks, filter block, index block, footer
+// a data block holds whole records, each block is written once it grows past the block size
+// the filter block is a bloom filter over every key in the segment, empty if filters are disabled
+// the index block has one entry per data block, with the last key in it and where it lives
+pub const FORMAT_VERSION: u32 = 2;
+pub const FOOTER_SIZE: u64 = 52;
+const MAGIC: u64 = 0x7373_7461_626c_6521;
+
+#[derive(Clone, Debug)]
+pub struct BlockHandle {
+    pub last_key: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+pub struct MetaBlockHandle {
+    pub offset: u64,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+impl MetaBlockHandle {
+    pub fn end(&self) -> Option<u64> {
+        self.offset.checked_add(self.size)
+    }
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_u64(buf, self.offset);
+        put_u64(buf, self.size);
+        put_u32(buf, self.checksum);
+    }
+    fn decode(decoder: &mut Decoder) -> Result<Self, String> {
+        Ok(MetaBlockHandle {
+            offset: decoder.u64()?,
+            size: decoder.u64()?,
+            checksum: decoder.u32()?,
+        })
+    }
+}
+
+// footer layout: filter block handle (20 bytes), index block handle (20 bytes),
+// format version (4 bytes), magic number (8 bytes)
+// a block handle is its offset (8 bytes), size (8 bytes) and checksum (4 bytes)
+pub struct Footer {
+    pub filter: MetaBlockHandle,
+    pub index: MetaBlockHandle,
+}
+
+impl Footer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_SIZE as usize);
+        self.filter.encode(&mut buf);
+        self.index.encode(&mut buf);
+        put_u32(&mut buf, FORMAT_VERSION);
+        put_u64(&mut buf, MAGIC);
+        buf
+    }
+    // returns None if the bytes don't end with the magic number, i.e. the segment was never sealed
+    pub fn decode(bytes: &[u8]) -> Result<Option<Footer>, String> {
+        let mut decoder = Decoder::new(bytes);
+        let filter = MetaBlockHandle::decode(&mut decoder)?;
+        let index = MetaBlockHandle::decode(&mut decoder)?;
+        let version = decoder.u32()?;
+        if decoder.u64()? != MAGIC {
+            return Ok(None);
+        }
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported segment format version {}", version));
+        }
+        Ok(Some(Footer { filter, index }))
+    }
+}
+
+pub fn encode_index(index: &[BlockHandle]) -> Vec<u8> {
+    let mut buf = vec![];
+    put_u32(&mut buf, index.len() as u32);
+    for handle in index {
+        put_str(&mut buf, &handle.last_key);
+        put_u64(&mut buf, handle.offset);
+        put_u64(&mut buf, handle.size);
+    }
+    buf
+}
+
+pub fn decode_index(bytes: &[u8]) -> Result<Vec<BlockHandle>, String> {
+    let mut decoder = Decoder::new(bytes);
+    let num_blocks = decoder.u32()?;
+    let mut index = Vec::with_capacity(num_blocks as usize);
+    for _ in 0..num_blocks {
+        index.push(BlockHandle {
+            last_key: decoder.str()?,
+            offset: decoder.u64()?,
+            size: decoder.u64()?,
+        });
+    }
+    if !decoder.is_empty() {
+        return Err("trailing bytes after the last index entry".to_string());
+    }
+    Ok(index)
+}
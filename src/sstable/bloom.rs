@@ -0,0 +1,71 @@
+use crate::encoding::Decoder;
+
+const MIN_NUM_BITS: u64 = 64;
+const MAX_NUM_PROBES: u32 = 30;
+
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u32,
+}
+
+impl BloomFilter {
+    pub fn build(key_hashes: &[u64], bits_per_key: u64) -> Self {
+        let num_bits = (key_hashes.len() as u64 * bits_per_key).max(MIN_NUM_BITS);
+        // ln(2) * bits per key probes minimise the false positive rate
+        let num_probes = ((bits_per_key as f64 * 0.69).round() as u32).clamp(1, MAX_NUM_PROBES);
+        let mut filter = BloomFilter {
+            bits: vec![0; num_bits.div_ceil(8) as usize],
+            num_probes,
+        };
+        for &hash in key_hashes {
+            for bit in filter.probes(hash) {
+                filter.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.probes(hash_key(key))
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+    // filter layout: bit array, number of probes (1 byte)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bits.clone();
+        buf.push(self.num_probes as u8);
+        buf
+    }
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut decoder = Decoder::new(bytes);
+        let bits = decoder.bytes(bytes.len().saturating_sub(1))?.to_vec();
+        let num_probes = decoder.bytes(1)?[0] as u32;
+        if bits.is_empty() || num_probes == 0 || num_probes > MAX_NUM_PROBES {
+            return Err(format!(
+                "bloom filter of {} bytes with {} probes is ill-formed",
+                bits.len(),
+                num_probes
+            ));
+        }
+        Ok(BloomFilter { bits, num_probes })
+    }
+    // double hashing, the probes are h1, h1 + h2, h1 + 2 * h2 and so on
+    fn probes(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let num_bits = self.bits.len() as u64 * 8;
+        let h1 = hash & 0xFFFF_FFFF;
+        let h2 = hash >> 32;
+        (0..self.num_probes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+// FNV-1a followed by the splitmix64 finalizer, so both halves of the hash are well mixed
+pub fn hash_key(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key.as_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -0,0 +1,234 @@
+use std::{
+    cmp::Reverse,
+    mem::size_of,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicPtr, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rand::Rng;
+
+use crate::error::DbResult;
+use crate::kvdb::{KeyRange, KeyStatus, ScanDirection};
+
+// A skiplist of every write since the memtable was created. Writers link their node in with a
+// compare-and-swap at each level and readers follow the links without taking any lock, so any
+// number of both can work on it at once. Nodes are never unlinked: setting or deleting a key that's
+// already there adds a newer version of it in front of the older ones, and every node is freed
+// together when the memtable is dropped.
+const MAX_HEIGHT: usize = 12;
+// each level links about one in this many of the nodes of the level below
+const BRANCHING: u32 = 4;
+
+struct Node {
+    key: String,
+    // the sequence number of the write, orders the versions of a key, the newest first
+    seq: u64,
+    status: KeyStatus<String>,
+    next: Vec<AtomicPtr<Node>>,
+}
+
+impl Node {
+    fn new(key: String, seq: u64, status: KeyStatus<String>, height: usize) -> *mut Node {
+        Box::into_raw(Box::new(Node {
+            key,
+            seq,
+            status,
+            next: (0..height).map(|_| AtomicPtr::new(null_mut())).collect(),
+        }))
+    }
+    // the memory the node takes, roughly
+    fn size(&self) -> usize {
+        let value_len = match &self.status {
+            KeyStatus::Present(value) => value.len(),
+            KeyStatus::Deleted => 0,
+        };
+        size_of::<Node>()
+            + self.next.len() * size_of::<AtomicPtr<Node>>()
+            + self.key.len()
+            + value_len
+    }
+    fn next(&self, level: usize) -> *mut Node {
+        self.next[level].load(Ordering::Acquire)
+    }
+    // whether the node sorts before the version `seq` of `key`
+    fn is_before(&self, key: &str, seq: u64) -> bool {
+        (self.key.as_str(), Reverse(self.seq)) < (key, Reverse(seq))
+    }
+}
+
+pub struct Memtable {
+    // sorts before every node, its key and status are never read
+    head: *mut Node,
+    // bytes taken by the nodes, keys and values included
+    approximate_size: AtomicUsize,
+}
+
+// nodes are only reached through the memtable, they aren't changed once they're linked in other
+// than through their atomic links, and they live until the memtable is dropped
+unsafe impl Send for Memtable {}
+unsafe impl Sync for Memtable {}
+
+impl Memtable {
+    pub fn new() -> Self {
+        Memtable {
+            head: Node::new(String::new(), 0, KeyStatus::Deleted, MAX_HEIGHT),
+            approximate_size: AtomicUsize::new(0),
+        }
+    }
+    pub fn approximate_size(&self) -> usize {
+        self.approximate_size.load(Ordering::Relaxed)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.approximate_size() == 0
+    }
+    // returns the bytes the write added to the memtable
+    pub fn insert(&self, key: &str, seq: u64, status: KeyStatus<String>) -> usize {
+        let height = random_height();
+        let node = Node::new(key.to_owned(), seq, status, height);
+        let node_size = unsafe { &*node }.size();
+
+        let mut preds = [self.head; MAX_HEIGHT];
+        let mut succs = [null_mut(); MAX_HEIGHT];
+        let mut pred = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            (pred, succs[level]) = find_splice(pred, level, key, seq);
+            preds[level] = pred;
+        }
+        // linked bottom up, so a node that can be reached at some level can be reached at all the
+        // ones below it
+        for level in 0..height {
+            loop {
+                unsafe { &*node }.next[level].store(succs[level], Ordering::Relaxed);
+                let linked = unsafe { &*preds[level] }.next[level].compare_exchange(
+                    succs[level],
+                    node,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                );
+                if linked.is_ok() {
+                    break;
+                }
+                // another writer linked a node in between, which can only have gone after the
+                // predecessor
+                (preds[level], succs[level]) = find_splice(preds[level], level, key, seq);
+            }
+        }
+        self.approximate_size
+            .fetch_add(node_size, Ordering::Relaxed);
+        node_size
+    }
+    // the status of the latest write to the key as of `seq`, `None` if it wasn't written to by
+    // then
+    pub fn get_at(&self, key: &str, seq: u64) -> Option<&KeyStatus<String>> {
+        let node = self.find_first(|node| node.is_before(key, seq));
+        if node.is_null() || unsafe { &*node }.key != key {
+            return None;
+        }
+        Some(&unsafe { &*node }.status)
+    }
+    // every version of the keys in the range, ordered by key in the given direction, and the
+    // versions of a key from the newest on when going forward, the scan holds on to the memtable
+    // so it can outlive the caller's reference to it
+    pub fn scan(self: &Arc<Self>, range: &KeyRange, direction: ScanDirection) -> MemtableIterator {
+        let node = match direction {
+            ScanDirection::Forward => self.find_first(|node| range.is_before_start(&node.key)),
+            ScanDirection::Reverse => self.find_last(|node| !range.is_after_end(&node.key)),
+        };
+        MemtableIterator {
+            memtable: Arc::clone(self),
+            node,
+            range: range.clone(),
+            direction,
+        }
+    }
+    // the first node that `is_before` doesn't hold for, null if there's none, `is_before` has to
+    // hold for every node up to some point and for none after it
+    fn find_first(&self, is_before: impl Fn(&Node) -> bool) -> *mut Node {
+        unsafe { &*self.find_last_or_head(is_before) }.next(0)
+    }
+    // the last node that `is_before` holds for, null if there's none
+    fn find_last(&self, is_before: impl Fn(&Node) -> bool) -> *mut Node {
+        match self.find_last_or_head(is_before) {
+            node if node == self.head => null_mut(),
+            node => node,
+        }
+    }
+    fn find_last_or_head(&self, is_before: impl Fn(&Node) -> bool) -> *mut Node {
+        let mut pred = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            loop {
+                let next = unsafe { &*pred }.next(level);
+                if next.is_null() || !is_before(unsafe { &*next }) {
+                    break;
+                }
+                pred = next;
+            }
+        }
+        pred
+    }
+}
+
+impl Drop for Memtable {
+    fn drop(&mut self) {
+        // every node is linked at level 0
+        let mut node = self.head;
+        while !node.is_null() {
+            let next = unsafe { &*node }.next(0);
+            drop(unsafe { Box::from_raw(node) });
+            node = next;
+        }
+    }
+}
+
+pub struct MemtableIterator {
+    memtable: Arc<Memtable>,
+    // the next version, null once the scan is done
+    node: *mut Node,
+    range: KeyRange,
+    direction: ScanDirection,
+}
+
+impl Iterator for MemtableIterator {
+    type Item = DbResult<(String, KeyStatus<String>, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.node.is_null() {
+            return None;
+        }
+        let node = unsafe { &*self.node };
+        if self.range.is_exhausted_at(&node.key, self.direction) {
+            self.node = null_mut();
+            return None;
+        }
+        self.node = match self.direction {
+            ScanDirection::Forward => node.next(0),
+            ScanDirection::Reverse => self
+                .memtable
+                .find_last(|other| other.is_before(&node.key, node.seq)),
+        };
+        Some(Ok((node.key.clone(), node.status.clone(), node.seq)))
+    }
+}
+
+fn find_splice(start: *mut Node, level: usize, key: &str, seq: u64) -> (*mut Node, *mut Node) {
+    let mut pred = start;
+    loop {
+        let next = unsafe { &*pred }.next(level);
+        if next.is_null() || !unsafe { &*next }.is_before(key, seq) {
+            return (pred, next);
+        }
+        pred = next;
+    }
+}
+
+fn random_height() -> usize {
+    let mut rng = rand::thread_rng();
+    let mut height = 1;
+    while height < MAX_HEIGHT && rng.gen_ratio(1, BRANCHING) {
+        height += 1;
+    }
+    height
+}
@@ -1,40 +1,88 @@
 use std::{
-    collections::BTreeMap,
-    mem::swap,
-    sync::{Arc, Mutex, RwLock},
-    thread::{spawn, JoinHandle},
+    collections::VecDeque,
+    fs::create_dir_all,
+    iter::empty,
+    mem::replace,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread::{sleep, spawn, JoinHandle},
+    time::Instant,
 };
 
-use self::segment_file::{Factory, File, ReaderFactory};
+use self::memtable::Memtable;
+use self::segment_file::{Factory, File};
+use self::write_buffer::{WriteBufferBudget, WriteBufferPolicy, WriteStallStats};
 use crate::error::DbResult;
-use crate::tmp_file_names::TMP_MEMTABLE_BACKUP_SWAP_FILE_NAME;
 use crate::{
     check_key_status,
     error::Error,
-    kv_file::KVFile,
+    kv_file::{KVFile, RecoveryPolicy},
     kvdb::{
-        KVDb,
-        KeyStatus::{self, Deleted, Present},
+        shared_db::ConcurrentKVDb,
+        write_batch::WriteBatch,
+        KVDb, KeyRange,
+        KeyStatus::{Deleted, Present},
+        ScanDirection, ScanIterator, Snapshot, StatusIterator,
+    },
+    segmented_files_db::{
+        block_cache::BlockCache,
+        compaction::CompactionStrategy,
+        merging_iterator::MergingIterator,
+        snapshots::{visible_versions, LiveSnapshots},
+        SegmentCreationPolicy, SegmentedFilesDb,
     },
-    segmented_files_db::{SegmentCreationPolicy, SegmentedFilesDb},
-    utils::is_thread_running,
+    utils::{process_dir_contents, sync_dir},
 };
 
 pub const MEMTABLE_BACKUP_FILE_NAME: &str = "memtable_backup.txt";
+// the backup of the one memtable that could wait to be flushed before there was a queue of them
 pub const TMP_MEMTABLE_BACKUP_FILE_NAME: &str = "tmp_memtable_backup.txt";
+const IMMUTABLE_MEMTABLE_BACKUP_FILE_PREFIX: &str = "immutable_memtable_backup_";
 
+mod block;
+mod bloom;
+pub mod memtable;
 mod segment_file;
+pub mod write_buffer;
 
-type Memtable = BTreeMap<String, KeyStatus<String>>;
+struct ImmutableMemtable {
+    memtable: Arc<Memtable>,
+    backup: KVFile,
+}
+
+struct ImmutableMemtables {
+    // from the oldest to the newest, each is flushed to its own segment in this order
+    queue: VecDeque<ImmutableMemtable>,
+    // whether a thread is flushing the queue, it keeps going until the queue is empty
+    is_flushing: bool,
+    // the error the last flush stopped at, the queue can't be flushed past it and every write
+    // fails with it until the SSTable is reopened, which flushes the queue again from the backups
+    flush_error: Option<Error>,
+}
 
 pub struct SSTable {
     description: String,
-    memtable_size_threshold: usize,
-    memtable: Memtable,
+    write_buffer_policy: WriteBufferPolicy,
+    // writes take it one at a time and reads never do, so a write that's slowed down or stalled
+    // waiting for a flush only holds up the writes after it
+    locked_writer: Mutex<SSTableWriter>,
+    // the lock is only held to swap a full memtable for an empty one, reads clone it and let go
+    locked_memtable: RwLock<Arc<Memtable>>,
+    // the lock is only held to push and pop memtables, reads and the flush work on them without it
+    locked_immutable_memtables: Arc<Mutex<ImmutableMemtables>>,
+    // notified each time a memtable is flushed and popped from the queue
+    memtable_flushed: Arc<Condvar>,
+    locked_segmented_files_db: Arc<RwLock<SegmentedFilesDb<File, Factory>>>,
+    // the last write that's done, reads skip the writes after it the memtable may still be taking
+    last_seq: AtomicU64,
+}
+
+struct SSTableWriter {
+    write_stall_stats: WriteStallStats,
     memtable_backup: KVFile,
-    locked_tmp_memtable: Arc<RwLock<Memtable>>,
-    locked_tmp_memtable_backup: Arc<RwLock<KVFile>>,
-    locked_segmented_files_db: Arc<Mutex<SegmentedFilesDb<File, Factory, ReaderFactory>>>,
+    next_immutable_memtable_backup_number: usize,
     flush_memtable_thread_join_handle: Option<JoinHandle<()>>,
 }
 
@@ -43,38 +91,171 @@ impl KVDb for SSTable {
         self.description.clone()
     }
     fn set(&mut self, key: &str, value: &str) -> DbResult<()> {
-        self.flush_memtable_if_big().and_then(|_| {
-            let status = Present(value.to_string());
-            if let Err(e) = self.memtable_backup.append_line(key, &status) {
-                println!("error in writing to memtable backup: {}", e);
-            }
-            self.memtable.insert(key.to_string(), status);
-            Ok(())
-        })
+        self.set_shared(key, value)
     }
     fn delete(&mut self, key: &str) -> DbResult<()> {
-        self.flush_memtable_if_big().and_then(|_| {
-            if let Err(e) = self.memtable_backup.append_line(key, &Deleted) {
-                println!("error in writing to memtable backup: {}", e);
-            }
-            self.memtable.insert(key.to_string(), Deleted);
-            Ok(())
-        })
+        self.delete_shared(key)
+    }
+    fn write(&mut self, batch: &WriteBatch) -> DbResult<()> {
+        self.write_shared(batch)
+    }
+    fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Acquire)
     }
     fn get(&mut self, key: &str) -> DbResult<Option<String>> {
-        check_key_status!(self.memtable.get(key));
-        {
-            let tmp_memtable = self.locked_tmp_memtable.read()?;
-            check_key_status!(tmp_memtable.get(key));
+        self.get_shared(key)
+    }
+    fn scan(&mut self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'_>> {
+        self.scan_shared(range, direction)
+    }
+    // the memtables and the segments keep every version of a key, flushes and merges only drop
+    // the ones no live snapshot reads
+    fn snapshot(&mut self) -> DbResult<Box<dyn Snapshot + Send>> {
+        let live_snapshots = Arc::clone(self.locked_segmented_files_db.read()?.live_snapshots());
+        let seq = self.last_seq();
+        live_snapshots.register(seq)?;
+        Ok(Box::new(SSTableSnapshot {
+            seq,
+            memtable: self.memtable()?,
+            locked_immutable_memtables: Arc::clone(&self.locked_immutable_memtables),
+            locked_segmented_files_db: Arc::clone(&self.locked_segmented_files_db),
+            live_snapshots,
+        }))
+    }
+}
+
+impl ConcurrentKVDb for SSTable {
+    fn set_shared(&self, key: &str, value: &str) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        let memtable = self.make_room_for_write(&mut writer)?;
+        let status = Present(value.to_string());
+        let seq = self.last_seq() + 1;
+        // the memtable only takes writes that made it to the backup, so that a failed append isn't
+        // lost on a restart after it was read
+        writer.memtable_backup.append_line(key, &status, seq)?;
+        let size = memtable.insert(key, seq, status);
+        reserve_write_buffer(&self.write_buffer_policy.budget, size);
+        self.last_seq.store(seq, Ordering::Release);
+        Ok(())
+    }
+    fn delete_shared(&self, key: &str) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        let memtable = self.make_room_for_write(&mut writer)?;
+        let seq = self.last_seq() + 1;
+        writer.memtable_backup.append_line(key, &Deleted, seq)?;
+        let size = memtable.insert(key, seq, Deleted);
+        reserve_write_buffer(&self.write_buffer_policy.budget, size);
+        self.last_seq.store(seq, Ordering::Release);
+        Ok(())
+    }
+    // the batch is a single record in the backup and all goes into the same memtable, so it's
+    // flushed to a single segment too, and reads only see it once its last entry is in
+    fn write_shared(&self, batch: &WriteBatch) -> DbResult<()> {
+        let mut writer = self.locked_writer.lock()?;
+        let memtable = self.make_room_for_write(&mut writer)?;
+        let mut seq = self.last_seq();
+        writer.memtable_backup.append_batch(batch, seq + 1)?;
+        for (key, status) in batch.entries() {
+            seq += 1;
+            let size = memtable.insert(key, seq, status.clone());
+            reserve_write_buffer(&self.write_buffer_policy.budget, size);
         }
-        self.locked_segmented_files_db.lock()?.get(key)
+        self.last_seq.store(seq, Ordering::Release);
+        Ok(())
+    }
+    // the memtable is read as of the last write that's done, the immutable memtables and the
+    // segments only hold writes that are done
+    fn get_shared(&self, key: &str) -> DbResult<Option<String>> {
+        let seq = self.last_seq();
+        let memtable = self.memtable()?;
+        get_at(
+            &memtable,
+            seq,
+            &self.locked_immutable_memtables,
+            &self.locked_segmented_files_db,
+            key,
+            u64::MAX,
+        )
+    }
+    fn scan_shared(
+        &self,
+        range: KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<ScanIterator<'static>> {
+        let seq = self.last_seq();
+        scan_at(
+            &self.memtable()?,
+            seq,
+            &self.locked_immutable_memtables,
+            &self.locked_segmented_files_db,
+            range,
+            direction,
+            u64::MAX,
+        )
+    }
+}
+
+// Reads the same memtables and segments as the SSTable, skipping the versions written after the
+// snapshot was taken.
+pub struct SSTableSnapshot {
+    seq: u64,
+    // the memtable written to when the snapshot was taken, the ones after it only hold later
+    // writes
+    memtable: Arc<Memtable>,
+    locked_immutable_memtables: Arc<Mutex<ImmutableMemtables>>,
+    locked_segmented_files_db: Arc<RwLock<SegmentedFilesDb<File, Factory>>>,
+    live_snapshots: Arc<LiveSnapshots>,
+}
+
+impl Snapshot for SSTableSnapshot {
+    fn seq(&self) -> u64 {
+        self.seq
+    }
+    fn get(&self, key: &str) -> DbResult<Option<String>> {
+        get_at(
+            &self.memtable,
+            self.seq,
+            &self.locked_immutable_memtables,
+            &self.locked_segmented_files_db,
+            key,
+            self.seq,
+        )
+    }
+    fn scan(&self, range: KeyRange, direction: ScanDirection) -> DbResult<ScanIterator<'static>> {
+        scan_at(
+            &self.memtable,
+            self.seq,
+            &self.locked_immutable_memtables,
+            &self.locked_segmented_files_db,
+            range,
+            direction,
+            self.seq,
+        )
+    }
+}
+
+impl Drop for SSTableSnapshot {
+    fn drop(&mut self) {
+        let _ = self.live_snapshots.release(self.seq);
     }
 }
 
 impl Drop for SSTable {
     fn drop(&mut self) {
-        if let Some(handle) = self.flush_memtable_thread_join_handle.take() {
-            let _ = handle.join();
+        if let Ok(writer) = self.locked_writer.get_mut() {
+            if let Some(handle) = writer.flush_memtable_thread_join_handle.take() {
+                let _ = handle.join();
+            }
+        }
+        // the flush emptied the queue, only the memtable is left holding on to the budget
+        if let Ok(memtable) = self.locked_memtable.get_mut() {
+            release_write_buffer(
+                &self.write_buffer_policy.budget,
+                memtable.approximate_size(),
+            );
+        }
+        if let Some(budget) = &self.write_buffer_policy.budget {
+            budget.remove_sharer();
         }
     }
 }
@@ -82,136 +263,361 @@ impl Drop for SSTable {
 impl SSTable {
     pub fn new(
         dir_path: &str,
-        merging_threshold: u64,
-        sparsity: u64,
-        memtable_size_threshold: usize,
+        compaction_strategy: Box<dyn CompactionStrategy>,
+        block_size: u64,
+        bloom_bits_per_key: u64,
+        block_cache: Arc<BlockCache>,
+        write_buffer_policy: WriteBufferPolicy,
+        recovery_policy: RecoveryPolicy,
     ) -> DbResult<Self> {
-        let description = format!("SS Table with merging threshold of {} files, sparsity of {} bytes and memtable size threshold of {} keys",
-            merging_threshold, sparsity, memtable_size_threshold
+        if write_buffer_policy.max_immutable_memtables == 0 {
+            return Err(Error::InvalidInput(
+                "at least one immutable memtable has to be able to wait to be flushed".to_owned(),
+            ));
+        }
+        let description = format!(
+            "SS Table with {}, block size of {} bytes, bloom filter of {} bits per key, {} and {}",
+            compaction_strategy, block_size, bloom_bits_per_key, block_cache, write_buffer_policy
         );
-        let (memtable, memtable_backup) =
-            Self::recover_memtable_from_backup(dir_path, MEMTABLE_BACKUP_FILE_NAME)?;
-        let (tmp_memtable, tmp_memtable_backup) =
-            Self::recover_memtable_from_backup(dir_path, TMP_MEMTABLE_BACKUP_FILE_NAME)?;
-        Ok(SSTable {
-            description,
-            memtable_size_threshold,
-            memtable,
-            memtable_backup,
-            locked_tmp_memtable: Arc::new(RwLock::new(tmp_memtable)),
-            locked_tmp_memtable_backup: Arc::new(RwLock::new(tmp_memtable_backup)),
-            locked_segmented_files_db: Arc::new(Mutex::new(SegmentedFilesDb::<
-                File,
-                Factory,
-                ReaderFactory,
-            >::new(
-                dir_path,
-                merging_threshold,
-                SegmentCreationPolicy::Triggered,
-                Factory {
-                    dir_path: dir_path.to_owned(),
-                    sparsity,
-                },
-                ReaderFactory {},
-            )?)),
-            flush_memtable_thread_join_handle: None,
-        })
-    }
-    fn flush_memtable_if_big(&mut self) -> DbResult<()> {
-        if self.memtable.len() >= self.memtable_size_threshold {
-            let moved = self.try_moving_data_to_tmp_memtable()?;
-            if moved {
-                self.flush_tmp_memtable_in_background();
+        create_dir_all(dir_path)?;
+        let (memtable, memtable_backup, mut last_seq) = Self::recover_memtable_from_backup(
+            dir_path,
+            MEMTABLE_BACKUP_FILE_NAME,
+            recovery_policy,
+        )?;
+        // the memtables that were waiting to be flushed, or that were flushed but didn't get to
+        // delete their backup, flushing them again is harmless either way
+        let mut backup_numbers = vec![];
+        process_dir_contents(dir_path, &mut |path| {
+            let backup_number = path
+                .file_stem()
+                .and_then(|file_stem| file_stem.to_str())
+                .and_then(|file_stem| file_stem.strip_prefix(IMMUTABLE_MEMTABLE_BACKUP_FILE_PREFIX))
+                .and_then(|backup_number| backup_number.parse::<usize>().ok());
+            backup_numbers.extend(backup_number);
+            Ok(())
+        })?;
+        backup_numbers.sort();
+        let mut queue = VecDeque::new();
+        let backup_file_names = [TMP_MEMTABLE_BACKUP_FILE_NAME.to_owned()]
+            .into_iter()
+            .chain(
+                backup_numbers
+                    .iter()
+                    .map(|&n| get_immutable_memtable_backup_file_name(n)),
+            );
+        for backup_file_name in backup_file_names {
+            let (memtable, mut backup, backup_last_seq) =
+                Self::recover_memtable_from_backup(dir_path, &backup_file_name, recovery_policy)?;
+            last_seq = last_seq.max(backup_last_seq);
+            if memtable.is_empty() {
+                backup.delete()?;
+                continue;
             }
+            queue.push_back(ImmutableMemtable {
+                memtable: Arc::new(memtable),
+                backup,
+            });
         }
-        Ok(())
+        let is_flushing = !queue.is_empty();
+        for memtable in [&memtable]
+            .into_iter()
+            .chain(queue.iter().map(|immutable| &*immutable.memtable))
+        {
+            reserve_write_buffer(&write_buffer_policy.budget, memtable.approximate_size());
+        }
+
+        let segmented_files_db = SegmentedFilesDb::<File, Factory>::new(
+            dir_path,
+            compaction_strategy,
+            SegmentCreationPolicy::Triggered,
+            recovery_policy,
+            Factory {
+                dir_path: dir_path.to_owned(),
+                block_size,
+                bloom_bits_per_key,
+                block_cache,
+                recovery_policy,
+            },
+        )?;
+        let last_seq = last_seq.max(segmented_files_db.last_seq());
+
+        // given up when the SSTable is dropped
+        if let Some(budget) = &write_buffer_policy.budget {
+            budget.add_sharer();
+        }
+        let sstable = SSTable {
+            description,
+            write_buffer_policy,
+            locked_writer: Mutex::new(SSTableWriter {
+                write_stall_stats: WriteStallStats::default(),
+                memtable_backup,
+                next_immutable_memtable_backup_number: backup_numbers.last().map_or(0, |n| n + 1),
+                flush_memtable_thread_join_handle: None,
+            }),
+            locked_memtable: RwLock::new(Arc::new(memtable)),
+            locked_immutable_memtables: Arc::new(Mutex::new(ImmutableMemtables {
+                queue,
+                is_flushing,
+                flush_error: None,
+            })),
+            memtable_flushed: Arc::new(Condvar::new()),
+            locked_segmented_files_db: Arc::new(RwLock::new(segmented_files_db)),
+            last_seq: AtomicU64::new(last_seq),
+        };
+        if is_flushing {
+            sstable.flush_immutable_memtables_in_background(&mut *sstable.locked_writer.lock()?);
+        }
+        Ok(sstable)
+    }
+    pub fn write_stall_stats(&self) -> DbResult<WriteStallStats> {
+        Ok(self.locked_writer.lock()?.write_stall_stats)
+    }
+    fn memtable(&self) -> DbResult<Arc<Memtable>> {
+        Ok(Arc::clone(&*self.locked_memtable.read()?))
     }
-    fn try_moving_data_to_tmp_memtable(&mut self) -> DbResult<bool> {
-        if is_thread_running(&self.flush_memtable_thread_join_handle) {
-            return Ok(false);
+    // slows the write down if the flushes are falling behind, and moves a full memtable to the
+    // queue, waiting for a flush if the queue is full too, a memtable is full once it reaches its
+    // size threshold or once the budget shared with other SSTables is used up and it holds enough
+    // of it, returns the memtable the write goes to
+    fn make_room_for_write(&self, writer: &mut SSTableWriter) -> DbResult<Arc<Memtable>> {
+        let policy = &self.write_buffer_policy;
+        let mut immutable_memtables = self.locked_immutable_memtables.lock()?;
+        immutable_memtables.check_flush_error()?;
+        if immutable_memtables.queue.len() >= policy.slowdown_threshold {
+            drop(immutable_memtables);
+            let slowdown_start = Instant::now();
+            sleep(policy.slowdown_delay);
+            writer.write_stall_stats.num_slowdowns += 1;
+            writer.write_stall_stats.slowdown_duration += slowdown_start.elapsed();
+            immutable_memtables = self.locked_immutable_memtables.lock()?;
+            immutable_memtables.check_flush_error()?;
         }
-        let should_move;
-        {
-            let mut tmp_memtable = self.locked_tmp_memtable.write()?;
-            should_move = tmp_memtable.is_empty();
-            if should_move {
-                swap(&mut (*tmp_memtable), &mut self.memtable);
-            }
+        let memtable = self.memtable()?;
+        let size = memtable.approximate_size();
+        let is_full = size >= policy.memtable_size_threshold
+            || policy
+                .budget
+                .as_ref()
+                .is_some_and(|budget| budget.is_used_up() && size >= budget.min_memtable_size());
+        if !is_full || memtable.is_empty() {
+            return Ok(memtable);
         }
-        if should_move {
-            if let Err(e) = self.swap_memtable_backup_files() {
-                println!("error in swapping memtable backup files: {}", e);
+
+        let max_immutable_memtables = policy.max_immutable_memtables;
+        if immutable_memtables.queue.len() >= max_immutable_memtables {
+            let stall_start = Instant::now();
+            while immutable_memtables.queue.len() >= max_immutable_memtables {
+                immutable_memtables = self.memtable_flushed.wait(immutable_memtables)?;
+                immutable_memtables.check_flush_error()?;
             }
+            writer.write_stall_stats.num_stalls += 1;
+            writer.write_stall_stats.stall_duration += stall_start.elapsed();
         }
-        Ok(should_move)
+        // the memtable's backup becomes the immutable memtable's with a single rename, which is
+        // atomic, a crash leaves the backup under one name or the other; the rename is made
+        // durable before a new backup takes the old name
+        let backup_file_name =
+            get_immutable_memtable_backup_file_name(writer.next_immutable_memtable_backup_number);
+        writer.next_immutable_memtable_backup_number += 1;
+        writer.memtable_backup.rename(&backup_file_name)?;
+        sync_dir(&writer.memtable_backup.dir_path)?;
+        let memtable_backup =
+            KVFile::new(&writer.memtable_backup.dir_path, MEMTABLE_BACKUP_FILE_NAME)?;
+        // queued before it's swapped out, so a read that still gets the full memtable finds it
+        // twice rather than a read that gets the empty one missing it
+        immutable_memtables.queue.push_back(ImmutableMemtable {
+            memtable,
+            backup: replace(&mut writer.memtable_backup, memtable_backup),
+        });
+        let memtable = Arc::new(Memtable::new());
+        *self.locked_memtable.write()? = Arc::clone(&memtable);
+        if !immutable_memtables.is_flushing {
+            immutable_memtables.is_flushing = true;
+            drop(immutable_memtables);
+            self.flush_immutable_memtables_in_background(writer);
+        }
+        Ok(memtable)
     }
-    fn flush_tmp_memtable_in_background(&mut self) {
-        assert_eq!(
-            is_thread_running(&self.flush_memtable_thread_join_handle),
-            false
-        );
-        let locked_tmp_memtable = Arc::clone(&self.locked_tmp_memtable);
-        let locked_tmp_memtable_backup = Arc::clone(&self.locked_tmp_memtable_backup);
+    fn flush_immutable_memtables_in_background(&self, writer: &mut SSTableWriter) {
+        // the last thread is done with the queue, but might not have returned yet
+        if let Some(handle) = writer.flush_memtable_thread_join_handle.take() {
+            let _ = handle.join();
+        }
+        let locked_immutable_memtables = Arc::clone(&self.locked_immutable_memtables);
+        let memtable_flushed = Arc::clone(&self.memtable_flushed);
         let locked_segmented_files_db = Arc::clone(&self.locked_segmented_files_db);
-        self.flush_memtable_thread_join_handle = Some(spawn(move || {
-            if let Err(e) = Self::flush_tmp_memtable(
-                locked_tmp_memtable,
-                locked_tmp_memtable_backup,
-                locked_segmented_files_db,
+        let budget = self.write_buffer_policy.budget.clone();
+        writer.flush_memtable_thread_join_handle = Some(spawn(move || {
+            if let Err(e) = Self::flush_immutable_memtables(
+                &locked_immutable_memtables,
+                &memtable_flushed,
+                &locked_segmented_files_db,
+                &budget,
             ) {
-                panic!("error in flushing memtable: {}", e);
+                // the writes waiting for a flush are woken up to fail with the error rather than
+                // wait for one that never comes
+                if let Ok(mut immutable_memtables) = locked_immutable_memtables.lock() {
+                    immutable_memtables.is_flushing = false;
+                    immutable_memtables.flush_error = Some(e);
+                }
+                memtable_flushed.notify_all();
             }
         }));
     }
-    fn flush_tmp_memtable(
-        locked_tmp_memtable: Arc<RwLock<Memtable>>,
-        locked_tmp_memtable_backup: Arc<RwLock<KVFile>>,
-        locked_segmented_files_db: Arc<Mutex<SegmentedFilesDb<File, Factory, ReaderFactory>>>,
+    // flushes the queue from its oldest memtable on, until it's empty
+    fn flush_immutable_memtables(
+        locked_immutable_memtables: &Mutex<ImmutableMemtables>,
+        memtable_flushed: &Condvar,
+        locked_segmented_files_db: &RwLock<SegmentedFilesDb<File, Factory>>,
+        budget: &Option<Arc<WriteBufferBudget>>,
     ) -> DbResult<()> {
-        {
-            let tmp_memtable = locked_tmp_memtable.read()?;
-            if tmp_memtable.is_empty() {
-                return Ok(());
-            }
-
-            let mut segmented_files_db = locked_segmented_files_db.lock()?;
-            segmented_files_db
-                .create_fresh_segment()
-                .map_err(|e| Error::wrap("error in creating fresh segment", e))?;
+        loop {
+            let memtable = {
+                let mut immutable_memtables = locked_immutable_memtables.lock()?;
+                match immutable_memtables.queue.front() {
+                    Some(immutable_memtable) => Arc::clone(&immutable_memtable.memtable),
+                    None => {
+                        immutable_memtables.is_flushing = false;
+                        return Ok(());
+                    }
+                }
+            };
 
-            for (key, status) in tmp_memtable.iter() {
-                match status {
-                    Present(value) => segmented_files_db.set(key, value)?,
-                    Deleted => segmented_files_db.delete(key)?,
+            {
+                let mut segmented_files_db = locked_segmented_files_db.write()?;
+                let snapshot_seqs = segmented_files_db.live_snapshots().seqs()?;
+                let mut memtable_versions = MergingIterator::new(
+                    vec![Box::new(
+                        memtable.scan(&KeyRange::new(..), ScanDirection::Forward),
+                    )],
+                    ScanDirection::Forward,
+                );
+                while let Some((key, versions)) = memtable_versions.next_versions()? {
+                    for (status, seq) in visible_versions(versions, &snapshot_seqs) {
+                        segmented_files_db.set_status(&key, &status, seq)?;
+                    }
                 }
+                // archiving seals the segment the memtable was written to
+                segmented_files_db
+                    .create_fresh_segment()
+                    .map_err(|e| Error::wrap("error in archiving flushed segment", e))?;
             }
+            let mut flushed = locked_immutable_memtables
+                .lock()?
+                .queue
+                .pop_front()
+                .unwrap();
+            release_write_buffer(budget, flushed.memtable.approximate_size());
+            memtable_flushed.notify_all();
+            // only once the segment the memtable was flushed to is in the manifest
+            flushed.backup.delete()?;
+            sync_dir(&flushed.backup.dir_path)?;
         }
-        locked_tmp_memtable.write()?.clear();
-        locked_tmp_memtable_backup.write()?.delete()?;
-
-        Ok(())
-    }
-    fn swap_memtable_backup_files(&mut self) -> DbResult<()> {
-        {
-            let mut tmp_memtable_backup = self.locked_tmp_memtable_backup.write()?;
-            swap(&mut (*tmp_memtable_backup), &mut self.memtable_backup);
-            self.memtable_backup
-                .rename(TMP_MEMTABLE_BACKUP_SWAP_FILE_NAME)?;
-            tmp_memtable_backup.rename(TMP_MEMTABLE_BACKUP_FILE_NAME)?;
-        }
-        self.memtable_backup.rename(MEMTABLE_BACKUP_FILE_NAME)?;
-        Ok(())
     }
     fn recover_memtable_from_backup(
         dir_path: &str,
         file_name: &str,
-    ) -> DbResult<(Memtable, KVFile)> {
+        recovery_policy: RecoveryPolicy,
+    ) -> DbResult<(Memtable, KVFile, u64)> {
         let mut backup = KVFile::new(dir_path, file_name)?;
-        let mut memtable = Memtable::new();
-        for line_result in backup.iter()? {
-            let line = line_result?;
-            memtable.insert(line.key, line.status);
+        let memtable = Memtable::new();
+        let mut last_seq = 0;
+        backup.recover(recovery_policy, &mut |line| {
+            last_seq = last_seq.max(line.seq);
+            memtable.insert(&line.key, line.seq, line.status);
+            Ok(())
+        })?;
+        Ok((memtable, backup, last_seq))
+    }
+}
+
+impl ImmutableMemtables {
+    fn check_flush_error(&self) -> DbResult<()> {
+        match &self.flush_error {
+            Some(e) => Err(Error::wrap("error in flushing memtable", e.clone())),
+            None => Ok(()),
         }
-        Ok((memtable, backup))
+    }
+}
+
+// the immutable memtables from the oldest to the newest
+fn immutable_memtables(
+    locked_immutable_memtables: &Mutex<ImmutableMemtables>,
+) -> DbResult<Vec<Arc<Memtable>>> {
+    Ok(locked_immutable_memtables
+        .lock()?
+        .queue
+        .iter()
+        .map(|immutable_memtable| Arc::clone(&immutable_memtable.memtable))
+        .collect())
+}
+
+// the value of the key as of `seq`, looked up in the memtable as of `memtable_seq`, then in the
+// immutable memtables from the newest, and then in the segments
+fn get_at(
+    memtable: &Memtable,
+    memtable_seq: u64,
+    locked_immutable_memtables: &Mutex<ImmutableMemtables>,
+    locked_segmented_files_db: &RwLock<SegmentedFilesDb<File, Factory>>,
+    key: &str,
+    seq: u64,
+) -> DbResult<Option<String>> {
+    check_key_status!(memtable.get_at(key, memtable_seq));
+    for memtable in immutable_memtables(locked_immutable_memtables)?
+        .iter()
+        .rev()
+    {
+        check_key_status!(memtable.get_at(key, seq));
+    }
+    locked_segmented_files_db.read()?.get_at(key, seq)
+}
+
+fn scan_at(
+    memtable: &Arc<Memtable>,
+    memtable_seq: u64,
+    locked_immutable_memtables: &Mutex<ImmutableMemtables>,
+    locked_segmented_files_db: &RwLock<SegmentedFilesDb<File, Factory>>,
+    range: KeyRange,
+    direction: ScanDirection,
+    seq: u64,
+) -> DbResult<ScanIterator<'static>> {
+    if range.is_empty() {
+        return Ok(Box::new(empty()));
+    }
+    let mut sources: Vec<StatusIterator> =
+        vec![Box::new(memtable.scan(&range, direction).filter(
+            move |entry| !matches!(entry, Ok((_, _, seq)) if *seq > memtable_seq),
+        ))];
+
+    // taken before the segments so that a flush finishing in between can't hide its entries,
+    // the scans keep the memtables alive after the flush lets go of them
+    for memtable in immutable_memtables(locked_immutable_memtables)?
+        .iter()
+        .rev()
+    {
+        sources.push(Box::new(memtable.scan(&range, direction)));
+    }
+
+    sources.extend(locked_segmented_files_db.read()?.scan(&range, direction)?);
+    Ok(Box::new(MergingIterator::as_of(sources, direction, seq)))
+}
+
+fn get_immutable_memtable_backup_file_name(backup_number: usize) -> String {
+    format!(
+        "{}{}.txt",
+        IMMUTABLE_MEMTABLE_BACKUP_FILE_PREFIX, backup_number
+    )
+}
+
+fn reserve_write_buffer(budget: &Option<Arc<WriteBufferBudget>>, bytes: usize) {
+    if let Some(budget) = budget {
+        budget.reserve(bytes);
+    }
+}
+
+fn release_write_buffer(budget: &Option<Arc<WriteBufferBudget>>, bytes: usize) {
+    if let Some(budget) = budget {
+        budget.release(bytes);
     }
 }
@@ -1,143 +1,210 @@
-use std::collections::VecDeque;
-use std::mem::replace;
+use std::iter::empty;
+use std::mem::take;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::error::DbResult;
-use crate::tmp_file_names::{TMP_COMPACTION_FILE_NAME, TMP_MERGING_FILE_NAME};
+use super::block::{decode_index, encode_index, BlockHandle, Footer, MetaBlockHandle, FOOTER_SIZE};
+use super::bloom::{hash_key, BloomFilter};
+use crate::crc::crc32;
+use crate::error::{DbResult, Error};
 use crate::{
-    kv_file::{KVFile, KVLine},
-    kvdb::KeyStatus,
-    segmented_files_db::segment_file::{
-        SegmentFile, SegmentFileFactory, SegmentReader, SegmentReaderFactory,
+    kv_file::{encode_record, KVFile, KVLine, RecoveryPolicy},
+    kvdb::{KeyRange, KeyStatus, ScanDirection, StatusIterator},
+    segmented_files_db::{
+        block_cache::BlockCache,
+        segment_file::{SegmentFile, SegmentFileFactory},
     },
 };
 
-pub struct Reader<'a> {
-    kvfile: KVFile,
-    sparse_index: &'a Vec<(String, u64)>,
-}
-
-impl<'a> SegmentReader<'a> for Reader<'a> {
-    fn get_status(&mut self, key: &str) -> DbResult<Option<KeyStatus<String>>> {
-        get_status(self.sparse_index, &mut self.kvfile, key)
-    }
+#[derive(Default)]
+struct PendingBlock {
+    bytes: Vec<u8>,
+    last_key: Option<String>,
 }
 
 pub struct File {
-    sparsity: u64,
+    block_size: u64,
+    bloom_bits_per_key: u64,
     kvfile: KVFile,
-    sparse_index: Vec<(String, u64)>,
-    last_indexed_offset: u64,
+    // lookups read data blocks through the cache, scans read around it so that a long scan doesn't
+    // push out the blocks lookups keep coming back to
+    block_cache: Arc<BlockCache>,
+    file_id: u64,
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+    // hashes of the keys written so far, the filter is built from them when the segment is sealed
+    key_hashes: Vec<u64>,
+    first_key: Option<String>,
+    pending_block: PendingBlock,
+    // the sequence number of the last record written, orders the versions of the last key
+    last_written_seq: u64,
+    sealed: bool,
 }
 
 impl SegmentFile for File {
-    type Reader<'a> = Reader<'a>;
-    fn set_status(&mut self, key: &str, status: &KeyStatus<String>) -> DbResult<()> {
-        set_status(
-            &mut self.sparse_index,
-            &mut self.last_indexed_offset,
-            self.sparsity,
-            &mut self.kvfile,
-            key,
-            status,
-        )
-    }
-    fn get_status(&mut self, key: &str) -> DbResult<Option<KeyStatus<String>>> {
-        get_status(&self.sparse_index, &mut self.kvfile, key)
-    }
-    fn absorb<'a>(&mut self, other: &mut Reader<'a>) -> DbResult<()> {
-        let mut new_file = KVFile::new(&self.kvfile.dir_path, TMP_MERGING_FILE_NAME)?;
-        let mut new_index = vec![];
-        let mut last_indexed_offset = 0;
-
-        let mut this_iter = self.kvfile.iter()?;
-        let mut this_buf = this_iter.try_next()?;
-
-        let mut other_iter = other.kvfile.iter()?;
-        let mut other_buf = other_iter.try_next()?;
-
-        let mut writer_buf = None;
-
-        loop {
-            let use_other = match &this_buf {
-                None => true,
-                Some(KVLine { key: this_key, .. }) => match &other_buf {
-                    None => false,
-                    Some(KVLine { key: other_key, .. }) => this_key >= other_key,
-                },
-            };
-            let (buf, iter) = match use_other {
-                true => (&mut other_buf, &mut other_iter),
-                false => (&mut this_buf, &mut this_iter),
-            };
-
-            let prev_writer_buf = replace(&mut writer_buf, replace(buf, iter.try_next()?));
-            match prev_writer_buf {
-                None => {
-                    if writer_buf.is_none() {
-                        break;
-                    }
-                }
-                Some(KVLine {
-                    key: ref prev_key,
-                    status: prev_status,
-                    ..
-                }) => {
-                    let should_write = match writer_buf {
-                        None => true,
-                        Some(KVLine {
-                            key: ref current_key,
-                            ..
-                        }) => current_key > prev_key,
-                    };
-                    if should_write {
-                        let offset = new_file.append_line(&prev_key, &prev_status)?;
-                        if should_create_new_index_entry(
-                            &new_index,
-                            offset,
-                            last_indexed_offset,
-                            self.sparsity,
-                        ) {
-                            new_index.push((prev_key.to_owned(), offset));
-                            last_indexed_offset = offset;
-                        }
-                    }
-                }
-            };
+    fn set_status(&mut self, key: &str, status: &KeyStatus<String>, seq: u64) -> DbResult<()> {
+        if self.sealed {
+            return Err(Error::InvalidInput(format!(
+                "segment {} is sealed",
+                self.kvfile.file_name
+            )));
+        }
+        let is_new_key = match self.last_key() {
+            Some(last_key) if key < last_key || key == last_key && seq >= self.last_written_seq => {
+                return Err(Error::InvalidInput(format!(
+                    "keys must be written in increasing order and their versions from the newest, got {} at {} after {} at {}",
+                    key, seq, last_key, self.last_written_seq
+                )));
+            }
+            Some(last_key) => key != last_key,
+            None => true,
+        };
+        // the versions of a key all go in the same block, the one a lookup for the key reads
+        if is_new_key && self.pending_block.bytes.len() as u64 >= self.block_size {
+            self.flush_pending_block()?;
+        }
+        encode_record(&mut self.pending_block.bytes, key, status, seq)?;
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        self.pending_block.last_key = Some(key.to_owned());
+        self.last_written_seq = seq;
+        if is_new_key && self.bloom_bits_per_key > 0 {
+            self.key_hashes.push(hash_key(key));
         }
-
-        self.replace(new_file, new_index, last_indexed_offset)?;
         Ok(())
     }
-    fn rename(&mut self, new_file_name: &str) -> DbResult<()> {
-        self.kvfile.rename(new_file_name)
+    fn get_status(&self, key: &str) -> DbResult<Option<KeyStatus<String>>> {
+        self.get_status_at(key, u64::MAX)
     }
-    fn compact(&mut self) -> DbResult<()> {
-        let old_sparse_index = replace(&mut self.sparse_index, vec![]);
-        drop(old_sparse_index);
-
-        let mut new_file = KVFile::new(&self.kvfile.dir_path, TMP_COMPACTION_FILE_NAME)?;
-        let mut new_index = vec![];
-        let mut last_indexed_offset = 0;
-
-        let mut file_iter = self.kvfile.iter()?;
-        loop {
-            let Some(line) = file_iter.try_next()? else {
+    fn get_status_at(&self, key: &str, seq: u64) -> DbResult<Option<KeyStatus<String>>> {
+        if let Some(filter) = &self.filter {
+            if !filter.may_contain(key) {
+                return Ok(None);
+            }
+        }
+        let block_idx = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        let block = match self.index.get(block_idx) {
+            Some(handle) => {
+                let block = self
+                    .block_cache
+                    .get_or_read(self.file_id, handle.offset, || {
+                        self.kvfile.read_bytes(handle.offset, handle.size)
+                    })?;
+                self.kvfile.iter_block(handle.offset, block)?
+            }
+            None => match self.pending_block() {
+                Some((offset, block)) => self.kvfile.iter_block(offset, block)?,
+                None => return Ok(None),
+            },
+        };
+        for line_result in block {
+            let line = line_result?;
+            if line.key.as_str() > key {
                 break;
-            };
-            // skip deleted entries
-            if let KeyStatus::Present(_) = line.status {
-                set_status(
-                    &mut new_index,
-                    &mut last_indexed_offset,
-                    self.sparsity,
-                    &mut new_file,
-                    &line.key,
-                    &line.status,
-                )?;
             }
+            if line.key == key && line.seq <= seq {
+                return Ok(Some(line.status));
+            }
+        }
+        Ok(None)
+    }
+    fn scan(
+        &self,
+        range: &KeyRange,
+        direction: ScanDirection,
+    ) -> DbResult<StatusIterator<'static>> {
+        // the blocks from the one that can hold the start of the range to the one that can hold its end
+        let first_block = self
+            .index
+            .partition_point(|handle| range.is_before_start(&handle.last_key));
+        let end_block = self
+            .index
+            .partition_point(|handle| !range.is_after_end(&handle.last_key));
+        let blocks_end = (end_block + 1).min(self.index.len()).max(first_block);
+        let mut blocks: Vec<ScanBlock> = self.index[first_block..blocks_end]
+            .iter()
+            .cloned()
+            .map(ScanBlock::OnDisk)
+            .collect();
+        // the pending block's keys come after every written one's
+        if end_block == self.index.len() {
+            if let Some((offset, block)) = self.pending_block() {
+                blocks.push(ScanBlock::InMemory(offset, block));
+            }
+        }
+        if blocks.is_empty() {
+            return Ok(Box::new(empty()));
+        }
+        if direction == ScanDirection::Reverse {
+            blocks.reverse();
         }
 
-        self.replace(new_file, new_index, last_indexed_offset)?;
+        // the scan shares the file's handle, so it keeps working after a merge deletes the file
+        let kvfile = KVFile::share(&self.kvfile)?;
+        Ok(Box::new(SegmentScan {
+            kvfile,
+            blocks: blocks.into_iter(),
+            lines: vec![].into_iter(),
+            range: range.clone(),
+            direction,
+            done: false,
+        }))
+    }
+    fn ready_to_be_archived(&mut self) -> DbResult<bool> {
+        Ok(self.last_key().is_some())
+    }
+    fn key_range(&mut self) -> DbResult<Option<(String, String)>> {
+        self.flush_pending_block()?;
+        Ok(self
+            .first_key
+            .clone()
+            .zip(self.index.last().map(|handle| handle.last_key.clone())))
+    }
+    fn size(&self) -> DbResult<u64> {
+        self.kvfile.size()
+    }
+    fn last_modified(&self) -> DbResult<SystemTime> {
+        self.kvfile.last_modified()
+    }
+    fn seal(&mut self) -> DbResult<()> {
+        if self.sealed {
+            return Ok(());
+        }
+        self.flush_pending_block()?;
+
+        let filter = match self.bloom_bits_per_key {
+            0 => None,
+            bits_per_key => Some(BloomFilter::build(
+                &take(&mut self.key_hashes),
+                bits_per_key,
+            )),
+        };
+        let filter_block = filter.as_ref().map_or(vec![], |filter| filter.encode());
+        let index_block = encode_index(&self.index);
+        let filter_handle = MetaBlockHandle {
+            offset: self.kvfile.size()?,
+            size: filter_block.len() as u64,
+            checksum: crc32(&filter_block),
+        };
+        let footer = Footer {
+            index: MetaBlockHandle {
+                offset: filter_handle.offset + filter_handle.size,
+                size: index_block.len() as u64,
+                checksum: crc32(&index_block),
+            },
+            filter: filter_handle,
+        };
+        let mut tail = filter_block;
+        tail.extend_from_slice(&index_block);
+        tail.extend_from_slice(&footer.encode());
+        self.kvfile.append_bytes(&tail)?;
+        self.kvfile.sync()?;
+
+        self.filter = filter;
+        self.sealed = true;
         Ok(())
     }
     fn delete(mut self) -> DbResult<()> {
@@ -146,123 +213,219 @@ impl SegmentFile for File {
 }
 
 impl File {
-    fn replace(
-        &mut self,
-        new_file: KVFile,
-        new_index: Vec<(String, u64)>,
-        last_indexed_offset: u64,
-    ) -> DbResult<()> {
-        let mut old_file = replace(&mut self.kvfile, new_file);
-        let file_name = old_file.file_name.clone();
-        old_file.delete()?;
-        self.kvfile.rename(&file_name)?;
-        self.sparse_index = new_index;
-        self.last_indexed_offset = last_indexed_offset;
+    fn new(
+        dir_path: &str,
+        file_name: &str,
+        block_size: u64,
+        bloom_bits_per_key: u64,
+        block_cache: &Arc<BlockCache>,
+    ) -> DbResult<File> {
+        Ok(File {
+            block_size,
+            bloom_bits_per_key,
+            kvfile: KVFile::new(dir_path, file_name)?,
+            block_cache: Arc::clone(block_cache),
+            file_id: block_cache.new_file_id()?,
+            index: vec![],
+            filter: None,
+            key_hashes: vec![],
+            first_key: None,
+            pending_block: PendingBlock::default(),
+            last_written_seq: 0,
+            sealed: false,
+        })
+    }
+    fn last_key(&self) -> Option<&str> {
+        self.pending_block
+            .last_key
+            .as_deref()
+            .or_else(|| self.index.last().map(|handle| handle.last_key.as_str()))
+    }
+    fn flush_pending_block(&mut self) -> DbResult<()> {
+        let Some(last_key) = self.pending_block.last_key.take() else {
+            return Ok(());
+        };
+        let bytes = take(&mut self.pending_block.bytes);
+        let offset = self.kvfile.append_bytes(&bytes)?;
+        self.index.push(BlockHandle {
+            last_key,
+            offset,
+            size: bytes.len() as u64,
+        });
         Ok(())
     }
+    // a copy of the block being filled and the offset it's going to be written at, `None` if it's
+    // empty, only a segment that is still being written has one
+    fn pending_block(&self) -> Option<(u64, Arc<[u8]>)> {
+        self.pending_block.last_key.as_ref()?;
+        let offset = self
+            .index
+            .last()
+            .map_or(0, |handle| handle.offset + handle.size);
+        Some((offset, self.pending_block.bytes.as_slice().into()))
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // the id isn't handed out again, the file's blocks would only take up room
+        let _ = self.block_cache.erase_file(self.file_id);
+    }
 }
 
-pub struct ReaderFactory {}
+// a block for a scan to read, the pending one is copied out of memory
+enum ScanBlock {
+    OnDisk(BlockHandle),
+    InMemory(u64, Arc<[u8]>),
+}
 
-impl SegmentReaderFactory<File> for ReaderFactory {
-    fn new<'a>(&self, file: &'a File) -> DbResult<<File as SegmentFile>::Reader<'a>> {
-        return Ok(Reader {
-            kvfile: KVFile::copy(&file.kvfile)?,
-            sparse_index: &file.sparse_index,
-        });
+// statuses of the keys in a range, reading one block at a time
+struct SegmentScan {
+    kvfile: KVFile,
+    blocks: std::vec::IntoIter<ScanBlock>,
+    lines: std::vec::IntoIter<KVLine>,
+    range: KeyRange,
+    direction: ScanDirection,
+    done: bool,
+}
+
+impl SegmentScan {
+    fn try_next(&mut self) -> DbResult<Option<(String, KeyStatus<String>, u64)>> {
+        while !self.done {
+            let Some(line) = self.lines.next() else {
+                let Some(block) = self.blocks.next() else {
+                    self.done = true;
+                    break;
+                };
+                let block = match block {
+                    ScanBlock::OnDisk(handle) => {
+                        self.kvfile.read_block(handle.offset, handle.size)?
+                    }
+                    ScanBlock::InMemory(offset, bytes) => self.kvfile.iter_block(offset, bytes)?,
+                };
+                let mut lines = block.collect::<DbResult<Vec<KVLine>>>()?;
+                if self.direction == ScanDirection::Reverse {
+                    lines.reverse();
+                }
+                self.lines = lines.into_iter();
+                continue;
+            };
+            if self.range.is_exhausted_at(&line.key, self.direction) {
+                self.done = true;
+            } else if self.range.contains(&line.key) {
+                return Ok(Some((line.key, line.status, line.seq)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for SegmentScan {
+    type Item = DbResult<(String, KeyStatus<String>, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.try_next();
+        if result.is_err() {
+            self.done = true;
+        }
+        result.transpose()
     }
 }
 
 pub struct Factory {
     pub dir_path: String,
-    pub sparsity: u64,
+    pub block_size: u64,
+    pub bloom_bits_per_key: u64,
+    pub block_cache: Arc<BlockCache>,
+    pub recovery_policy: RecoveryPolicy,
 }
 
 impl SegmentFileFactory<File> for Factory {
     fn new(&self, file_name: &str) -> DbResult<File> {
-        let kvfile = KVFile::new(&self.dir_path, file_name)?;
-        Ok(File {
-            sparsity: self.sparsity,
-            kvfile,
-            sparse_index: vec![],
-            last_indexed_offset: 0,
-        })
+        File::new(
+            &self.dir_path,
+            file_name,
+            self.block_size,
+            self.bloom_bits_per_key,
+            &self.block_cache,
+        )
     }
     fn from_disk(&self, file_name: &str) -> DbResult<File> {
-        let mut kvfile = KVFile::new(&self.dir_path, file_name)?;
-
-        let mut last_indexed_offset = 0;
-        let mut sparse_index = vec![];
-        for line_result in kvfile.iter()? {
-            let KVLine { key, offset, .. } = line_result?;
-            if should_create_new_index_entry(
-                &sparse_index,
-                offset,
-                last_indexed_offset,
-                self.sparsity,
-            ) {
-                sparse_index.push((key.to_owned(), offset));
-                last_indexed_offset = offset;
+        let mut file = self.new(file_name)?;
+        match read_meta_blocks(&file.kvfile)? {
+            Some((index, filter)) => {
+                file.first_key = match index.first() {
+                    Some(handle) => file
+                        .kvfile
+                        .read_block(handle.offset, handle.size)?
+                        .try_next()?
+                        .map(|line| line.key),
+                    None => None,
+                };
+                file.index = index;
+                file.filter = filter;
+                file.sealed = true;
             }
+            None if file.kvfile.size()? == 0 => {}
+            // the segment was being written when the process stopped, its records are still
+            // in the memtable backup that was being flushed
+            None => match self.recovery_policy {
+                RecoveryPolicy::TruncateTornTail => file.kvfile.truncate(0)?,
+                RecoveryPolicy::Refuse => {
+                    return Err(Error::InvalidData(format!(
+                        "segment {} has no footer",
+                        file.kvfile.get_file_path()
+                    )))
+                }
+            },
         }
-
-        Ok(File {
-            sparsity: self.sparsity,
-            kvfile,
-            sparse_index,
-            last_indexed_offset,
-        })
+        Ok(file)
     }
 }
 
-fn get_status(
-    sparse_index: &Vec<(String, u64)>,
-    kvfile: &mut KVFile,
-    key: &str,
-) -> DbResult<Option<KeyStatus<String>>> {
-    let index = match sparse_index.binary_search_by(|(this_key, _)| this_key.cmp(&key.to_string()))
-    {
-        Ok(index) => index,
-        Err(0) => return Ok(None),
-        Err(index) => index - 1,
-    };
-    let (_, start_offset) = sparse_index.get(index).unwrap();
+// reads the footer, the index block and the filter block, returns None if the segment was never
+// sealed
+fn read_meta_blocks(kvfile: &KVFile) -> DbResult<Option<(Vec<BlockHandle>, Option<BloomFilter>)>> {
+    let size = kvfile.size()?;
+    if size < FOOTER_SIZE {
+        return Ok(None);
+    }
+    let footer_offset = size - FOOTER_SIZE;
+    let footer_bytes = kvfile.read_bytes(footer_offset, FOOTER_SIZE)?;
+    let file_path = kvfile.get_file_path();
+    let corrupted = |offset, msg| Error::Corrupted(file_path.clone(), offset, msg);
 
-    let mut status = None;
-    for line_result in kvfile.iter_from_offset(*start_offset)? {
-        let line = line_result?;
-        if line.key.as_str() > key {
-            break;
-        }
-        if line.key == key {
-            status = Some(line.status)
-        }
+    let Some(footer) = Footer::decode(&footer_bytes).map_err(|e| corrupted(footer_offset, e))?
+    else {
+        return Ok(None);
+    };
+    if footer.filter.end() != Some(footer.index.offset) || footer.index.end() != Some(footer_offset)
+    {
+        return Err(corrupted(
+            footer_offset,
+            "filter and index blocks don't end where the footer starts".to_string(),
+        ));
     }
-    Ok(status)
-}
 
-fn set_status(
-    sparse_index: &mut Vec<(String, u64)>,
-    last_indexed_offset: &mut u64,
-    sparsity: u64,
-    kvfile: &mut KVFile,
-    key: &str,
-    status: &KeyStatus<String>,
-) -> DbResult<()> {
-    kvfile.append_line(key, status).and_then(|offset| {
-        if should_create_new_index_entry(&sparse_index, offset, *last_indexed_offset, sparsity) {
-            sparse_index.push((key.to_owned(), offset));
-            *last_indexed_offset = offset;
+    let read_meta_block = |handle: &MetaBlockHandle, name: &str| {
+        let bytes = kvfile.read_bytes(handle.offset, handle.size)?;
+        if crc32(&bytes) != handle.checksum {
+            return Err(corrupted(
+                handle.offset,
+                format!("{} block checksum mismatch", name),
+            ));
         }
-        Ok(())
-    })
-}
+        Ok(bytes)
+    };
+    let filter_bytes = read_meta_block(&footer.filter, "filter")?;
+    let index_bytes = read_meta_block(&footer.index, "index")?;
 
-fn should_create_new_index_entry<T>(
-    index: &Vec<T>,
-    offset: u64,
-    last_indexed_offset: u64,
-    sparsity: u64,
-) -> bool {
-    index.is_empty() || offset - last_indexed_offset > sparsity
+    let filter = match filter_bytes.is_empty() {
+        true => None,
+        false => Some(
+            BloomFilter::decode(&filter_bytes).map_err(|e| corrupted(footer.filter.offset, e))?,
+        ),
+    };
+    let index = decode_index(&index_bytes).map_err(|e| corrupted(footer.index.offset, e))?;
+    Ok(Some((index, filter)))
 }
@@ -0,0 +1,106 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+// How much memory a memtable takes before it's flushed, and how writes are held back when
+// memtables fill up faster than they're flushed. A full memtable joins a queue of immutable ones
+// that are flushed in order, writes are slowed down once the queue gets long and wait for a flush
+// once it's full.
+#[derive(Clone)]
+pub struct WriteBufferPolicy {
+    // bytes a memtable takes before it's flushed, counting its keys, values and the skiplist's own
+    // overhead, roughly
+    pub memtable_size_threshold: usize,
+    // number of immutable memtables that can wait to be flushed, at least 1
+    pub max_immutable_memtables: usize,
+    // each write is delayed by `slowdown_delay` once this many immutable memtables are waiting,
+    // writes are only ever stalled if it's more than `max_immutable_memtables`
+    pub slowdown_threshold: usize,
+    pub slowdown_delay: Duration,
+    // shared with the other SSTables whose memtables count against it, if any
+    pub budget: Option<Arc<WriteBufferBudget>>,
+}
+
+impl fmt::Display for WriteBufferPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "memtable size threshold of {} bytes, up to {} immutable memtables, slowing writes down by {:?} from {}",
+            self.memtable_size_threshold,
+            self.max_immutable_memtables,
+            self.slowdown_delay,
+            self.slowdown_threshold
+        )?;
+        if let Some(budget) = &self.budget {
+            write!(
+                f,
+                ", sharing a write buffer budget of {} bytes",
+                budget.limit()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A cap on the memory taken by the memtables of every SSTable it's shared by, immutable ones
+// included until they're flushed. A write that finds the budget used up moves its SSTable's
+// memtable to the flush queue, once the memtable holds at least half of its SSTable's share of the
+// budget: a smaller one would free next to nothing and leave a tiny segment behind, and the
+// memtables below that take less than half of the budget together, so it's overshot by at most as
+// much.
+pub struct WriteBufferBudget {
+    limit: usize,
+    used: AtomicUsize,
+    // the open SSTables sharing the budget
+    num_sharers: AtomicUsize,
+}
+
+impl WriteBufferBudget {
+    pub fn new(limit: usize) -> Self {
+        WriteBufferBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            num_sharers: AtomicUsize::new(0),
+        }
+    }
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+    pub fn is_used_up(&self) -> bool {
+        self.used() >= self.limit
+    }
+    // the size a memtable has to reach before it's flushed for the budget
+    pub fn min_memtable_size(&self) -> usize {
+        self.limit / (2 * self.num_sharers.load(Ordering::Relaxed).max(1))
+    }
+    pub fn add_sharer(&self) {
+        self.num_sharers.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn remove_sharer(&self) {
+        self.num_sharers.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn reserve(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteStallStats {
+    // writes that were delayed because the queue was getting long
+    pub num_slowdowns: u64,
+    pub slowdown_duration: Duration,
+    // writes that waited for a flush because the queue was full
+    pub num_stalls: u64,
+    pub stall_duration: Duration,
+}
@@ -0,0 +1,138 @@
+use std::{collections::HashMap, fs, sync::Arc, time::Duration};
+
+use crate::kv_file::RecoveryPolicy;
+use crate::kvdb::KVDb;
+use crate::segmented_files_db::{block_cache::BlockCache, compaction::MergeAllCompaction};
+use crate::segmented_logs_with_indices_db::SegmentedLogsWithIndicesDb;
+use crate::sstable::{write_buffer::WriteBufferPolicy, SSTable};
+
+use super::{utils::generate_random_operations, Operation};
+
+// Runs the same operations on an SSTable and on segmented logs that share a block cache, small
+// enough that it keeps evicting blocks and that merges keep deleting the segments it holds blocks
+// of. Checks every read, that the cache stays within its capacity, and that it lets go of every
+// block once the databases are closed.
+pub struct BlockCacheTest {
+    operations: Vec<Operation>,
+}
+
+impl BlockCacheTest {
+    pub fn new(
+        num_keys: u32,
+        num_operations: u32,
+        read_write_ratio: f32,
+        set_delete_ratio: f32,
+        hit_reads_ratio: f32,
+    ) -> Self {
+        BlockCacheTest {
+            operations: generate_random_operations(
+                num_keys,
+                num_operations,
+                read_write_ratio,
+                set_delete_ratio,
+                hit_reads_ratio,
+                false,
+            ),
+        }
+    }
+    pub fn run(&self, dir_path: &str, block_cache: Arc<BlockCache>) {
+        let _ = fs::remove_dir_all(dir_path);
+        let mut dbs: Vec<Box<dyn KVDb>> = vec![
+            Box::new(
+                SSTable::new(
+                    &format!("{}sstable/", dir_path),
+                    Box::new(MergeAllCompaction {
+                        merging_threshold: 5,
+                    }),
+                    500,
+                    10,
+                    Arc::clone(&block_cache),
+                    WriteBufferPolicy {
+                        memtable_size_threshold: 10 << 10,
+                        max_immutable_memtables: 4,
+                        slowdown_threshold: 3,
+                        slowdown_delay: Duration::from_millis(1),
+                        budget: None,
+                    },
+                    RecoveryPolicy::TruncateTornTail,
+                )
+                .unwrap(),
+            ),
+            Box::new(
+                SegmentedLogsWithIndicesDb::new(
+                    &format!("{}segmented_logs_with_indices_db/", dir_path),
+                    2000,
+                    Box::new(MergeAllCompaction {
+                        merging_threshold: 3,
+                    }),
+                    Arc::clone(&block_cache),
+                    RecoveryPolicy::TruncateTornTail,
+                )
+                .unwrap(),
+            ),
+        ];
+        println!(
+            "-------Running block cache test suite for {} shared by {} and {}-------",
+            block_cache,
+            dbs[0].description(),
+            dbs[1].description()
+        );
+
+        let mut sot = HashMap::new();
+        for op in &self.operations {
+            for db in dbs.iter_mut() {
+                match op {
+                    Operation::Set(ref key, ref value) => {
+                        if let Err(e) = db.set(key, value) {
+                            panic!("Test failed: unexpected error in write: {}", e);
+                        }
+                    }
+                    Operation::Delete(ref key) => {
+                        if let Err(e) = db.delete(key) {
+                            panic!("Test failed: unexpected error in delete: {}", e);
+                        }
+                    }
+                    Operation::Read(ref key) => match db.get(key) {
+                        Ok(got) if got == sot.get(key).cloned().flatten() => {}
+                        Ok(got) => panic!(
+                            "Test failed: expected {:?} for {}, got {:?}",
+                            sot.get(key),
+                            key,
+                            got
+                        ),
+                        Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+                    },
+                }
+            }
+            match op {
+                Operation::Set(ref key, ref value) => {
+                    sot.insert(key.clone(), Some(value.clone()));
+                }
+                Operation::Delete(ref key) => {
+                    sot.insert(key.clone(), None);
+                }
+                Operation::Read(_) => {}
+            }
+            let size = block_cache.size().unwrap();
+            if size > block_cache.capacity() {
+                panic!(
+                    "Test failed: {} bytes cached in a cache of {} bytes",
+                    size,
+                    block_cache.capacity()
+                );
+            }
+        }
+
+        let stats = block_cache.stats().unwrap();
+        println!("{} hits and {} misses", stats.hits, stats.misses);
+        if stats.hits == 0 || stats.misses == 0 {
+            panic!("Test failed: reads didn't both hit and miss the cache");
+        }
+        drop(dbs);
+        let size = block_cache.size().unwrap();
+        if size != 0 {
+            panic!("Test failed: {} bytes still cached after closing", size);
+        }
+        println!("Test passed");
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    thread::{spawn, JoinHandle},
+};
+
+use rand::Rng;
+
+use crate::error::DbResult;
+use crate::kv_file::RecoveryPolicy;
+use crate::kvdb::{
+    shared_db::{ConcurrentKVDb, SharedDb},
+    write_batch::WriteBatch,
+    KeyRange, KeyStatus, ScanDirection, StatusIterator,
+};
+use crate::segmented_files_db::{
+    block_cache::BlockCache,
+    segment_file::{SegmentFile, SegmentFileFactory},
+};
+use crate::segmented_logs_with_indices_db::segment_file::Factory;
+use crate::sstable::memtable::Memtable;
+
+// Writes from a few threads while others read and scan, all through clones of the same handle.
+// Each writer owns its own keys and writes them values tagged with increasing versions, some of
+// them a few keys at a time in a batch, so a reader can check that every value it gets was written
+// to that key and that a key never goes back to an older version. Once every thread is done, each
+// key has to hold its last write. The same runs against a single memtable, whose writers link
+// their nodes in at the same time, and against a single segment file, whose scans read through a
+// handle they share with it while the writes to it go on.
+pub struct ConcurrencyTest {
+    num_keys: u32,
+    num_writes: u32,
+    num_reads: u32,
+    num_writers: u32,
+    num_readers: u32,
+}
+
+impl ConcurrencyTest {
+    pub fn new(
+        num_keys: u32,
+        num_writes: u32,
+        num_reads: u32,
+        num_writers: u32,
+        num_readers: u32,
+    ) -> Self {
+        ConcurrencyTest {
+            num_keys,
+            num_writes,
+            num_reads,
+            num_writers,
+            num_readers,
+        }
+    }
+    pub fn run<T: ConcurrentKVDb + 'static>(&self, db: SharedDb<T>) {
+        println!(
+            "-------Running concurrency test suite for {} with {} writers and {} readers-------",
+            db.description(),
+            self.num_writers,
+            self.num_readers
+        );
+
+        let writers: Vec<JoinHandle<HashMap<String, Option<String>>>> = (0..self.num_writers)
+            .map(|writer| {
+                let (db, num_keys, num_writes, num_writers) =
+                    (db.clone(), self.num_keys, self.num_writes, self.num_writers);
+                spawn(move || write(&db, writer, num_keys, num_writes, num_writers))
+            })
+            .collect();
+        let readers: Vec<JoinHandle<()>> = (0..self.num_readers)
+            .map(|_| {
+                let (db, num_keys, num_reads) = (db.clone(), self.num_keys, self.num_reads);
+                spawn(move || read(&db, num_keys, num_reads))
+            })
+            .collect();
+
+        let mut sot = HashMap::new();
+        for writer in writers {
+            match writer.join() {
+                Ok(writes) => sot.extend(writes),
+                Err(_) => panic!("Test failed: a writer panicked"),
+            }
+        }
+        for reader in readers {
+            if reader.join().is_err() {
+                panic!("Test failed: a reader panicked");
+            }
+        }
+        for (key, want) in &sot {
+            match db.get(key) {
+                Ok(got) if got == *want => {}
+                Ok(got) => panic!(
+                    "Test failed: expected {:?} for {}, got {:?}",
+                    want, key, got
+                ),
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            }
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_memtable(&self) {
+        println!(
+            "-------Running concurrency test suite for a memtable with {} writers and {} readers-------",
+            self.num_writers, self.num_readers
+        );
+        let memtable = Arc::new(Memtable::new());
+        let next_seq = Arc::new(AtomicU64::new(1));
+        let writers: Vec<JoinHandle<_>> = (0..self.num_writers)
+            .map(|writer| {
+                let (memtable, next_seq) = (Arc::clone(&memtable), Arc::clone(&next_seq));
+                let (num_keys, num_writes, num_writers) =
+                    (self.num_keys, self.num_writes, self.num_writers);
+                spawn(move || {
+                    let mut size = 0;
+                    let writes =
+                        write_statuses(writer, num_keys, num_writes, num_writers, |key, status| {
+                            let seq = next_seq.fetch_add(1, Ordering::Relaxed);
+                            size += memtable.insert(key, seq, status);
+                        });
+                    (writes, size)
+                })
+            })
+            .collect();
+        let readers: Vec<JoinHandle<()>> = (0..self.num_readers)
+            .map(|_| {
+                let memtable = Arc::clone(&memtable);
+                let (num_keys, num_reads) = (self.num_keys, self.num_reads);
+                spawn(move || {
+                    read_statuses(
+                        num_keys,
+                        num_reads,
+                        |key| Ok(memtable.get_at(key, u64::MAX).cloned()),
+                        |range, direction| Ok(Box::new(memtable.scan(&range, direction))),
+                    )
+                })
+            })
+            .collect();
+
+        let mut sot = HashMap::new();
+        let mut size = 0;
+        for writer in writers {
+            match writer.join() {
+                Ok((writes, writer_size)) => {
+                    sot.extend(writes);
+                    size += writer_size;
+                }
+                Err(_) => panic!("Test failed: a writer panicked"),
+            }
+        }
+        join_readers(readers);
+        for (key, want) in &sot {
+            let got = match memtable.get_at(key, u64::MAX) {
+                Some(KeyStatus::Present(value)) => Some(value.clone()),
+                _ => None,
+            };
+            if got != *want {
+                panic!(
+                    "Test failed: expected {:?} for {}, got {:?}",
+                    want, key, got
+                );
+            }
+        }
+        // every version is still there, and was counted once
+        let num_versions = memtable
+            .scan(&KeyRange::new(..), ScanDirection::Forward)
+            .count();
+        let num_writes = (self.num_writers * self.num_writes) as usize;
+        if num_versions != num_writes {
+            panic!(
+                "Test failed: expected {} versions in the memtable, found {}",
+                num_writes, num_versions
+            );
+        }
+        if memtable.approximate_size() != size {
+            panic!(
+                "Test failed: expected the memtable to take {} bytes, it takes {}",
+                size,
+                memtable.approximate_size()
+            );
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_segment(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        fs::create_dir_all(dir_path).unwrap();
+        println!(
+            "-------Running concurrency test suite for a segment file with {} writers and {} readers-------",
+            self.num_writers, self.num_readers
+        );
+        let factory = Factory {
+            dir_path: dir_path.to_owned(),
+            file_size_threshold: u64::MAX,
+            block_cache: Arc::new(BlockCache::new(64 << 10)),
+            recovery_policy: RecoveryPolicy::Refuse,
+        };
+        // locked the way a segment's file is
+        let locked_file = Arc::new(RwLock::new(factory.new("0.txt").unwrap()));
+        let next_seq = Arc::new(AtomicU64::new(1));
+        let writers: Vec<JoinHandle<HashMap<String, Option<String>>>> = (0..self.num_writers)
+            .map(|writer| {
+                let (locked_file, next_seq) = (Arc::clone(&locked_file), Arc::clone(&next_seq));
+                let (num_keys, num_writes, num_writers) =
+                    (self.num_keys, self.num_writes, self.num_writers);
+                spawn(move || {
+                    write_statuses(writer, num_keys, num_writes, num_writers, |key, status| {
+                        let mut file = locked_file.write().unwrap();
+                        let seq = next_seq.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = file.set_status(key, &status, seq) {
+                            panic!("Test failed: unexpected error in write: {}", e);
+                        }
+                    })
+                })
+            })
+            .collect();
+        let readers: Vec<JoinHandle<()>> = (0..self.num_readers)
+            .map(|_| {
+                let locked_file = Arc::clone(&locked_file);
+                let (num_keys, num_reads) = (self.num_keys, self.num_reads);
+                spawn(move || {
+                    // the scan is only made under the lock, it reads the records after it's let go
+                    read_statuses(
+                        num_keys,
+                        num_reads,
+                        |key| locked_file.read().unwrap().get_status(key),
+                        |range, direction| locked_file.read().unwrap().scan(&range, direction),
+                    )
+                })
+            })
+            .collect();
+
+        let mut sot = HashMap::new();
+        for writer in writers {
+            match writer.join() {
+                Ok(writes) => sot.extend(writes),
+                Err(_) => panic!("Test failed: a writer panicked"),
+            }
+        }
+        join_readers(readers);
+        drop(Arc::into_inner(locked_file).unwrap());
+        // the file is read back from disk, without a hint file as it was never sealed
+        let file = factory.from_disk("0.txt").unwrap();
+        for (key, want) in &sot {
+            let got = match file.get_status(key) {
+                Ok(Some(KeyStatus::Present(value))) => Some(value),
+                Ok(_) => None,
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            };
+            if got != *want {
+                panic!(
+                    "Test failed: expected {:?} for {}, got {:?}",
+                    want, key, got
+                );
+            }
+        }
+        println!("Test passed");
+    }
+}
+
+// like `write`, one version at a time with no batches, through `write_status`
+fn write_statuses(
+    writer: u32,
+    num_keys: u32,
+    num_writes: u32,
+    num_writers: u32,
+    mut write_status: impl FnMut(&str, KeyStatus<String>),
+) -> HashMap<String, Option<String>> {
+    let mut rng = rand::thread_rng();
+    let mut writes = HashMap::new();
+    for version in 0..num_writes {
+        let key = format!(
+            "key{}",
+            rng.gen_range(0..num_keys / num_writers) * num_writers + writer
+        );
+        if rng.gen_ratio(4, 5) {
+            let value = format!("{}:{}", key, version);
+            write_status(&key, KeyStatus::Present(value.clone()));
+            writes.insert(key, Some(value));
+        } else {
+            write_status(&key, KeyStatus::Deleted);
+            writes.insert(key, None);
+        }
+    }
+    writes
+}
+
+// like `read`, over the statuses `get_status` and `scan` give, a scan has every version of a key
+// when it comes from a memtable, ordered from the newest on going forward and from the oldest on
+// going in reverse, and only the latest version when it comes from a segment file
+fn read_statuses(
+    num_keys: u32,
+    num_reads: u32,
+    get_status: impl Fn(&str) -> DbResult<Option<KeyStatus<String>>>,
+    scan: impl Fn(KeyRange, ScanDirection) -> DbResult<StatusIterator<'static>>,
+) {
+    let mut rng = rand::thread_rng();
+    let mut versions: HashMap<String, u32> = HashMap::new();
+    for i in 0..num_reads {
+        let key = format!("key{}", rng.gen_range(0..num_keys));
+        if i % 100 > 0 {
+            match get_status(&key) {
+                Ok(Some(KeyStatus::Present(value))) => check_version(&mut versions, &key, &value),
+                Ok(_) => {}
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            }
+            continue;
+        }
+        let (range, direction) = match i % 200 {
+            0 => (KeyRange::new(key.as_str()..), ScanDirection::Forward),
+            _ => (KeyRange::new(..=key.as_str()), ScanDirection::Reverse),
+        };
+        let entries = match scan(range, direction) {
+            Ok(entries) => entries,
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        };
+        let mut last: Option<(String, Reverse<u64>)> = None;
+        for entry in entries.take(50) {
+            let (key, status, seq) = match entry {
+                Ok(entry) => entry,
+                Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+            };
+            let is_first_version = match &last {
+                Some((last_key, last_seq)) => {
+                    let in_order = match direction {
+                        ScanDirection::Forward => (last_key, *last_seq) < (&key, Reverse(seq)),
+                        ScanDirection::Reverse => (last_key, *last_seq) > (&key, Reverse(seq)),
+                    };
+                    if !in_order {
+                        panic!(
+                            "Test failed: {:?} scan returned {}@{} after {}@{}",
+                            direction, key, seq, last_key, last_seq.0
+                        );
+                    }
+                    *last_key != key
+                }
+                None => true,
+            };
+            // only the newest version of a key, the first one going forward, can be checked
+            // against the last one read, the others only have to belong to the key
+            if let KeyStatus::Present(value) = &status {
+                match (direction, is_first_version) {
+                    (ScanDirection::Forward, true) => check_version(&mut versions, &key, value),
+                    _ => {
+                        parse_version(&key, value);
+                    }
+                }
+            }
+            last = Some((key, Reverse(seq)));
+        }
+    }
+}
+
+fn join_readers(readers: Vec<JoinHandle<()>>) {
+    for reader in readers {
+        if reader.join().is_err() {
+            panic!("Test failed: a reader panicked");
+        }
+    }
+}
+
+// checks that the value was written to the key, and that the key didn't go back to an older
+// version than the last one read
+fn check_version(versions: &mut HashMap<String, u32>, key: &str, value: &str) {
+    let version = parse_version(key, value);
+    let latest = versions.entry(key.to_owned()).or_insert(version);
+    if version < *latest {
+        panic!(
+            "Test failed: {} went back from version {} to {}",
+            key, latest, version
+        );
+    }
+    *latest = version;
+}
+
+// the writer's keys are the ones whose number is the writer's modulo the number of writers,
+// every tenth version goes to a few of them in one batch, returns the last write to each of them
+fn write<T: ConcurrentKVDb>(
+    db: &SharedDb<T>,
+    writer: u32,
+    num_keys: u32,
+    num_writes: u32,
+    num_writers: u32,
+) -> HashMap<String, Option<String>> {
+    let mut rng = rand::thread_rng();
+    let mut writes = HashMap::new();
+    let key_of = |n: u32| format!("key{}", n * num_writers + writer);
+    for version in 0..num_writes {
+        if version % 10 == 0 {
+            let mut batch = WriteBatch::new();
+            for _ in 0..5 {
+                let key = key_of(rng.gen_range(0..num_keys / num_writers));
+                let value = format!("{}:{}", key, version);
+                batch.set(&key, &value);
+                writes.insert(key, Some(value));
+            }
+            if let Err(e) = db.write(&batch) {
+                panic!("Test failed: unexpected error in batch: {}", e);
+            }
+            continue;
+        }
+        let key = key_of(rng.gen_range(0..num_keys / num_writers));
+        if rng.gen_ratio(4, 5) {
+            let value = format!("{}:{}", key, version);
+            if let Err(e) = db.set(&key, &value) {
+                panic!("Test failed: unexpected error in write: {}", e);
+            }
+            writes.insert(key, Some(value));
+        } else {
+            if let Err(e) = db.delete(&key) {
+                panic!("Test failed: unexpected error in delete: {}", e);
+            }
+            writes.insert(key, None);
+        }
+    }
+    writes
+}
+
+// reads random keys, with a short scan every so often
+fn read<T: ConcurrentKVDb>(db: &SharedDb<T>, num_keys: u32, num_reads: u32) {
+    let mut rng = rand::thread_rng();
+    // the latest version of each key the reader saw
+    let mut versions: HashMap<String, u32> = HashMap::new();
+    let mut check = |key: &str, value: &str| check_version(&mut versions, key, value);
+    for i in 0..num_reads {
+        let key = format!("key{}", rng.gen_range(0..num_keys));
+        if i % 100 > 0 {
+            match db.get(&key) {
+                Ok(Some(value)) => check(&key, &value),
+                Ok(None) => {}
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            }
+            continue;
+        }
+        let scan = match db.scan(KeyRange::new(key.as_str()..), ScanDirection::Forward) {
+            Ok(scan) => scan,
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        };
+        let mut last_key: Option<String> = None;
+        for entry in scan.take(20) {
+            let (key, value) = match entry {
+                Ok(entry) => entry,
+                Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+            };
+            if last_key.as_ref().is_some_and(|last_key| *last_key >= key) {
+                panic!(
+                    "Test failed: scan returned {} after {}",
+                    key,
+                    last_key.unwrap()
+                );
+            }
+            check(&key, &value);
+            last_key = Some(key);
+        }
+    }
+}
+
+// the version in a value written to the key
+fn parse_version(key: &str, value: &str) -> u32 {
+    match value
+        .strip_prefix(key)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .and_then(|version| version.parse::<u32>().ok())
+    {
+        Some(version) => version,
+        None => panic!("Test failed: got {} for {}", value, key),
+    }
+}
@@ -5,6 +5,9 @@ use super::{
     Operation, Test,
 };
 
+// number of writes between two manual compactions
+const COMPACTION_INTERVAL: u32 = 10000;
+
 pub struct CorrectnessTest {
     operations: Vec<Operation>,
 }
@@ -48,6 +51,11 @@ impl Test for CorrectnessTest {
                     }
                 }
             }
+            if !matches!(op, Operation::Read(_)) && i % COMPACTION_INTERVAL == 0 {
+                if let Err(e) = db.compact() {
+                    panic!("Test failed: unexpected error in compaction: {}", e);
+                }
+            }
         }
         println!("Test passed");
     }
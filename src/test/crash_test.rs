@@ -0,0 +1,232 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use rand::Rng;
+
+use crate::error::DbResult;
+use crate::kvdb::{write_batch::WriteBatch, KVDb, KeyRange, ScanDirection};
+
+use super::{
+    utils::{generate_random_operations, read_test_cases_from_file, write_test_cases_to_file},
+    Operation,
+};
+
+// passed to the child process, followed by the test's directory, the name of the db, the first
+// operation to apply and the number of operations per batch
+const CHILD_ARG: &str = "--crash-test-child";
+const OPERATIONS_FILE_NAME: &str = "operations.txt";
+const DB_DIR_NAME: &str = "db/";
+
+pub type OpenDb = fn(&str) -> DbResult<Box<dyn KVDb>>;
+
+// Runs the writes in a child process and kills it at a random point, then reopens the db and
+// checks that it holds every write the child finished, plus at most the one it was in the middle
+// of. With batches of more than one operation, the child writes them as atomic batches and the db
+// has to hold either all of the batch in flight or none of it. Its last sequence number can't go
+// back either, and has to have moved on if any write made it. The next round picks up from
+// wherever the db ended up.
+pub struct CrashTest {
+    dir_path: String,
+    num_rounds: u32,
+    batch_size: usize,
+    operations: Vec<Operation>,
+}
+
+impl CrashTest {
+    pub fn new(
+        dir_path: &str,
+        num_keys: u32,
+        num_operations: u32,
+        set_delete_ratio: f32,
+        num_rounds: u32,
+        batch_size: usize,
+    ) -> CrashTest {
+        let operations =
+            generate_random_operations(num_keys, num_operations, 0.0, set_delete_ratio, 0.0, false);
+        CrashTest {
+            dir_path: dir_path.to_owned(),
+            num_rounds,
+            batch_size,
+            operations,
+        }
+    }
+    pub fn run(&self, db_name: &str, open_db: OpenDb) {
+        let _ = fs::remove_dir_all(&self.dir_path);
+        fs::create_dir_all(&self.dir_path).unwrap();
+        write_test_cases_to_file(
+            &self.operations,
+            &(self.dir_path.clone() + OPERATIONS_FILE_NAME),
+        );
+        let db_dir_path = self.dir_path.clone() + DB_DIR_NAME;
+        let description = match open_db(&db_dir_path) {
+            Ok(db) => db.description(),
+            Err(e) => panic!("Test failed: unexpected error in open: {}", e),
+        };
+        println!(
+            "-------Running crash test suite for {} with batches of {} operations-------",
+            description, self.batch_size
+        );
+
+        let mut rng = rand::thread_rng();
+        let mut sot = BTreeMap::new();
+        let mut next_op = 0;
+        let mut last_seq = 0;
+        for _ in 0..self.num_rounds {
+            if next_op == self.operations.len() {
+                break;
+            }
+            let round_start = next_op;
+            let mut child = Command::new(env::current_exe().unwrap())
+                .args([
+                    CHILD_ARG,
+                    &self.dir_path,
+                    db_name,
+                    &next_op.to_string(),
+                    &self.batch_size.to_string(),
+                ])
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            thread::sleep(Duration::from_millis(rng.gen_range(50..300)));
+            let _ = child.kill();
+            child.wait().unwrap();
+            let mut output = String::new();
+            child
+                .stdout
+                .take()
+                .unwrap()
+                .read_to_string(&mut output)
+                .unwrap();
+            // the child prints the index of the last operation of every batch once it's done
+            let num_done = output
+                .lines()
+                .last()
+                .map_or(next_op, |idx| idx.parse::<usize>().unwrap() + 1);
+            for op in &self.operations[next_op..num_done] {
+                apply(&mut sot, op);
+            }
+            next_op = num_done;
+
+            let mut db = match open_db(&db_dir_path) {
+                Ok(db) => db,
+                Err(e) => panic!("Test failed: unexpected error in recovery: {}", e),
+            };
+            let got = read_all(&mut db);
+            if got != sot {
+                // the batch in flight might have made it as well, but only as a whole
+                if next_op == self.operations.len() {
+                    panic!("Test failed: db doesn't match the writes made before the crash");
+                }
+                let batch_end = (next_op + self.batch_size).min(self.operations.len());
+                for op in &self.operations[next_op..batch_end] {
+                    apply(&mut sot, op);
+                }
+                if got != sot {
+                    panic!(
+                        "Test failed: db doesn't match the writes made before the crash at operation {}",
+                        next_op
+                    );
+                }
+                next_op = batch_end;
+            }
+            let got_last_seq = db.last_seq();
+            if got_last_seq < last_seq || (next_op > round_start && got_last_seq == last_seq) {
+                panic!(
+                    "Test failed: last sequence number went from {} to {} after {} writes",
+                    last_seq,
+                    got_last_seq,
+                    next_op - round_start
+                );
+            }
+            last_seq = got_last_seq;
+        }
+        println!("Test passed");
+    }
+    // applies the operations when this process is a child spawned by `run`, returning whether it
+    // is, the db is opened with the function of the same name
+    pub fn run_child_if_spawned(dbs: &[(&str, OpenDb)]) -> bool {
+        let args: Vec<String> = env::args().collect();
+        if args.len() != 6 || args[1] != CHILD_ARG {
+            return false;
+        }
+        let dir_path = &args[2];
+        let (_, open_db) = dbs.iter().find(|(name, _)| *name == args[3]).unwrap();
+        let start: usize = args[4].parse().unwrap();
+        let batch_size: usize = args[5].parse().unwrap();
+        let operations = read_test_cases_from_file(&(dir_path.clone() + OPERATIONS_FILE_NAME));
+        let mut db = open_db(&(dir_path.clone() + DB_DIR_NAME)).unwrap();
+        let mut stdout = io::stdout().lock();
+        for (batch_idx, ops) in operations[start..].chunks(batch_size).enumerate() {
+            if batch_size == 1 {
+                match &ops[0] {
+                    Operation::Set(key, value) => db.set(key, value).unwrap(),
+                    Operation::Delete(key) => db.delete(key).unwrap(),
+                    Operation::Read(_) => continue,
+                }
+            } else {
+                let mut batch = WriteBatch::new();
+                for op in ops {
+                    match op {
+                        Operation::Set(key, value) => batch.set(key, value),
+                        Operation::Delete(key) => batch.delete(key),
+                        Operation::Read(_) => {}
+                    }
+                }
+                db.write(&batch).unwrap();
+            }
+            writeln!(stdout, "{}", start + batch_idx * batch_size + ops.len() - 1).unwrap();
+            stdout.flush().unwrap();
+        }
+        true
+    }
+}
+
+fn apply(sot: &mut BTreeMap<String, String>, op: &Operation) {
+    match op {
+        Operation::Set(key, value) => {
+            sot.insert(key.clone(), value.clone());
+        }
+        Operation::Delete(key) => {
+            sot.remove(key);
+        }
+        Operation::Read(_) => {}
+    }
+}
+
+// everything in the db, checking along the way that both scan directions and point reads agree
+fn read_all(db: &mut Box<dyn KVDb>) -> BTreeMap<String, String> {
+    let mut scans = [ScanDirection::Forward, ScanDirection::Reverse].map(|direction| {
+        match db.scan(KeyRange::new(..), direction) {
+            Ok(iter) => match iter.collect::<DbResult<Vec<(String, String)>>>() {
+                Ok(entries) => entries,
+                Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+            },
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        }
+    });
+    let [forward, reverse] = &mut scans;
+    reverse.reverse();
+    if forward != reverse {
+        panic!("Test failed: forward and reverse scans disagree after recovery");
+    }
+    if forward.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        panic!("Test failed: scan after recovery is out of order");
+    }
+    for (key, value) in forward.iter() {
+        match db.get(key) {
+            Ok(Some(got)) if got == *value => {}
+            Ok(got) => panic!(
+                "Test failed: scan returned {} for key {} but read returned {:?}",
+                value, key, got
+            ),
+            Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+        }
+    }
+    scans[0].drain(..).collect()
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use crate::kvdb::KVDb;
+
+use super::{utils::generate_random_operations, Operation, Test};
+
+// Checks DBs that drop old data, like the ones with FIFO compaction: a read has to return either
+// the latest value of the key or nothing, never an older value or a deleted one.
+pub struct ExpiryTest {
+    operations: Vec<Operation>,
+}
+
+impl Test for ExpiryTest {
+    fn run(&self, db: &mut Box<dyn KVDb>) {
+        println!(
+            "-------Running expiry test suite for {}-------",
+            db.description()
+        );
+        let mut sot = HashMap::new();
+        let (mut num_reads, mut num_expired_reads) = (0, 0);
+        for op in &self.operations {
+            match op {
+                Operation::Set(ref key, ref value) => {
+                    sot.insert(key.clone(), value.clone());
+                    if let Err(e) = db.set(key, value) {
+                        panic!("Test failed: unexpected error in write: {}", e);
+                    }
+                }
+                Operation::Delete(ref key) => {
+                    sot.remove(key);
+                    if let Err(e) = db.delete(key) {
+                        panic!("Test failed: unexpected error in delete: {}", e);
+                    }
+                }
+                Operation::Read(ref key) => {
+                    let got = match db.get(key) {
+                        Ok(got) => got,
+                        Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+                    };
+                    let want = sot.get(key);
+                    match (want, got) {
+                        (_, None) => {
+                            if want.is_some() {
+                                num_expired_reads += 1;
+                            }
+                        }
+                        (Some(want), Some(got)) if *want == got => {}
+                        (want, Some(got)) => panic!(
+                            "Test failed: expected {:?} or nothing for {}, got {}",
+                            want, key, got
+                        ),
+                    }
+                    num_reads += 1;
+                }
+            }
+        }
+        println!(
+            "{} of {} reads found their key expired",
+            num_expired_reads, num_reads
+        );
+        println!("Test passed");
+    }
+}
+
+impl ExpiryTest {
+    pub fn new(
+        num_keys: u32,
+        num_operations: u32,
+        read_write_ratio: f32,
+        set_delete_ratio: f32,
+    ) -> ExpiryTest {
+        ExpiryTest {
+            operations: generate_random_operations(
+                num_keys,
+                num_operations,
+                read_write_ratio,
+                set_delete_ratio,
+                1.0,
+                false,
+            ),
+        }
+    }
+}
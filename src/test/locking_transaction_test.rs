@@ -0,0 +1,225 @@
+use std::{
+    thread::{sleep, spawn, JoinHandle},
+    time::Duration,
+};
+
+use rand::Rng;
+
+use crate::error::{DbResult, Error};
+use crate::kvdb::{
+    locking_transaction::{LockingDb, LockingTransaction},
+    KVDb,
+};
+
+const INITIAL_BALANCE: u64 = 100;
+
+// Transfers money between accounts from a few threads at once, each transfer a locking transaction
+// that reads both accounts for update and writes them, in whichever order they were picked in, so
+// transfers going opposite ways deadlock; a transfer that's picked as a victim or times out is
+// rolled back and retried. Other threads audit all of the accounts, and since an audit holds the
+// lock on every account it read, it has to find the total as it started out. Once every thread is
+// done, the total has to be the same too.
+pub struct LockingTransactionTest {
+    num_accounts: u32,
+    num_transfers: u32,
+    num_audits: u32,
+    num_transferrers: u32,
+    num_auditors: u32,
+}
+
+impl LockingTransactionTest {
+    pub fn new(
+        num_accounts: u32,
+        num_transfers: u32,
+        num_audits: u32,
+        num_transferrers: u32,
+        num_auditors: u32,
+    ) -> Self {
+        LockingTransactionTest {
+            num_accounts,
+            num_transfers,
+            num_audits,
+            num_transferrers,
+            num_auditors,
+        }
+    }
+    pub fn run<T: KVDb + Send + 'static>(&self, db: LockingDb<T>) {
+        println!(
+            "-------Running locking transaction test suite for {} with {} transferrers and {} auditors-------",
+            db.description().unwrap(),
+            self.num_transferrers,
+            self.num_auditors
+        );
+        let mut txn = db.begin().unwrap();
+        for account in 0..self.num_accounts {
+            txn.set(&account_key(account), &INITIAL_BALANCE.to_string())
+                .unwrap();
+        }
+        txn.commit().unwrap();
+        check_deadlock(&db);
+        check_timeout(&db);
+        let total = INITIAL_BALANCE * self.num_accounts as u64;
+
+        let transferrers: Vec<JoinHandle<u32>> = (0..self.num_transferrers)
+            .map(|_| {
+                let (db, num_accounts, num_transfers) =
+                    (db.clone(), self.num_accounts, self.num_transfers);
+                spawn(move || transfer(&db, num_accounts, num_transfers))
+            })
+            .collect();
+        let auditors: Vec<JoinHandle<u32>> = (0..self.num_auditors)
+            .map(|_| {
+                let (db, num_accounts, num_audits) =
+                    (db.clone(), self.num_accounts, self.num_audits);
+                spawn(move || audit(&db, num_accounts, num_audits, total))
+            })
+            .collect();
+
+        let mut num_aborts = 0;
+        for thread in transferrers.into_iter().chain(auditors) {
+            match thread.join() {
+                Ok(thread_aborts) => num_aborts += thread_aborts,
+                Err(_) => panic!("Test failed: a thread panicked"),
+            }
+        }
+        let got: u64 = (0..self.num_accounts)
+            .map(|account| parse_balance(&db.get(&account_key(account)).unwrap().unwrap()))
+            .sum();
+        if got != total {
+            panic!("Test failed: expected a total of {}, got {}", total, got);
+        }
+        println!(
+            "{} transactions were retried after a deadlock or a lock wait timeout",
+            num_aborts
+        );
+        println!("Test passed");
+    }
+}
+
+fn account_key(account: u32) -> String {
+    format!("account{:05}", account)
+}
+
+fn parse_balance(value: &str) -> u64 {
+    match value.parse() {
+        Ok(balance) => balance,
+        Err(_) => panic!("Test failed: {} isn't a balance", value),
+    }
+}
+
+// two transactions that each lock a key the other one then waits for: the younger one is the
+// victim, and once it rolls back the older one gets its lock
+fn check_deadlock<T: KVDb + Send + 'static>(db: &LockingDb<T>) {
+    let (first_key, second_key) = (account_key(0), account_key(1));
+    let mut older = db.begin().unwrap();
+    let mut younger = db.begin().unwrap();
+    older.set(&first_key, "0").unwrap();
+    younger.get(&second_key).unwrap();
+    let older_thread = spawn(move || -> DbResult<()> {
+        older.delete(&account_key(1))?;
+        older.rollback()
+    });
+    // gives the older transaction the time to start waiting, the victim is the same either way
+    sleep(Duration::from_millis(10));
+    match younger.get(&first_key) {
+        Err(Error::Deadlock(_)) => younger.rollback().unwrap(),
+        Ok(_) => panic!("Test failed: a deadlocked transaction got its lock"),
+        Err(e) => panic!("Test failed: expected a deadlock, got {}", e),
+    }
+    match older_thread.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => panic!(
+            "Test failed: unexpected error for the older transaction: {}",
+            e
+        ),
+        Err(_) => panic!("Test failed: the older transaction panicked"),
+    }
+}
+
+// a transaction waiting for a lock that isn't released gives up once the timeout is up
+fn check_timeout<T: KVDb>(db: &LockingDb<T>) {
+    let key = account_key(0);
+    let mut holder = db.begin().unwrap();
+    holder.get(&key).unwrap();
+    let mut waiter = db.begin().unwrap();
+    match waiter.set(&key, "0") {
+        Err(Error::LockTimeout(_)) => {}
+        Ok(()) => {
+            panic!("Test failed: got an exclusive lock on a key locked by another transaction")
+        }
+        Err(e) => panic!("Test failed: expected a lock wait timeout, got {}", e),
+    }
+    drop(waiter);
+    holder.commit().unwrap();
+}
+
+// whether the transaction has to be retried
+fn is_aborted<V>(result: &DbResult<V>) -> bool {
+    match result {
+        Ok(_) => false,
+        Err(Error::Deadlock(_) | Error::LockTimeout(_)) => true,
+        Err(e) => panic!("Test failed: unexpected error in transaction: {}", e),
+    }
+}
+
+fn try_transfer<T: KVDb>(
+    txn: &mut LockingTransaction<T>,
+    from: &str,
+    to: &str,
+    rng: &mut impl Rng,
+) -> DbResult<()> {
+    let from_balance = parse_balance(&txn.get_for_update(from)?.unwrap());
+    let amount = rng.gen_range(0..=from_balance);
+    txn.set(from, &(from_balance - amount).to_string())?;
+    // reads the write above when both are the same account
+    let to_balance = parse_balance(&txn.get_for_update(to)?.unwrap());
+    txn.set(to, &(to_balance + amount).to_string())
+}
+
+// returns the number of retries
+fn transfer<T: KVDb>(db: &LockingDb<T>, num_accounts: u32, num_transfers: u32) -> u32 {
+    let mut rng = rand::thread_rng();
+    let mut num_aborts = 0;
+    for _ in 0..num_transfers {
+        let from = account_key(rng.gen_range(0..num_accounts));
+        let to = account_key(rng.gen_range(0..num_accounts));
+        loop {
+            let mut txn = db.begin().unwrap();
+            if !is_aborted(&try_transfer(&mut txn, &from, &to, &mut rng)) {
+                txn.commit().unwrap();
+                break;
+            }
+            txn.rollback().unwrap();
+            num_aborts += 1;
+        }
+    }
+    num_aborts
+}
+
+// returns the number of retries
+fn audit<T: KVDb>(db: &LockingDb<T>, num_accounts: u32, num_audits: u32, total: u64) -> u32 {
+    let mut num_aborts = 0;
+    let mut num_done = 0;
+    while num_done < num_audits {
+        let mut txn = db.begin().unwrap();
+        let balances = (0..num_accounts)
+            .map(|account| txn.get(&account_key(account)))
+            .collect::<DbResult<Vec<Option<String>>>>();
+        if is_aborted(&balances) {
+            txn.rollback().unwrap();
+            num_aborts += 1;
+            continue;
+        }
+        let got: u64 = balances
+            .unwrap()
+            .iter()
+            .map(|balance| parse_balance(balance.as_ref().unwrap()))
+            .sum();
+        if got != total {
+            panic!("Test failed: expected a total of {}, got {}", total, got);
+        }
+        txn.commit().unwrap();
+        num_done += 1;
+    }
+    num_aborts
+}
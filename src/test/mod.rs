@@ -1,8 +1,21 @@
 use crate::kvdb::KVDb;
 
+pub mod block_cache_test;
+pub mod concurrency_test;
 pub mod correctness_test;
+pub mod crash_test;
+pub mod expiry_test;
 pub mod latency_test;
+pub mod locking_transaction_test;
+pub mod payload_test;
+pub mod recovery_test;
+pub mod scan_test;
+pub mod snapshot_test;
+pub mod transaction_test;
 mod utils;
+pub mod write_batch_test;
+pub mod write_buffer_budget_test;
+pub mod write_stall_test;
 
 pub trait Test {
     fn run(&self, db: &mut Box<dyn KVDb>);
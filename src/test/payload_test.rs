@@ -0,0 +1,146 @@
+use std::{collections::BTreeMap, fs};
+
+use crate::kv_file::KVFile;
+use crate::kvdb::{KVDb, KeyRange, KeyStatus, ScanDirection};
+
+use super::crash_test::OpenDb;
+
+const FILE_NAME: &str = "payloads.txt";
+
+// Writes keys and values that the old text format couldn't hold, commas and newlines, the literal
+// it used for tombstones, JSON and other control characters, and checks they read back the same
+// from a KVFile and from each DB, after enough other writes that they're flushed and merged, and
+// again after reopening. Keys and values are strings, so anything but UTF-8 is out of scope.
+pub struct PayloadTest {
+    payloads: Vec<(String, String)>,
+    // plain writes after the payloads, enough to get them flushed and merged
+    num_filler_keys: u32,
+}
+
+impl PayloadTest {
+    pub fn new(num_filler_keys: u32) -> Self {
+        let payloads = [
+            ("key,with,commas", "value"),
+            ("key", "value,with,commas"),
+            ("key\nwith\nnewlines", "value"),
+            ("newlines", "value\nwith\nnewlines\n"),
+            ("tombstone", "🪦"),
+            ("🪦", "tombstone as the key"),
+            ("json", r#"{"a": [1, 2], "b": "c,d\n", "e": null}"#),
+            ("control characters", "\0\r\n\t\u{7f}"),
+            ("empty value", ""),
+        ];
+        let mut payloads: Vec<(String, String)> = payloads
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        payloads.push(("long value".to_owned(), "🪦,\n".repeat(100)));
+        PayloadTest {
+            payloads,
+            num_filler_keys,
+        }
+    }
+    pub fn run_with_kv_file(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        fs::create_dir_all(dir_path).unwrap();
+        println!("-------Running payload test suite for a KV file-------");
+        let mut file = KVFile::new(dir_path, FILE_NAME).unwrap();
+        let mut want = vec![];
+        for (seq, (key, value)) in self.payloads.iter().enumerate() {
+            let status = KeyStatus::Present(value.clone());
+            file.append_line(key, &status, seq as u64).unwrap();
+            want.push((key.clone(), Some(value.clone())));
+        }
+        let seq = self.payloads.len() as u64;
+        file.append_line("🪦", &KeyStatus::Deleted, seq).unwrap();
+        want.push(("🪦".to_owned(), None));
+
+        let file = KVFile::new(dir_path, FILE_NAME).unwrap();
+        let got: Vec<(String, Option<String>)> = file
+            .iter()
+            .unwrap()
+            .map(|line| {
+                let line = line.unwrap_or_else(|e| panic!("Test failed: error in read: {}", e));
+                match line.status {
+                    KeyStatus::Present(value) => (line.key, Some(value)),
+                    KeyStatus::Deleted => (line.key, None),
+                }
+            })
+            .collect();
+        if got != want {
+            panic!(
+                "Test failed: expected {:?} from the KV file, got {:?}",
+                want, got
+            );
+        }
+        println!("Test passed");
+    }
+    pub fn run(&self, dir_path: &str, open_db: OpenDb) {
+        let _ = fs::remove_dir_all(dir_path);
+        let mut db = open_db(dir_path).unwrap();
+        println!(
+            "-------Running payload test suite for {}-------",
+            db.description()
+        );
+        let mut sot = BTreeMap::new();
+        for (key, value) in &self.payloads {
+            sot.insert(key.clone(), value.clone());
+            db.set(key, value).unwrap();
+        }
+        db.delete("🪦").unwrap();
+        sot.remove("🪦");
+        for i in 0..self.num_filler_keys {
+            let (key, value) = (format!("filler,{}", i), format!("filler\n{}", i));
+            db.set(&key, &value).unwrap();
+            sot.insert(key, value);
+        }
+        check(&mut db, &sot, "");
+        drop(db);
+
+        let mut db = open_db(dir_path).unwrap();
+        check(&mut db, &sot, " after reopening");
+        println!("Test passed");
+    }
+}
+
+fn check(db: &mut Box<dyn KVDb>, sot: &BTreeMap<String, String>, when: &str) {
+    for key in ["🪦", "no such key"] {
+        match db.get(key) {
+            Ok(None) => {}
+            Ok(got) => panic!(
+                "Test failed: expected no value for {:?}{}, got {:?}",
+                key, when, got
+            ),
+            Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+        }
+    }
+    for (key, want) in sot {
+        match db.get(key) {
+            Ok(Some(ref got)) if got == want => {}
+            Ok(got) => panic!(
+                "Test failed: expected {:?} for {:?}{}, got {:?}",
+                want, key, when, got
+            ),
+            Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+        }
+    }
+    let want: Vec<(String, String)> = sot
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let got: Vec<(String, String)> = match db.scan(KeyRange::new(..), ScanDirection::Forward) {
+        Ok(iter) => match iter.collect() {
+            Ok(got) => got,
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        },
+        Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+    };
+    if got != want {
+        panic!(
+            "Test failed: expected {} pairs in the scan{}, got {}",
+            want.len(),
+            when,
+            got.len()
+        );
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, OpenOptions},
+    os::unix::fs::FileExt,
+    sync::Arc,
+};
+
+use crate::btree_db::BTreeDb;
+use crate::error::{DbResult, Error};
+use crate::kv_file::{KVFile, RecoveryPolicy};
+use crate::kvdb::{KVDb, KeyRange, KeyStatus, ScanDirection};
+use crate::log_with_index_db::LogWithIndexDb;
+use crate::segmented_files_db::{block_cache::BlockCache, compaction::MergeAllCompaction};
+use crate::segmented_logs_with_indices_db::SegmentedLogsWithIndicesDb;
+use crate::utils::process_dir_contents;
+
+const FILE_NAME: &str = "log.txt";
+const MANIFEST_FILE_NAME: &str = "manifest.txt";
+const BTREE_FILE_NAME: &str = "btree.txt";
+const WAL_FILE_NAME: &str = "btree.wal";
+
+// Writes a log and damages it the two ways it can be found on open: cut off in the middle of its
+// last record, as an interrupted append leaves it, and with the length of a record in the middle
+// overwritten. The torn tail is refused or truncated depending on the recovery policy, while the
+// overwritten length is reported as corruption at the record's offset under either policy rather
+// than taken for a torn tail that everything after it is dropped with. The manifest of a segmented
+// DB gets the same overwritten length, which must not be taken for a torn tail either, as the
+// segments added after it would then be deleted on open as unlisted, and so does the WAL of a
+// B+tree DB, whose records after it would be dropped. Last, a segment damaged while the DB is open
+// makes the merge it goes into fail, and the writes after that have to fail with the merge's error.
+// Hint files that are missing, cut short or damaged are passed over for a scan of their segments,
+// which has to give back the same data. And a segment file the manifest doesn't list is deleted on
+// open, without its records showing up, while no file number is ever given out twice, across
+// merges and reopens and not even the orphan's.
+pub struct RecoveryTest {
+    num_records: u32,
+}
+
+impl RecoveryTest {
+    pub fn new(num_records: u32) -> Self {
+        RecoveryTest { num_records }
+    }
+    pub fn run(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        println!(
+            "-------Running recovery test suite for a log of {} records-------",
+            self.num_records
+        );
+        let file_path = format!("{}{}", dir_path, FILE_NAME);
+        let mut db = open(dir_path, RecoveryPolicy::Refuse).unwrap();
+        let mut offsets = vec![];
+        for record in 0..self.num_records {
+            offsets.push(fs::metadata(&file_path).unwrap().len());
+            db.set(&record_key(record), &record_value(record)).unwrap();
+        }
+        drop(db);
+        let size = fs::metadata(&file_path).unwrap().len();
+
+        let last_offset = *offsets.last().unwrap();
+        damage(&file_path, |file| file.set_len(size - 3).unwrap());
+        match open(dir_path, RecoveryPolicy::Refuse) {
+            Err(Error::TornTail(_, offset)) if offset == last_offset => {}
+            Err(e) => panic!(
+                "Test failed: expected a torn tail at {}, got {}",
+                last_offset, e
+            ),
+            Ok(_) => panic!("Test failed: opened a log with a torn tail under the refuse policy"),
+        }
+        let mut db = open(dir_path, RecoveryPolicy::TruncateTornTail).unwrap();
+        for record in 0..self.num_records {
+            let want = (record + 1 < self.num_records).then(|| record_value(record));
+            match db.get(&record_key(record)) {
+                Ok(got) if got == want => {}
+                Ok(got) => panic!(
+                    "Test failed: expected {:?} for {} after truncating the torn tail, got {:?}",
+                    want,
+                    record_key(record),
+                    got
+                ),
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            }
+        }
+        drop(db);
+        let size = fs::metadata(&file_path).unwrap().len();
+        if size != last_offset {
+            panic!(
+                "Test failed: expected the log to be truncated to {} bytes, it has {}",
+                last_offset, size
+            );
+        }
+
+        // the payload length, the 4 bytes after the payload checksum, now runs past the end
+        let middle_offset = offsets[offsets.len() / 2];
+        damage(&file_path, |file| {
+            file.write_all_at(&u32::MAX.to_le_bytes(), middle_offset + 4)
+                .unwrap()
+        });
+        for policy in [RecoveryPolicy::Refuse, RecoveryPolicy::TruncateTornTail] {
+            match open(dir_path, policy) {
+                Err(Error::Corrupted(_, offset, _)) if offset == middle_offset => {}
+                Err(e) => panic!(
+                    "Test failed: expected a corrupted record at {} under {:?}, got {}",
+                    middle_offset, policy, e
+                ),
+                Ok(_) => panic!(
+                    "Test failed: opened a log with a corrupted record under {:?}",
+                    policy
+                ),
+            }
+        }
+        if fs::metadata(&file_path).unwrap().len() != size {
+            panic!("Test failed: the log with a corrupted record was truncated");
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_manifest(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        println!(
+            "-------Running recovery test suite for the manifest of a segmented DB of {} records-------",
+            self.num_records
+        );
+        let mut db = open_segmented(dir_path, RecoveryPolicy::Refuse).unwrap();
+        for record in 0..self.num_records {
+            db.set(&record_key(record), &record_value(record)).unwrap();
+        }
+        drop(db);
+
+        // the offset of every edit, walking the manifest by the lengths in the record headers
+        let file_path = format!("{}{}", dir_path, MANIFEST_FILE_NAME);
+        let bytes = fs::read(&file_path).unwrap();
+        let mut offsets = vec![];
+        let mut pos = 0;
+        while pos < bytes.len() {
+            offsets.push(pos as u64);
+            let payload_len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap());
+            pos += 12 + payload_len as usize;
+        }
+        if offsets.len() < 3 {
+            panic!(
+                "Test failed: expected at least 3 edits in the manifest, found {}",
+                offsets.len()
+            );
+        }
+        let segment_files = list_segment_files(dir_path);
+
+        let middle_offset = offsets[offsets.len() / 2];
+        damage(&file_path, |file| {
+            file.write_all_at(&u32::MAX.to_le_bytes(), middle_offset + 4)
+                .unwrap()
+        });
+        for policy in [RecoveryPolicy::Refuse, RecoveryPolicy::TruncateTornTail] {
+            match open_segmented(dir_path, policy) {
+                Err(Error::Corrupted(_, offset, _)) if offset == middle_offset => {}
+                Err(e) => panic!(
+                    "Test failed: expected a corrupted manifest edit at {} under {:?}, got {}",
+                    middle_offset, policy, e
+                ),
+                Ok(_) => panic!(
+                    "Test failed: opened a DB with a corrupted manifest under {:?}",
+                    policy
+                ),
+            }
+        }
+        if list_segment_files(dir_path) != segment_files {
+            panic!(
+                "Test failed: segment files changed when opening a DB with a corrupted manifest"
+            );
+        }
+        if fs::read(&file_path).unwrap().len() != bytes.len() {
+            panic!("Test failed: the corrupted manifest was rewritten");
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_failing_merge(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        println!(
+            "-------Running recovery test suite for a failing merge in a segmented DB of {} records-------",
+            self.num_records
+        );
+        let mut db = open_segmented_with_merging_threshold(dir_path, 3).unwrap();
+        let mut damaged = false;
+        let mut failed = false;
+        for record in 0..self.num_records {
+            // the oldest segment is damaged once it's sealed, before the merge it goes into
+            let file_numbers = list_file_numbers(dir_path);
+            if !damaged && file_numbers.len() >= 3 {
+                let oldest = file_numbers.first().unwrap();
+                damage(&format!("{}{}.txt", dir_path, oldest), |file| {
+                    file.write_all_at(b"garbage", 20).unwrap()
+                });
+                damaged = true;
+            }
+            match db.set(&record_key(record), &record_value(record)) {
+                Ok(()) => {}
+                Err(Error::Wrapped(ref msg, _)) if msg == "error in merging segments" => {
+                    failed = true;
+                    break;
+                }
+                Err(e) => panic!("Test failed: unexpected error in write: {}", e),
+            }
+        }
+        if !failed {
+            panic!("Test failed: no write failed after the merge did");
+        }
+        match db.set("key_after_failure", "value") {
+            Err(Error::Wrapped(ref msg, _)) if msg == "error in merging segments" => {}
+            Err(e) => panic!("Test failed: unexpected error in write: {}", e),
+            Ok(()) => panic!("Test failed: a write went through after a merge failed"),
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_hint_files(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        println!(
+            "-------Running recovery test suite for the hint files of a segmented DB of {} records-------",
+            self.num_records
+        );
+        let mut db = open_segmented(dir_path, RecoveryPolicy::Refuse).unwrap();
+        // keys are overwritten and deleted, so a segment's index isn't just its records in order
+        let num_keys = (self.num_records / 4).max(1);
+        for record in 0..self.num_records {
+            let key = record_key(record % num_keys);
+            match record % 7 {
+                0 => db.delete(&key).unwrap(),
+                _ => db.set(&key, &record_value(record)).unwrap(),
+            }
+        }
+        let want = read_all(&mut db);
+        let last_seq = db.last_seq();
+        drop(db);
+
+        let hint_files: Vec<String> = list_segment_files(dir_path)
+            .into_iter()
+            .filter(|file_name| file_name.ends_with(".hint"))
+            .collect();
+        if hint_files.len() < 3 {
+            panic!(
+                "Test failed: expected at least 3 hint files, found {}",
+                hint_files.len()
+            );
+        }
+        let hint_file_path = |i: usize| format!("{}{}", dir_path, hint_files[i]);
+        fs::remove_file(hint_file_path(0)).unwrap();
+        damage(&hint_file_path(1), |file| {
+            let size = file.metadata().unwrap().len();
+            file.set_len(size / 2).unwrap()
+        });
+        damage(&hint_file_path(2), |file| {
+            file.write_all_at(b"garbage", 8).unwrap()
+        });
+
+        let mut db = open_segmented(dir_path, RecoveryPolicy::Refuse).unwrap();
+        let got = read_all(&mut db);
+        if got != want {
+            panic!(
+                "Test failed: expected {} pairs after reopening without some hint files, got {}",
+                want.len(),
+                got.len()
+            );
+        }
+        if db.last_seq() != last_seq {
+            panic!(
+                "Test failed: expected the last sequence number to be {} after reopening, got {}",
+                last_seq,
+                db.last_seq()
+            );
+        }
+        drop(db);
+        // the segments that were scanned get their hint files back
+        if !fs::exists(hint_file_path(0)).unwrap() {
+            panic!("Test failed: the deleted hint file wasn't written again");
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_file_numbers(&self, dir_path: &str, num_reopens: u32) {
+        let _ = fs::remove_dir_all(dir_path);
+        println!(
+            "-------Running recovery test suite for the file numbers of a segmented DB of {} records, reopened {} times-------",
+            self.num_records, num_reopens
+        );
+        // every file number seen on disk, and the ones whose files were deleted since
+        let mut seen = BTreeSet::new();
+        let mut gone = BTreeSet::new();
+        let observe = |seen: &mut BTreeSet<usize>, gone: &mut BTreeSet<usize>| {
+            let file_numbers = list_file_numbers(dir_path);
+            if let Some(file_number) = file_numbers.intersection(gone).next() {
+                panic!(
+                    "Test failed: file number {} was given out again",
+                    file_number
+                );
+            }
+            gone.extend(seen.difference(&file_numbers));
+            seen.extend(file_numbers);
+        };
+        let mut orphan_file_number = None;
+        for round in 0..=num_reopens {
+            let mut db = open_segmented_with_merging_threshold(dir_path, 3).unwrap();
+            if let Some(orphan_file_number) = orphan_file_number {
+                if list_file_numbers(dir_path).contains(&orphan_file_number) {
+                    panic!("Test failed: the orphan segment file wasn't deleted on open");
+                }
+                if let Ok(Some(value)) = db.get("orphan") {
+                    panic!("Test failed: read {:?} from the orphan segment file", value);
+                }
+            }
+            observe(&mut seen, &mut gone);
+            for record in 0..self.num_records {
+                db.set(&record_key(record), &record_value(round * record))
+                    .unwrap();
+                observe(&mut seen, &mut gone);
+            }
+            drop(db);
+            observe(&mut seen, &mut gone);
+
+            // a segment file that no manifest edit lists, as a crash leaves one that a merge or a
+            // flush was writing, with a number past every other one
+            let file_number = seen.last().unwrap() + 5;
+            let mut orphan = KVFile::new(dir_path, &format!("{}.txt", file_number)).unwrap();
+            orphan
+                .append_line("orphan", &KeyStatus::Present("value".to_owned()), u64::MAX)
+                .unwrap();
+            orphan_file_number = Some(file_number);
+            observe(&mut seen, &mut gone);
+        }
+        let orphan_file_number = orphan_file_number.unwrap();
+        let mut db = open_segmented_with_merging_threshold(dir_path, 3).unwrap();
+        db.set("last", "value").unwrap();
+        drop(db);
+        observe(&mut seen, &mut gone);
+        if !gone.contains(&orphan_file_number) {
+            panic!("Test failed: the orphan segment file wasn't deleted on open");
+        }
+        if seen.last() == Some(&orphan_file_number) {
+            panic!("Test failed: no segment file was created after the orphan was deleted");
+        }
+        println!("Test passed");
+    }
+    pub fn run_with_wal(&self, dir_path: &str) {
+        let _ = fs::remove_dir_all(dir_path);
+        println!(
+            "-------Running recovery test suite for the WAL of a B+tree DB of {} records-------",
+            self.num_records
+        );
+        let mut db = open_btree(dir_path, RecoveryPolicy::Refuse).unwrap();
+        for record in 0..self.num_records {
+            db.set(&record_key(record), &record_value(record)).unwrap();
+        }
+        // the WAL is emptied by the checkpoint on close, so it's kept as it was before that
+        let file_path = format!("{}{}", dir_path, WAL_FILE_NAME);
+        let mut bytes = fs::read(&file_path).unwrap();
+        drop(db);
+
+        // the offset of every record after the WAL header, walking the WAL by the lengths in the
+        // record headers
+        let mut offsets = vec![];
+        let mut pos = 8;
+        while pos < bytes.len() {
+            offsets.push(pos);
+            let payload_len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap());
+            pos += 12 + payload_len as usize;
+        }
+        let middle_offset = offsets[offsets.len() / 2];
+        bytes[middle_offset + 4..middle_offset + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&file_path, &bytes).unwrap();
+        for policy in [RecoveryPolicy::Refuse, RecoveryPolicy::TruncateTornTail] {
+            match open_btree(dir_path, policy) {
+                Err(Error::Corrupted(_, offset, _)) if offset == middle_offset as u64 => {}
+                Err(e) => panic!(
+                    "Test failed: expected a corrupted WAL record at {} under {:?}, got {}",
+                    middle_offset, policy, e
+                ),
+                Ok(_) => panic!(
+                    "Test failed: opened a DB with a corrupted WAL under {:?}",
+                    policy
+                ),
+            }
+        }
+        if fs::read(&file_path).unwrap() != bytes {
+            panic!("Test failed: the corrupted WAL was changed");
+        }
+        println!("Test passed");
+    }
+}
+
+fn open(dir_path: &str, policy: RecoveryPolicy) -> DbResult<LogWithIndexDb> {
+    LogWithIndexDb::new(dir_path, FILE_NAME, 0.5, policy)
+}
+
+fn open_segmented(dir_path: &str, policy: RecoveryPolicy) -> DbResult<SegmentedLogsWithIndicesDb> {
+    SegmentedLogsWithIndicesDb::new(
+        dir_path,
+        1000,
+        Box::new(MergeAllCompaction {
+            merging_threshold: usize::MAX,
+        }),
+        Arc::new(BlockCache::new(1 << 20)),
+        policy,
+    )
+}
+
+fn open_segmented_with_merging_threshold(
+    dir_path: &str,
+    merging_threshold: usize,
+) -> DbResult<SegmentedLogsWithIndicesDb> {
+    SegmentedLogsWithIndicesDb::new(
+        dir_path,
+        1000,
+        Box::new(MergeAllCompaction { merging_threshold }),
+        Arc::new(BlockCache::new(1 << 20)),
+        RecoveryPolicy::Refuse,
+    )
+}
+
+fn open_btree(dir_path: &str, policy: RecoveryPolicy) -> DbResult<BTreeDb> {
+    BTreeDb::new(dir_path, BTREE_FILE_NAME, 4096, 64, 1 << 30, policy)
+}
+
+fn list_segment_files(dir_path: &str) -> BTreeSet<String> {
+    let mut file_names = BTreeSet::new();
+    process_dir_contents(dir_path, &mut |path| {
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        if file_name != MANIFEST_FILE_NAME {
+            file_names.insert(file_name);
+        }
+        Ok(())
+    })
+    .unwrap();
+    file_names
+}
+
+fn list_file_numbers(dir_path: &str) -> BTreeSet<usize> {
+    list_segment_files(dir_path)
+        .iter()
+        .filter_map(|file_name| file_name.strip_suffix(".txt")?.parse().ok())
+        .collect()
+}
+
+// every key along with its value, both read one by one and scanned
+fn read_all(db: &mut SegmentedLogsWithIndicesDb) -> Vec<(String, String)> {
+    let scanned: Vec<(String, String)> = db
+        .scan(KeyRange::new(..), ScanDirection::Forward)
+        .unwrap()
+        .collect::<DbResult<_>>()
+        .unwrap_or_else(|e| panic!("Test failed: unexpected error in scan: {}", e));
+    for (key, value) in &scanned {
+        match db.get(key) {
+            Ok(Some(ref got)) if got == value => {}
+            Ok(got) => panic!(
+                "Test failed: expected {:?} for {} from a lookup, got {:?}",
+                value, key, got
+            ),
+            Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+        }
+    }
+    scanned
+}
+
+fn damage(file_path: &str, f: impl FnOnce(&fs::File)) {
+    let file = OpenOptions::new().write(true).open(file_path).unwrap();
+    f(&file);
+    file.sync_all().unwrap();
+}
+
+fn record_key(record: u32) -> String {
+    format!("key{:05}", record)
+}
+
+fn record_value(record: u32) -> String {
+    format!("value{}", record)
+}
@@ -0,0 +1,149 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use rand::Rng;
+
+use crate::kvdb::{KVDb, KeyRange, ScanDirection};
+
+use super::{utils::generate_random_operations, Operation, Test};
+
+// number of writes between two scans
+const SCAN_INTERVAL: usize = 1000;
+
+pub struct ScanTest {
+    num_keys: u32,
+    operations: Vec<Operation>,
+}
+
+impl Test for ScanTest {
+    fn run(&self, db: &mut Box<dyn KVDb>) {
+        println!(
+            "-------Running scan test suite for {}-------",
+            db.description()
+        );
+        let mut sot = BTreeMap::new();
+        let mut num_writes = 0;
+        for op in &self.operations {
+            match op {
+                Operation::Set(ref key, ref value) => {
+                    sot.insert(key.clone(), value.clone());
+                    if let Err(e) = db.set(key, value) {
+                        panic!("Test failed: unexpected error in write: {}", e);
+                    }
+                }
+                Operation::Delete(ref key) => {
+                    sot.remove(key);
+                    if let Err(e) = db.delete(key) {
+                        panic!("Test failed: unexpected error in delete: {}", e);
+                    }
+                }
+                Operation::Read(_) => continue,
+            }
+            num_writes += 1;
+            if num_writes % SCAN_INTERVAL == 0 {
+                check_scan(db, &sot, self.random_range());
+                check_prefix_scan(db, &sot, &self.random_prefix());
+            }
+        }
+        check_scan(db, &sot, KeyRange::new(..));
+        check_prefix_scan(db, &sot, "");
+        println!("Test passed");
+    }
+}
+
+impl ScanTest {
+    pub fn new(
+        num_keys: u32,
+        num_operations: u32,
+        set_delete_ratio: f32,
+        save_test_case: bool,
+    ) -> ScanTest {
+        let operations = generate_random_operations(
+            num_keys,
+            num_operations,
+            0.0,
+            set_delete_ratio,
+            0.0,
+            save_test_case,
+        );
+        ScanTest {
+            num_keys,
+            operations,
+        }
+    }
+    fn random_range(&self) -> KeyRange {
+        let mut rng = rand::thread_rng();
+        let mut keys = [0, 1].map(|_| format!("key{}", rng.gen_range(1..=self.num_keys)));
+        keys.sort();
+        let [start, end] = keys;
+        KeyRange {
+            start: match rng.gen_range(0..4) {
+                0 => Bound::Unbounded,
+                1 => Bound::Excluded(start),
+                _ => Bound::Included(start),
+            },
+            end: match rng.gen_range(0..4) {
+                0 => Bound::Unbounded,
+                1 => Bound::Included(end),
+                _ => Bound::Excluded(end),
+            },
+        }
+    }
+    // e.g. "key1", "key12" or "key123"
+    fn random_prefix(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let key = format!("key{}", rng.gen_range(1..=self.num_keys));
+        key[..rng.gen_range(3..=key.len())].to_string()
+    }
+}
+
+fn check_scan(db: &mut Box<dyn KVDb>, sot: &BTreeMap<String, String>, range: KeyRange) {
+    let mut want: Vec<(String, String)> = match range.is_empty() {
+        true => vec![],
+        false => sot
+            .range::<str, _>(range.as_bounds())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect(),
+    };
+    for direction in [ScanDirection::Forward, ScanDirection::Reverse] {
+        let got: Vec<(String, String)> = match db.scan(range.clone(), direction) {
+            Ok(iter) => match iter.collect() {
+                Ok(got) => got,
+                Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+            },
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        };
+        if got != want {
+            panic!(
+                "Test failed: expected {} pairs in {:?} scan of {:?}, got {}",
+                want.len(),
+                direction,
+                range,
+                got.len()
+            );
+        }
+        want.reverse();
+    }
+}
+
+fn check_prefix_scan(db: &mut Box<dyn KVDb>, sot: &BTreeMap<String, String>, prefix: &str) {
+    let want: Vec<(String, String)> = sot
+        .iter()
+        .filter(|(key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let got: Vec<(String, String)> = match db.scan_prefix(prefix) {
+        Ok(iter) => match iter.collect() {
+            Ok(got) => got,
+            Err(e) => panic!("Test failed: unexpected error in prefix scan: {}", e),
+        },
+        Err(e) => panic!("Test failed: unexpected error in prefix scan: {}", e),
+    };
+    if got != want {
+        panic!(
+            "Test failed: expected {} pairs under prefix {:?}, got {}",
+            want.len(),
+            prefix,
+            got.len()
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::spawn;
+
+use crate::kvdb::{KVDb, KeyRange, ScanDirection, Snapshot};
+
+use super::{utils::generate_random_operations, Operation, Test};
+
+type Pairs = BTreeMap<String, String>;
+
+// Takes snapshots every so often while the operations are written, over few enough keys that each
+// is overwritten many times, and checks every live snapshot's reads and scans against the pairs
+// there were when it was taken, keeping a few of them alive at a time so that flushes and merges
+// run both with and without versions to keep. The first snapshot lives through all of it, and a
+// thread keeps checking it while the writes go on.
+pub struct SnapshotTest {
+    num_snapshots: u32,
+    max_live_snapshots: usize,
+    operations: Vec<Operation>,
+}
+
+impl Test for SnapshotTest {
+    fn run(&self, db: &mut Box<dyn KVDb>) {
+        println!(
+            "-------Running snapshot test suite for {} with {} snapshots, up to {} of them live at a time-------",
+            db.description(),
+            self.num_snapshots,
+            self.max_live_snapshots
+        );
+        let mut sot = Pairs::new();
+        let is_done = Arc::new(AtomicBool::new(false));
+        let mut checker = None;
+        let snapshot_interval = self.operations.len() / self.num_snapshots as usize;
+        let mut snapshots: VecDeque<(Box<dyn Snapshot + Send>, Pairs)> = VecDeque::new();
+        for (idx, op) in self.operations.iter().enumerate() {
+            let result = match op {
+                Operation::Set(ref key, ref value) => {
+                    sot.insert(key.clone(), value.clone());
+                    db.set(key, value)
+                }
+                Operation::Delete(ref key) => {
+                    sot.remove(key);
+                    db.delete(key)
+                }
+                Operation::Read(ref key) => match db.get(key) {
+                    Ok(got) if got == sot.get(key).cloned() => Ok(()),
+                    Ok(got) => panic!(
+                        "Test failed: expected {:?} value for key {}, got {:?}",
+                        sot.get(key),
+                        key,
+                        got
+                    ),
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = result {
+                panic!("Test failed: unexpected error in operation: {}", e);
+            }
+            if (idx + 1) % snapshot_interval == 0 {
+                if checker.is_none() {
+                    let (first_snapshot, first_pairs) = (take_snapshot(db), sot.clone());
+                    let is_done = Arc::clone(&is_done);
+                    checker = Some(spawn(move || {
+                        let mut num_checks = 0;
+                        while !is_done.load(Ordering::Relaxed) || num_checks == 0 {
+                            check_snapshot(&*first_snapshot, &first_pairs);
+                            num_checks += 1;
+                        }
+                        num_checks
+                    }));
+                }
+                if snapshots.len() == self.max_live_snapshots {
+                    snapshots.pop_front();
+                }
+                snapshots.push_back((take_snapshot(db), sot.clone()));
+                for (snapshot, pairs) in &snapshots {
+                    check_snapshot(&**snapshot, pairs);
+                }
+            }
+        }
+
+        is_done.store(true, Ordering::Relaxed);
+        match checker.unwrap().join() {
+            Ok(num_checks) => println!("Checked the first snapshot {} times", num_checks),
+            Err(_) => panic!("Test failed: the thread checking the first snapshot panicked"),
+        }
+        println!("Test passed");
+    }
+}
+
+impl SnapshotTest {
+    pub fn new(
+        num_keys: u32,
+        num_operations: u32,
+        read_write_ratio: f32,
+        set_delete_ratio: f32,
+        num_snapshots: u32,
+        max_live_snapshots: usize,
+    ) -> SnapshotTest {
+        SnapshotTest {
+            num_snapshots,
+            max_live_snapshots,
+            operations: generate_random_operations(
+                num_keys,
+                num_operations,
+                read_write_ratio,
+                set_delete_ratio,
+                0.9,
+                false,
+            ),
+        }
+    }
+}
+
+fn take_snapshot(db: &mut Box<dyn KVDb>) -> Box<dyn Snapshot + Send> {
+    let snapshot = match db.snapshot() {
+        Ok(snapshot) => snapshot,
+        Err(e) => panic!("Test failed: unexpected error in taking snapshot: {}", e),
+    };
+    if snapshot.seq() != db.last_seq() {
+        panic!(
+            "Test failed: snapshot taken at {} after the write at {}",
+            snapshot.seq(),
+            db.last_seq()
+        );
+    }
+    snapshot
+}
+
+// reads back every pair, a few keys that aren't there, and scans the whole range both ways
+fn check_snapshot(snapshot: &dyn Snapshot, want: &Pairs) {
+    let missing_keys = ["key0".to_owned(), "key".to_owned(), "zzz".to_owned()];
+    for key in want.keys().chain(&missing_keys) {
+        match snapshot.get(key) {
+            Ok(got) if got.as_ref() == want.get(key) => {}
+            Ok(got) => panic!(
+                "Test failed: expected {:?} value for key {} in snapshot at {}, got {:?}",
+                want.get(key),
+                key,
+                snapshot.seq(),
+                got
+            ),
+            Err(e) => panic!("Test failed: unexpected error in snapshot read: {}", e),
+        }
+    }
+    for direction in [ScanDirection::Forward, ScanDirection::Reverse] {
+        let got = match snapshot.scan(KeyRange::new(..), direction) {
+            Ok(iter) => iter.collect::<Result<Vec<(String, String)>, _>>(),
+            Err(e) => panic!("Test failed: unexpected error in snapshot scan: {}", e),
+        };
+        let mut want: Vec<(String, String)> = want.clone().into_iter().collect();
+        if direction == ScanDirection::Reverse {
+            want.reverse();
+        }
+        match got {
+            Ok(got) if got == want => {}
+            Ok(_) => panic!(
+                "Test failed: {:?} scan of snapshot at {} doesn't match the pairs it was taken over",
+                direction,
+                snapshot.seq()
+            ),
+            Err(e) => panic!("Test failed: unexpected error in snapshot scan: {}", e),
+        }
+    }
+}
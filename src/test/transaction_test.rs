@@ -0,0 +1,202 @@
+use std::thread::{spawn, JoinHandle};
+
+use rand::Rng;
+
+use crate::error::Error;
+use crate::kvdb::{
+    transaction::{Transaction, TransactionalDb},
+    KVDb, KeyRange, ScanDirection,
+};
+
+const INITIAL_BALANCE: u64 = 100;
+
+// Transfers money between accounts from a few threads at once, each transfer a transaction that
+// reads both balances and writes them back, retried whenever it conflicts with another one. Other
+// threads audit the accounts in read-only transactions that read all of them, and every audit
+// that commits has to find the total as it started out. Once every thread is done, the total has
+// to be the same too.
+pub struct TransactionTest {
+    num_accounts: u32,
+    num_transfers: u32,
+    num_audits: u32,
+    num_transferrers: u32,
+    num_auditors: u32,
+}
+
+impl TransactionTest {
+    pub fn new(
+        num_accounts: u32,
+        num_transfers: u32,
+        num_audits: u32,
+        num_transferrers: u32,
+        num_auditors: u32,
+    ) -> Self {
+        TransactionTest {
+            num_accounts,
+            num_transfers,
+            num_audits,
+            num_transferrers,
+            num_auditors,
+        }
+    }
+    pub fn run<T: KVDb + Send + 'static>(&self, db: TransactionalDb<T>) {
+        println!(
+            "-------Running transaction test suite for {} with {} transferrers and {} auditors-------",
+            db.description().unwrap(),
+            self.num_transferrers,
+            self.num_auditors
+        );
+        for account in 0..self.num_accounts {
+            if let Err(e) = db.set(&account_key(account), &INITIAL_BALANCE.to_string()) {
+                panic!("Test failed: unexpected error in write: {}", e);
+            }
+        }
+        check_conflicts(&db, self.num_accounts);
+        let total = INITIAL_BALANCE * self.num_accounts as u64;
+
+        let transferrers: Vec<JoinHandle<u32>> = (0..self.num_transferrers)
+            .map(|_| {
+                let (db, num_accounts, num_transfers) =
+                    (db.clone(), self.num_accounts, self.num_transfers);
+                spawn(move || transfer(&db, num_accounts, num_transfers))
+            })
+            .collect();
+        let auditors: Vec<JoinHandle<u32>> = (0..self.num_auditors)
+            .map(|_| {
+                let (db, num_accounts, num_audits) =
+                    (db.clone(), self.num_accounts, self.num_audits);
+                spawn(move || audit(&db, num_accounts, num_audits, total))
+            })
+            .collect();
+
+        let mut num_conflicts = 0;
+        for thread in transferrers.into_iter().chain(auditors) {
+            match thread.join() {
+                Ok(thread_conflicts) => num_conflicts += thread_conflicts,
+                Err(_) => panic!("Test failed: a thread panicked"),
+            }
+        }
+        match db.scan(KeyRange::prefix("account"), ScanDirection::Forward) {
+            Ok(iter) => {
+                let got: u64 = iter.map(|pair| parse_balance(&pair.unwrap().1)).sum();
+                if got != total {
+                    panic!("Test failed: expected a total of {}, got {}", total, got);
+                }
+            }
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        }
+        println!(
+            "{} transactions were retried after a conflict",
+            num_conflicts
+        );
+        println!("Test passed");
+    }
+}
+
+fn account_key(account: u32) -> String {
+    format!("account{:05}", account)
+}
+
+fn parse_balance(value: &str) -> u64 {
+    match value.parse() {
+        Ok(balance) => balance,
+        Err(_) => panic!("Test failed: {} isn't a balance", value),
+    }
+}
+
+// a transaction that read a key someone else wrote to afterwards, or scanned a range someone else
+// wrote a key in, must not commit, and must not apply any of its writes
+fn check_conflicts<T: KVDb>(db: &TransactionalDb<T>, num_accounts: u32) {
+    let (read_key, written_key) = (account_key(0), account_key(1));
+    let mut txn = db.begin().unwrap();
+    let balance = txn.get(&read_key).unwrap();
+    txn.delete(&written_key);
+    if txn.get(&written_key).unwrap().is_some() {
+        panic!("Test failed: a transaction doesn't see its own write");
+    }
+    db.set(&read_key, &balance.unwrap()).unwrap();
+    if !commit(txn) {
+        panic!("Test failed: a transaction committed over a conflicting write");
+    }
+    if db.get(&written_key).unwrap() != Some(INITIAL_BALANCE.to_string()) {
+        panic!("Test failed: a transaction that didn't commit applied its write");
+    }
+
+    let mut txn = db.begin().unwrap();
+    txn.scan(KeyRange::prefix("account"), ScanDirection::Forward)
+        .unwrap()
+        .for_each(drop);
+    txn.set(&written_key, "0");
+    // deleting a key that isn't there is a write all the same
+    db.delete(&account_key(num_accounts)).unwrap();
+    if !commit(txn) {
+        panic!(
+            "Test failed: a transaction committed over a conflicting write in a range it scanned"
+        );
+    }
+}
+
+// commits the transaction, returns whether it conflicted
+fn commit<T: KVDb>(txn: Transaction<T>) -> bool {
+    match txn.commit() {
+        Ok(()) => false,
+        Err(Error::Conflict(_)) => true,
+        Err(e) => panic!("Test failed: unexpected error in commit: {}", e),
+    }
+}
+
+// returns the number of conflicts
+fn transfer<T: KVDb>(db: &TransactionalDb<T>, num_accounts: u32, num_transfers: u32) -> u32 {
+    let mut rng = rand::thread_rng();
+    let mut num_conflicts = 0;
+    for _ in 0..num_transfers {
+        let from = account_key(rng.gen_range(0..num_accounts));
+        let to = account_key(rng.gen_range(0..num_accounts));
+        loop {
+            let mut txn = db.begin().unwrap();
+            let from_balance = parse_balance(&txn.get(&from).unwrap().unwrap());
+            if from_balance == 0 {
+                txn.rollback().unwrap();
+                break;
+            }
+            let amount = rng.gen_range(1..=from_balance);
+            txn.set(&from, &(from_balance - amount).to_string());
+            // reads the write above when both are the same account
+            let to_balance = parse_balance(&txn.get(&to).unwrap().unwrap());
+            txn.set(&to, &(to_balance + amount).to_string());
+            if !commit(txn) {
+                break;
+            }
+            num_conflicts += 1;
+        }
+    }
+    num_conflicts
+}
+
+// every other audit reads the accounts one at a time, transfers can commit in between, so only
+// the commit's check keeps those from seeing a transfer half applied; returns the number of
+// conflicts
+fn audit<T: KVDb>(db: &TransactionalDb<T>, num_accounts: u32, num_audits: u32, total: u64) -> u32 {
+    let mut num_conflicts = 0;
+    for audit in 0..num_audits {
+        let mut txn = db.begin().unwrap();
+        let got: u64 = match audit % 2 {
+            0 => (0..num_accounts)
+                .map(|account| parse_balance(&txn.get(&account_key(account)).unwrap().unwrap()))
+                .sum(),
+            _ => txn
+                .scan(KeyRange::prefix("account"), ScanDirection::Forward)
+                .unwrap()
+                .map(|pair| parse_balance(&pair.unwrap().1))
+                .sum(),
+        };
+        match commit(txn) {
+            false if got != total => {
+                panic!("Test failed: expected a total of {}, got {}", total, got)
+            }
+            false => {}
+            true => num_conflicts += 1,
+        }
+    }
+    num_conflicts
+}
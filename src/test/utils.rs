@@ -87,26 +87,34 @@ pub fn generate_random_operations(
 
     if save {
         create_dir_all(DIR_PATH).unwrap();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(format!(
+        write_test_cases_to_file(
+            &operations,
+            &format!(
                 "{DIR_PATH}{num_keys}_{num_operations}_{read_write_ratio}_{set_delete_ratio}_{hit_reads_ratio}.txt"
-            )).unwrap();
-        for op in operations.iter() {
-            match op {
-                Operation::Set(key, value) => writeln!(&mut file, "S {key} {value}").unwrap(),
-                Operation::Delete(key) => writeln!(&mut file, "D {key}").unwrap(),
-                Operation::Read(key) => writeln!(&mut file, "R {key}").unwrap(),
-            }
-        }
+            ),
+        );
     }
 
     operations
 }
 
+pub fn write_test_cases_to_file(operations: &[Operation], file_path: &str) {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file_path)
+        .unwrap();
+    for op in operations.iter() {
+        match op {
+            Operation::Set(key, value) => writeln!(&mut file, "S {key} {value}").unwrap(),
+            Operation::Delete(key) => writeln!(&mut file, "D {key}").unwrap(),
+            Operation::Read(key) => writeln!(&mut file, "R {key}").unwrap(),
+        }
+    }
+}
+
 pub fn read_test_cases_from_file(file_path: &str) -> Vec<Operation> {
     let mut operations = vec![];
     let file = OpenOptions::new().read(true).open(file_path).unwrap();
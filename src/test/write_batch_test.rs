@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use crate::kvdb::{write_batch::WriteBatch, KVDb, KeyRange, ScanDirection};
+
+use super::{utils::generate_random_operations, Operation, Test};
+
+// Writes the operations in batches of random sizes, over few enough keys that a batch often
+// writes the same key more than once, and checks every read, as well as a full scan at the end,
+// against the writes so far.
+pub struct WriteBatchTest {
+    max_batch_size: usize,
+    operations: Vec<Operation>,
+}
+
+impl Test for WriteBatchTest {
+    fn run(&self, db: &mut Box<dyn KVDb>) {
+        println!(
+            "-------Running write batch test suite for {} with batches of up to {} operations-------",
+            db.description(),
+            self.max_batch_size
+        );
+        let mut rng = rand::thread_rng();
+        let mut sot = BTreeMap::new();
+        let mut batch = WriteBatch::new();
+        let mut batch_size = rng.gen_range(1..=self.max_batch_size);
+        for op in &self.operations {
+            match op {
+                Operation::Set(ref key, ref value) => {
+                    batch.set(key, value);
+                    sot.insert(key.clone(), value.clone());
+                }
+                Operation::Delete(ref key) => {
+                    batch.delete(key);
+                    sot.remove(key);
+                }
+                Operation::Read(ref key) => {
+                    // the batch isn't written yet, so the read must not see any of it
+                    let want = match batch.is_empty() {
+                        true => sot.get(key).cloned(),
+                        false => continue,
+                    };
+                    match db.get(key) {
+                        Ok(got) if got == want => {}
+                        Ok(got) => panic!(
+                            "Test failed: expected {:?} value for key {}, got {:?}",
+                            want, key, got
+                        ),
+                        Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+                    }
+                }
+            }
+            if batch.len() == batch_size {
+                if let Err(e) = db.write(&batch) {
+                    panic!("Test failed: unexpected error in write: {}", e);
+                }
+                batch = WriteBatch::new();
+                batch_size = rng.gen_range(1..=self.max_batch_size);
+            }
+        }
+        if let Err(e) = db.write(&batch) {
+            panic!("Test failed: unexpected error in write: {}", e);
+        }
+
+        let got = match db.scan(KeyRange::new(..), ScanDirection::Forward) {
+            Ok(iter) => iter.collect::<Result<BTreeMap<String, String>, _>>(),
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        };
+        match got {
+            Ok(got) if got == sot => {}
+            Ok(_) => panic!("Test failed: scan doesn't match the writes"),
+            Err(e) => panic!("Test failed: unexpected error in scan: {}", e),
+        }
+        println!("Test passed");
+    }
+}
+
+impl WriteBatchTest {
+    pub fn new(
+        num_keys: u32,
+        num_operations: u32,
+        read_write_ratio: f32,
+        set_delete_ratio: f32,
+        max_batch_size: usize,
+    ) -> WriteBatchTest {
+        WriteBatchTest {
+            max_batch_size,
+            operations: generate_random_operations(
+                num_keys,
+                num_operations,
+                read_write_ratio,
+                set_delete_ratio,
+                0.9,
+                false,
+            ),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, fs, sync::Arc};
+
+use crate::kv_file::RecoveryPolicy;
+use crate::kvdb::KVDb;
+use crate::segmented_files_db::{block_cache::BlockCache, compaction::MergeAllCompaction};
+use crate::sstable::{write_buffer::WriteBufferPolicy, SSTable};
+use crate::utils::process_dir_contents;
+
+use super::{utils::generate_random_operations, Operation};
+
+// Spreads the writes over several SSTables that share a write buffer budget, and checks that the
+// budget kept their memtables in check and that none of the writes were lost on the way. Once the
+// SSTables are dropped, their memtables shouldn't hold on to any of the budget. Segments aren't
+// merged, so that each one is a flushed memtable, and an SSTable with few writes shouldn't have
+// its memtable flushed for every write because the others used the budget up.
+pub struct WriteBufferBudgetTest {
+    operations: Vec<Operation>,
+}
+
+impl WriteBufferBudgetTest {
+    pub fn new(num_keys: u32, num_operations: u32, set_delete_ratio: f32) -> Self {
+        WriteBufferBudgetTest {
+            operations: generate_random_operations(
+                num_keys,
+                num_operations,
+                0.0,
+                set_delete_ratio,
+                0.0,
+                false,
+            ),
+        }
+    }
+    pub fn run(&self, dir_path: &str, num_dbs: usize, write_buffer_policy: WriteBufferPolicy) {
+        let budget = Arc::clone(write_buffer_policy.budget.as_ref().unwrap());
+        let block_cache = Arc::new(BlockCache::new(1 << 20));
+        let _ = fs::remove_dir_all(dir_path);
+        let mut dbs: Vec<SSTable> = (0..num_dbs)
+            .map(|i| {
+                SSTable::new(
+                    &format!("{}{}/", dir_path, i),
+                    Box::new(MergeAllCompaction {
+                        merging_threshold: usize::MAX,
+                    }),
+                    500,
+                    10,
+                    Arc::clone(&block_cache),
+                    write_buffer_policy.clone(),
+                    RecoveryPolicy::TruncateTornTail,
+                )
+                .unwrap()
+            })
+            .collect();
+        println!(
+            "-------Running write buffer budget test suite for {} of {}-------",
+            num_dbs,
+            dbs[0].description()
+        );
+
+        // each SSTable has at most its memtable and the immutable ones in its queue, and none of
+        // them grows past the budget by more than a write
+        let max_used =
+            num_dbs * (write_buffer_policy.max_immutable_memtables + 1) * (budget.limit() + 1024);
+        let min_memtable_size = budget.min_memtable_size();
+        let mut sots = vec![HashMap::new(); num_dbs];
+        // the memtable entries each SSTable got, taking each one to be its key and value along
+        // with more than the skiplist's overhead for an entry on average
+        let mut written = vec![0; num_dbs];
+        for (i, op) in self.operations.iter().enumerate() {
+            // the first SSTable only gets a write every so often
+            let idx = match i % (num_dbs * 10) {
+                0 => 0,
+                i => 1 + i % (num_dbs - 1),
+            };
+            let (db, sot) = (&mut dbs[idx], &mut sots[idx]);
+            written[idx] += match op {
+                Operation::Set(ref key, ref value) => key.len() + value.len() + 128,
+                Operation::Delete(ref key) => key.len() + 128,
+                Operation::Read(_) => 0,
+            };
+            match op {
+                Operation::Set(ref key, ref value) => {
+                    sot.insert(key.clone(), Some(value.clone()));
+                    if let Err(e) = db.set(key, value) {
+                        panic!("Test failed: unexpected error in write: {}", e);
+                    }
+                }
+                Operation::Delete(ref key) => {
+                    sot.insert(key.clone(), None);
+                    if let Err(e) = db.delete(key) {
+                        panic!("Test failed: unexpected error in delete: {}", e);
+                    }
+                }
+                Operation::Read(_) => {}
+            }
+            if budget.used() > max_used {
+                panic!(
+                    "Test failed: memtables took {} bytes of a {} byte budget",
+                    budget.used(),
+                    budget.limit()
+                );
+            }
+        }
+        for (db, sot) in dbs.iter_mut().zip(&sots) {
+            for (key, want) in sot {
+                match db.get(key) {
+                    Ok(got) if got == *want => {}
+                    Ok(got) => panic!(
+                        "Test failed: expected {:?} for {}, got {:?}",
+                        want, key, got
+                    ),
+                    Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+                }
+            }
+        }
+
+        drop(dbs);
+        if budget.used() != 0 {
+            panic!(
+                "Test failed: {} bytes of the budget still used after closing",
+                budget.used()
+            );
+        }
+        // every segment but the current one is a memtable that was flushed once it held enough
+        for (i, written) in written.into_iter().enumerate() {
+            let mut num_segments = 0;
+            process_dir_contents(&format!("{}{}/", dir_path, i), &mut |path| {
+                let file_stem = path.file_stem().and_then(|file_stem| file_stem.to_str());
+                if file_stem.is_some_and(|file_stem| file_stem.parse::<usize>().is_ok()) {
+                    num_segments += 1;
+                }
+                Ok(())
+            })
+            .unwrap();
+            let max_segments = written / min_memtable_size + 1;
+            if num_segments > max_segments {
+                panic!(
+                    "Test failed: SSTable {} has {} segments for {} bytes of writes, expected at most {}",
+                    i, num_segments, written, max_segments
+                );
+            }
+        }
+        println!("Test passed");
+    }
+}
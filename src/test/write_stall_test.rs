@@ -0,0 +1,148 @@
+use std::{collections::HashMap, fs};
+
+use crate::error::{DbResult, Error};
+use crate::kvdb::KVDb;
+use crate::sstable::SSTable;
+use crate::utils::process_dir_contents;
+
+use super::{utils::generate_random_operations, Operation};
+
+// Writes to an SSTable whose memtables fill up faster than they can be flushed, and checks that
+// the writes were held back and that none of them were lost on the way. It can also make the
+// flushes fail after a while, writes then have to fail with the flush's error rather than wait
+// for a flush forever, and reopening the SSTable has to bring back every write that went through.
+pub struct WriteStallTest {
+    operations: Vec<Operation>,
+}
+
+impl WriteStallTest {
+    pub fn new(num_keys: u32, num_operations: u32, set_delete_ratio: f32) -> WriteStallTest {
+        WriteStallTest {
+            operations: generate_random_operations(
+                num_keys,
+                num_operations,
+                0.0,
+                set_delete_ratio,
+                0.0,
+                false,
+            ),
+        }
+    }
+    pub fn run(&self, db: &mut SSTable) {
+        println!(
+            "-------Running write stall test suite for {}-------",
+            db.description()
+        );
+        let mut sot = HashMap::new();
+        for op in &self.operations {
+            match op {
+                Operation::Set(ref key, ref value) => {
+                    sot.insert(key.clone(), Some(value.clone()));
+                    if let Err(e) = db.set(key, value) {
+                        panic!("Test failed: unexpected error in write: {}", e);
+                    }
+                }
+                Operation::Delete(ref key) => {
+                    sot.insert(key.clone(), None);
+                    if let Err(e) = db.delete(key) {
+                        panic!("Test failed: unexpected error in delete: {}", e);
+                    }
+                }
+                Operation::Read(_) => {}
+            }
+        }
+        for (key, want) in &sot {
+            match db.get(key) {
+                Ok(got) if got == *want => {}
+                Ok(got) => panic!(
+                    "Test failed: expected {:?} for {}, got {:?}",
+                    want, key, got
+                ),
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            }
+        }
+
+        let stats = db.write_stall_stats().unwrap();
+        println!(
+            "{} writes slowed down for {:?} in total, {} stalled for {:?} in total",
+            stats.num_slowdowns, stats.slowdown_duration, stats.num_stalls, stats.stall_duration
+        );
+        if stats.num_slowdowns + stats.num_stalls == 0 {
+            panic!("Test failed: no write was held back");
+        }
+        println!("Test passed");
+    }
+    // `open` has to give an SSTable in `dir_path` whose memtables fill up faster than they can be
+    // flushed and that doesn't merge segments
+    pub fn run_with_failing_flush(&self, dir_path: &str, open: impl Fn() -> DbResult<SSTable>) {
+        let _ = fs::remove_dir_all(dir_path);
+        let mut db = open().unwrap();
+        println!(
+            "-------Running write stall test suite with failing flushes for {}-------",
+            db.description()
+        );
+        // segment files a few flushes from now can't be created, as directories take their names
+        let mut last_file_number = 0;
+        process_dir_contents(dir_path, &mut |path| {
+            let file_number = path
+                .file_stem()
+                .and_then(|file_stem| file_stem.to_str())
+                .and_then(|file_stem| file_stem.parse::<usize>().ok());
+            last_file_number = last_file_number.max(file_number.unwrap_or(0));
+            Ok(())
+        })
+        .unwrap();
+        let blocked_paths: Vec<String> = (last_file_number + 3..last_file_number + 1000)
+            .map(|file_number| format!("{}{}.txt", dir_path, file_number))
+            .collect();
+        for path in &blocked_paths {
+            fs::create_dir(path).unwrap();
+        }
+
+        let mut sot = HashMap::new();
+        let mut failed = false;
+        for op in &self.operations {
+            let (key, result, want) = match op {
+                Operation::Set(ref key, ref value) => {
+                    (key, db.set(key, value), Some(value.clone()))
+                }
+                Operation::Delete(ref key) => (key, db.delete(key), None),
+                Operation::Read(_) => continue,
+            };
+            match result {
+                Ok(()) => {
+                    sot.insert(key.clone(), want);
+                }
+                Err(Error::Wrapped(ref msg, _)) if msg == "error in flushing memtable" => {
+                    failed = true;
+                    break;
+                }
+                Err(e) => panic!("Test failed: unexpected error in write: {}", e),
+            }
+        }
+        if !failed {
+            panic!("Test failed: no write failed along with the flush");
+        }
+        if db.set("key_after_failure", "value").is_ok() {
+            panic!("Test failed: a write went through after a flush failed");
+        }
+        sot.insert("key_after_failure".to_owned(), None);
+        drop(db);
+
+        for path in &blocked_paths {
+            fs::remove_dir(path).unwrap();
+        }
+        let mut db = open().unwrap();
+        for (key, want) in &sot {
+            match db.get(key) {
+                Ok(got) if got == *want => {}
+                Ok(got) => panic!(
+                    "Test failed: expected {:?} for {} after reopening, got {:?}",
+                    want, key, got
+                ),
+                Err(e) => panic!("Test failed: unexpected error in read: {}", e),
+            }
+        }
+        println!("Test passed");
+    }
+}
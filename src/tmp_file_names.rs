@@ -1,4 +1,2 @@
-pub const TMP_SEGMENT_FILE_NAME: &str = "_tmp_segment_file.txt";
-pub const TMP_MERGING_FILE_NAME: &str = "_tmp_merging_file.txt";
-pub const TMP_MEMTABLE_BACKUP_SWAP_FILE_NAME: &str = "_tmp_backup_swap_file.txt";
 pub const TMP_COMPACTION_FILE_NAME: &str = "_tmp_compaction_file.txt";
+pub const TMP_MANIFEST_FILE_NAME: &str = "_tmp_manifest_file.txt";
@@ -1,4 +1,4 @@
-use std::{fs::read_dir, path::PathBuf, thread::JoinHandle};
+use std::{fs::{read_dir, File}, path::PathBuf, thread::JoinHandle};
 use crate::error::DbResult;
 
 #[macro_export]
@@ -30,3 +30,9 @@ pub fn is_thread_running<T>(maybe_handle: &Option<JoinHandle<T>>) -> bool {
         None => false,
     }
 }
+
+// makes the files created, renamed and deleted in the directory durable
+pub fn sync_dir(dir_path: &str) -> DbResult<()> {
+    File::open(dir_path)?.sync_all()?;
+    Ok(())
+}